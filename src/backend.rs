@@ -0,0 +1,193 @@
+//! Backends collecting the coverage of test executables.
+//!
+//! The pipeline in `collect_coverage` builds the tests, and leaves running them and producing the
+//! report to a `Backend`. Two backends are provided: `KcovBackend` traces the tests with kcov, and
+//! `InstrumentBackend` builds them with rustc's source-based coverage and reports with the LLVM
+//! tools. Both write an HTML report with `index.html` into the output directory.
+
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::remove_dir_all;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use errors::Error;
+use kcov::{Kcov, TestRun};
+use output_dir::find_result_dirs_into;
+
+/// Collects the coverage of test executables.
+pub trait Backend {
+    /// `RUSTFLAGS` the tests are built with.
+    fn rustflags(&self) -> &str;
+
+    /// Runs each test executable, writing the raw results into `cov_path`.
+    fn run_tests(&self, cov_path: &Path, tests: Vec<PathBuf>) -> Result<Vec<TestRun>, Error>;
+
+    /// Combines the results of `test_runs` into a report in `cov_path`. Used for the reports of
+    /// the individual feature sets.
+    fn merge(&self, cov_path: &Path, test_runs: &[TestRun]) -> Result<(), Error>;
+
+    /// Writes the final report into `cov_path`. By default this is the same as `merge`.
+    fn finish(&self, cov_path: &Path, test_runs: &[TestRun]) -> Result<(), Error> {
+        self.merge(cov_path, test_runs)
+    }
+
+    /// Finds the raw results kept in `cov_path` by `--append` from earlier runs, to be included in
+    /// the final report. By default none are returned, for backends whose `merge` already reads
+    /// every result in the output directory.
+    fn previous_runs(&self, cov_path: &Path) -> Result<Vec<TestRun>, Error> {
+        let _ = cov_path;
+        Ok(Vec::new())
+    }
+
+    /// Deletes the raw results of `test` from `cov_path`, before running it again in watch mode so
+    /// the coverage of the previous run is not added up. By default nothing is deleted.
+    fn remove_results(&self, cov_path: &Path, test: &Path) -> Result<(), Error> {
+        let _ = (cov_path, test);
+        Ok(())
+    }
+}
+
+/// The backends built into cargo-kcov, selected by `--backend`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BackendKind {
+    /// Trace the tests with kcov.
+    Kcov,
+    /// Build the tests with `-C instrument-coverage`.
+    InstrumentCoverage,
+}
+
+impl BackendKind {
+    /// The names accepted by `parse`.
+    pub const NAMES: &'static [&'static str] = &["kcov", "instrument-coverage"];
+
+    pub fn parse(name: &str) -> Option<BackendKind> {
+        match name {
+            "kcov" => Some(BackendKind::Kcov),
+            "instrument-coverage" => Some(BackendKind::InstrumentCoverage),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for BackendKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            BackendKind::Kcov => "kcov",
+            BackendKind::InstrumentCoverage => "instrument-coverage",
+        })
+    }
+}
+
+/// `RUSTFLAGS` used to build the tests for kcov. Unused functions are kept, so they are reported
+/// as not covered.
+pub const KCOV_RUSTFLAGS: &str = "-C link-dead-code";
+
+/// Runs the tests under kcov, and merges the results with `kcov --merge`.
+pub struct KcovBackend {
+    kcov: Kcov,
+    coveralls_option: Option<OsString>,
+}
+
+impl KcovBackend {
+    /// Uses `kcov`, uploading the final report to coveralls.io if `coveralls_option` is given
+    /// (see `get_coveralls_option`).
+    pub fn new(kcov: Kcov, coveralls_option: Option<OsString>) -> Self {
+        KcovBackend {
+            kcov,
+            coveralls_option,
+        }
+    }
+}
+
+impl Backend for KcovBackend {
+    fn rustflags(&self) -> &str {
+        KCOV_RUSTFLAGS
+    }
+
+    fn run_tests(&self, cov_path: &Path, tests: Vec<PathBuf>) -> Result<Vec<TestRun>, Error> {
+        self.kcov.run_tests(cov_path, tests)
+    }
+
+    fn merge(&self, cov_path: &Path, test_runs: &[TestRun]) -> Result<(), Error> {
+        self.kcov.merge(cov_path, None, &result_dirs(test_runs))
+    }
+
+    fn finish(&self, cov_path: &Path, test_runs: &[TestRun]) -> Result<(), Error> {
+        self.kcov.merge(
+            cov_path,
+            self.coveralls_option.clone(),
+            &result_dirs(test_runs),
+        )
+    }
+
+    /// kcov writes the results of each executable into a directory named after it.
+    fn previous_runs(&self, cov_path: &Path) -> Result<Vec<TestRun>, Error> {
+        let mut result_dirs = Vec::new();
+        find_result_dirs_into(&mut result_dirs, cov_path)?;
+        Ok(result_dirs
+            .into_iter()
+            .map(|dir| TestRun {
+                executable: PathBuf::from(dir.file_name().unwrap()),
+                cov_path: dir,
+                cached: false,
+            })
+            .collect())
+    }
+
+    /// kcov accumulates the coverage of every run into an existing output directory.
+    fn remove_results(&self, cov_path: &Path, test: &Path) -> Result<(), Error> {
+        match remove_dir_all(cov_path.join(test.file_name().unwrap())) {
+            Ok(_) => Ok(()),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(Error::CannotCreateCoverageDirectory(e)),
+        }
+    }
+}
+
+fn result_dirs(test_runs: &[TestRun]) -> Vec<PathBuf> {
+    test_runs.iter().map(|run| run.cov_path.clone()).collect()
+}
+
+#[test]
+fn test_backend_kind() {
+    for name in BackendKind::NAMES {
+        assert_eq!(BackendKind::parse(name).unwrap().to_string(), *name);
+    }
+    assert_eq!(BackendKind::parse("gcov"), None);
+}
+
+#[test]
+fn test_kcov_previous_runs() {
+    use kcov::{KcovExecutable, KcovSource};
+    use std::fs::create_dir_all;
+    use tempdir::TempDir;
+
+    let root = TempDir::new("test_kcov_previous_runs").unwrap();
+    let cov_path = root.path().join("cov");
+    for dir in &[
+        "data",
+        "kcov-merged",
+        "first-d5d6293fc6d22a93",
+        "features-default/kcov-merged",
+        "features-default/second-f0ac3ec8d3d3bcd5",
+    ] {
+        create_dir_all(cov_path.join(dir)).unwrap();
+    }
+    let kcov = KcovExecutable {
+        path: PathBuf::from("kcov"),
+        version: None,
+        source: KcovSource::Path,
+        runner: None,
+    };
+    let backend = KcovBackend::new(Kcov::new(kcov, Vec::new()), None);
+    let runs = backend.previous_runs(&cov_path).unwrap();
+    assert_eq!(
+        runs.iter().map(|run| &run.cov_path).collect::<Vec<_>>(),
+        [
+            &cov_path.join("features-default/second-f0ac3ec8d3d3bcd5"),
+            &cov_path.join("first-d5d6293fc6d22a93"),
+        ]
+    );
+    assert_eq!(runs[1].executable, Path::new("first-d5d6293fc6d22a93"));
+}
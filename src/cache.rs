@@ -0,0 +1,306 @@
+//! Content-addressed cache of the per-executable kcov output.
+//!
+//! Each test executable is identified by the SHA-256 of its content together with everything
+//! passed to kcov when running it. If a previous run already produced a kcov directory for the same
+//! key, that directory is copied into the output directory instead of running the executable again,
+//! so the output directory stays self-contained and can be merged later.
+//!
+//! Only the `MAX_ENTRIES` most recently used entries are kept, the others are deleted whenever a new
+//! entry is stored.
+
+use std::env::{current_dir, vars_os};
+use std::ffi::OsStr;
+use std::fs::{copy, create_dir_all, read_dir, remove_dir_all, rename, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+use errors::Error;
+
+/// Bump this whenever the layout of a cache entry or the hashed inputs change.
+const CACHE_VERSION: &[u8] = b"cargo-kcov-cache-v1";
+
+/// Name of the file written into a cache entry after kcov finished successfully. Entries without
+/// this file were interrupted or failed, and will be regenerated.
+const COMPLETE_MARKER: &str = ".cargo-kcov-complete";
+
+/// Number of entries kept in the cache. Each test executable of each feature set takes one, so this
+/// leaves room for a few versions of a large workspace.
+const MAX_ENTRIES: usize = 256;
+
+pub struct Cache {
+    root: PathBuf,
+    max_entries: usize,
+}
+
+impl Cache {
+    /// Creates a cache stored in `target/kcov-cache`. This is deliberately outside the coverage
+    /// output directory, which is recreated on every run, and is kept by the clean rebuild, which
+    /// only cleans the packages being tested.
+    pub fn new(target_path: &Path) -> Cache {
+        Cache {
+            root: target_path.join("kcov-cache"),
+            max_entries: MAX_ENTRIES,
+        }
+    }
+
+    /// Deletes all entries. Returns the path of the cache if it existed.
+    pub fn clear(&self) -> Result<Option<&Path>, Error> {
+        match remove_dir_all(&self.root) {
+            Ok(_) => Ok(Some(&self.root)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(Error::Cache(e)),
+        }
+    }
+
+    /// Computes the cache key of running `kcov <kcov_args> <out> <test> <test_args>`.
+    pub fn key<S: AsRef<OsStr>>(
+        &self,
+        kcov_path: &OsStr,
+        kcov_args: &[S],
+        test: &Path,
+        test_args: &[S],
+    ) -> Result<String, Error> {
+        let mut hasher = Sha256::new();
+        hasher.update(CACHE_VERSION);
+        hash_os_str(&mut hasher, kcov_path);
+        hash_os_strs(&mut hasher, kcov_args);
+        hash_os_strs(&mut hasher, test_args);
+        hash_os_str(&mut hasher, test.file_name().unwrap_or_default());
+        hash_environment(&mut hasher).map_err(Error::Cache)?;
+        hash_file(&mut hasher, test).map_err(Error::Cache)?;
+
+        let digest = hasher.finalize();
+        Ok(digest.iter().map(|b| format!("{:02x}", b)).collect())
+    }
+
+    /// Copies the entry of `key` into `dest`, and marks it as recently used. Returns false on cache
+    /// miss.
+    pub fn restore(&self, key: &str, dest: &Path) -> Result<bool, Error> {
+        let path = self.root.join(key);
+        if !path.join(COMPLETE_MARKER).is_file() {
+            return Ok(false);
+        }
+        let _ = remove_dir_all(dest);
+        // Recreating the marker updates its modification time, which orders the eviction.
+        match copy_dir_all(&path, dest).and_then(|_| File::create(path.join(COMPLETE_MARKER))) {
+            Ok(_) => Ok(true),
+            Err(e) => Err(Error::Cache(e)),
+        }
+    }
+
+    /// Stores a copy of the kcov output directory `src` as the entry of `key`.
+    pub fn store(&self, key: &str, src: &Path) -> Result<(), Error> {
+        let path = self.root.join(key);
+        let tmp_path = self.root.join(format!("{}.tmp", key));
+        let _ = remove_dir_all(&tmp_path);
+        let result = copy_dir_all(src, &tmp_path)
+            .and_then(|_| File::create(tmp_path.join(COMPLETE_MARKER)))
+            .and_then(|_| {
+                let _ = remove_dir_all(&path);
+                rename(&tmp_path, &path)
+            })
+            .and_then(|_| self.evict());
+        match result {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Error::Cache(e)),
+        }
+    }
+
+    /// Deletes the least recently used entries beyond `max_entries`. Incomplete entries are left
+    /// alone, since another run may be writing them.
+    fn evict(&self) -> io::Result<()> {
+        let mut entries = Vec::new();
+        for entry in read_dir(&self.root)? {
+            let path = entry?.path();
+            if let Ok(metadata) = path.join(COMPLETE_MARKER).metadata() {
+                entries.push((metadata.modified()?, path));
+            }
+        }
+        if entries.len() <= self.max_entries {
+            return Ok(());
+        }
+        entries.sort();
+        let excess = entries.len() - self.max_entries;
+        for (_, path) in entries.into_iter().take(excess) {
+            if let Err(e) = remove_dir_all(&path) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Recursively copies a directory. Symlinks (kcov creates one for each executable) are recreated
+/// instead of followed.
+pub fn copy_dir_all(src: &Path, dest: &Path) -> io::Result<()> {
+    create_dir_all(dest)?;
+    for entry in read_dir(src)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        let dest_path = dest.join(entry.file_name());
+        if file_type.is_dir() {
+            copy_dir_all(&entry.path(), &dest_path)?;
+        } else if file_type.is_symlink() {
+            copy_symlink(&entry.path(), &dest_path)?;
+        } else {
+            copy(entry.path(), dest_path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn copy_symlink(src: &Path, dest: &Path) -> io::Result<()> {
+    use std::fs::read_link;
+    use std::os::unix::fs::symlink;
+    symlink(read_link(src)?, dest)
+}
+
+#[cfg(not(unix))]
+fn copy_symlink(src: &Path, dest: &Path) -> io::Result<()> {
+    copy(src, dest).map(|_| ())
+}
+
+fn hash_os_str(hasher: &mut Sha256, s: &OsStr) {
+    let s = s.to_string_lossy();
+    hasher.update((s.len() as u64).to_le_bytes());
+    hasher.update(s.as_bytes());
+}
+
+fn hash_os_strs<S: AsRef<OsStr>>(hasher: &mut Sha256, strs: &[S]) {
+    hasher.update((strs.len() as u64).to_le_bytes());
+    for s in strs {
+        hash_os_str(hasher, s.as_ref());
+    }
+}
+
+/// Hashes the part of the environment which could affect how a test executable behaves. The whole
+/// environment is not used since CI services put job-specific variables in it.
+fn hash_environment(hasher: &mut Sha256) -> io::Result<()> {
+    let mut vars = vars_os()
+        .filter(|(k, _)| {
+            let k = k.to_string_lossy();
+            k.starts_with("RUST")
+                || k.starts_with("CARGO")
+                || k == "LD_LIBRARY_PATH"
+                || k == "DYLD_LIBRARY_PATH"
+        })
+        .collect::<Vec<_>>();
+    vars.sort();
+    hasher.update((vars.len() as u64).to_le_bytes());
+    for (k, v) in vars {
+        hash_os_str(hasher, &k);
+        hash_os_str(hasher, &v);
+    }
+    hash_os_str(hasher, current_dir()?.as_os_str());
+    Ok(())
+}
+
+pub(crate) fn hash_file(hasher: &mut Sha256, path: &Path) -> io::Result<()> {
+    let mut file = File::open(path)?;
+    let mut buf = [0; 65536];
+    loop {
+        let len = file.read(&mut buf)?;
+        if len == 0 {
+            return Ok(());
+        }
+        hasher.update(&buf[..len]);
+    }
+}
+
+#[test]
+fn test_cache_key() {
+    use std::fs::write;
+    use tempdir::TempDir;
+
+    let root = TempDir::new("test_cache_key").unwrap();
+    let root_path = root.path();
+    let cache = Cache::new(root_path);
+    let kcov = OsStr::new("kcov");
+    let no_args: &[&str] = &[];
+
+    let first = root_path.join("first-d5d6293fc6d22a93");
+    let second = root_path.join("second-f0ac3ec8d3d3bcd5");
+    write(&first, b"\x7fELF first").unwrap();
+    write(&second, b"\x7fELF first").unwrap();
+
+    let key = cache.key(kcov, &["--verify"], &first, no_args).unwrap();
+    assert_eq!(key.len(), 64);
+    assert_eq!(
+        key,
+        cache.key(kcov, &["--verify"], &first, no_args).unwrap()
+    );
+    assert_ne!(
+        key,
+        cache.key(kcov, &["--verify"], &second, no_args).unwrap()
+    );
+    assert_ne!(key, cache.key(kcov, no_args, &first, no_args).unwrap());
+    assert_ne!(
+        key,
+        cache
+            .key(kcov, &["--verify"], &first, &["--ignored"])
+            .unwrap()
+    );
+    assert_ne!(
+        key,
+        cache
+            .key(OsStr::new("/opt/kcov"), &["--verify"], &first, no_args)
+            .unwrap()
+    );
+
+    write(&first, b"\x7fELF changed").unwrap();
+    assert_ne!(
+        key,
+        cache.key(kcov, &["--verify"], &first, no_args).unwrap()
+    );
+}
+
+#[test]
+fn test_cache_store_restore() {
+    use std::fs::{read_to_string, write};
+    use std::thread::sleep;
+    use std::time::Duration;
+    use tempdir::TempDir;
+
+    let root = TempDir::new("test_cache_store_restore").unwrap();
+    let cache = Cache::new(root.path());
+    let src = root.path().join("cov/first");
+    let dest = root.path().join("cov2/first");
+    create_dir_all(src.join("first.0123")).unwrap();
+    write(src.join("first.0123/cobertura.xml"), "<coverage/>").unwrap();
+
+    assert!(!cache.restore("0123", &dest).unwrap());
+    assert!(!dest.exists());
+
+    cache.store("0123", &src).unwrap();
+    assert!(cache.restore("0123", &dest).unwrap());
+    assert_eq!(
+        read_to_string(dest.join("first.0123/cobertura.xml")).unwrap(),
+        "<coverage/>"
+    );
+
+    // an interrupted store does not leave a usable entry behind.
+    create_dir_all(root.path().join("kcov-cache/4567.tmp")).unwrap();
+    assert!(!cache.restore("4567", &dest).unwrap());
+
+    // the least recently used entries are evicted.
+    let cache = Cache {
+        max_entries: 2,
+        ..cache
+    };
+    let wait = || sleep(Duration::from_millis(20));
+    wait();
+    cache.store("89ab", &src).unwrap();
+    wait();
+    assert!(cache.restore("0123", &dest).unwrap());
+    wait();
+    cache.store("cdef", &src).unwrap();
+    assert!(cache.restore("0123", &dest).unwrap());
+    assert!(!cache.restore("89ab", &dest).unwrap());
+    assert!(cache.restore("cdef", &dest).unwrap());
+    assert!(root.path().join("kcov-cache/4567.tmp").is_dir());
+}
@@ -1,13 +1,135 @@
 use std::convert::AsRef;
 use std::env::var_os;
-use std::ffi::OsStr;
+use std::ffi::{OsStr, OsString};
 use std::fmt;
-use std::process::Command;
-
-use clap::ArgMatches;
+use std::io;
+use std::path::PathBuf;
+use std::process::{Command, Output};
 
 use errors::Error;
 
+/// Options forwarded to cargo when building the test executables.
+///
+/// Each cargo subcommand accepts a different subset of these options, so they are rendered
+/// separately by `clean_args`, `test_args`, `build_args`, `metadata_args` and `pkgid_args`.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct CargoOptions {
+    /// In a workspace, select all members (`--all`).
+    pub all: bool,
+    /// Select these workspace members (`--package`).
+    pub packages: Vec<String>,
+    /// Select the package's library (`--lib`).
+    pub lib: bool,
+    /// Select the binaries (`--bin`).
+    pub bins: Vec<String>,
+    /// Select the examples (`--example`).
+    pub examples: Vec<String>,
+    /// Select the integration tests (`--test`).
+    pub tests: Vec<String>,
+    /// Select the benchmarks (`--bench`).
+    pub benches: Vec<String>,
+    /// Space-separated list of features to activate (`--features`).
+    pub features: Option<String>,
+    /// Do not activate the `default` feature (`--no-default-features`).
+    pub no_default_features: bool,
+    /// Build in release mode (`--release`).
+    pub release: bool,
+    /// Build for the target triple (`--target`).
+    pub target: Option<String>,
+    /// Path to the manifest (`--manifest-path`).
+    pub manifest_path: Option<PathBuf>,
+    /// Number of parallel jobs (`--jobs`).
+    pub jobs: Option<u32>,
+    /// Build all tests regardless of failure (`--no-fail-fast`).
+    pub no_fail_fast: bool,
+}
+
+impl CargoOptions {
+    /// Arguments of `cargo clean`, cleaning only `package` if given.
+    pub fn clean_args(&self, package: Option<&str>) -> Vec<OsString> {
+        let mut args = Vec::new();
+        push_value(&mut args, "--package", package);
+        self.push_manifest_path(&mut args);
+        self.push_profile(&mut args);
+        args
+    }
+
+    /// Arguments of `cargo test --no-run`.
+    pub fn test_args(&self) -> Vec<OsString> {
+        let mut args = Vec::new();
+        push_flag(&mut args, "--lib", self.lib);
+        push_values(&mut args, "--bin", &self.bins);
+        push_values(&mut args, "--example", &self.examples);
+        push_values(&mut args, "--test", &self.tests);
+        push_values(&mut args, "--bench", &self.benches);
+        self.push_build(&mut args);
+        push_flag(&mut args, "--no-fail-fast", self.no_fail_fast);
+        push_flag(&mut args, "--all", self.all);
+        push_values(&mut args, "--package", &self.packages);
+        args
+    }
+
+    /// Arguments of `cargo build`. The target filters are not included, since they select test
+    /// executables.
+    pub fn build_args(&self) -> Vec<OsString> {
+        let mut args = Vec::new();
+        self.push_build(&mut args);
+        args
+    }
+
+    /// Arguments of `cargo metadata`.
+    pub fn metadata_args(&self) -> Vec<OsString> {
+        let mut args = Vec::new();
+        self.push_manifest_path(&mut args);
+        args
+    }
+
+    /// Arguments of `cargo pkgid`.
+    pub fn pkgid_args(&self) -> Vec<OsString> {
+        let mut args = Vec::new();
+        self.push_manifest_path(&mut args);
+        args
+    }
+
+    fn push_build(&self, args: &mut Vec<OsString>) {
+        push_value(args, "--jobs", self.jobs.map(|jobs| jobs.to_string()));
+        self.push_profile(args);
+        self.push_manifest_path(args);
+        push_value(args, "--features", self.features.as_ref());
+        push_flag(args, "--no-default-features", self.no_default_features);
+    }
+
+    fn push_profile(&self, args: &mut Vec<OsString>) {
+        push_flag(args, "--release", self.release);
+        push_value(args, "--target", self.target.as_ref());
+    }
+
+    fn push_manifest_path(&self, args: &mut Vec<OsString>) {
+        push_value(args, "--manifest-path", self.manifest_path.as_ref());
+    }
+}
+
+fn push_flag(args: &mut Vec<OsString>, name: &str, is_present: bool) {
+    if is_present {
+        args.push(name.into());
+    }
+}
+
+fn push_value<S: AsRef<OsStr>>(args: &mut Vec<OsString>, name: &str, value: Option<S>) {
+    if let Some(value) = value {
+        args.push(name.into());
+        args.push(value.as_ref().to_owned());
+    }
+}
+
+fn push_values<S: AsRef<OsStr>>(args: &mut Vec<OsString>, name: &str, values: &[S]) {
+    for value in values {
+        push_value(args, name, Some(value));
+    }
+}
+
+//-------------------------------------------------------------------------------------------------
+
 pub struct Cmd {
     cmd: Command,
     subcommand: &'static str,
@@ -20,25 +142,6 @@ impl fmt::Display for Cmd {
     }
 }
 
-enum ArgType {
-    Flag,
-    Single,
-    Multiple,
-}
-
-fn parse_arg_type(option: &str) -> Option<ArgType> {
-    match option {
-        "--manifest-path" | "--target" | "--jobs" | "--features" | "--coveralls" => {
-            Some(ArgType::Single)
-        }
-        "--release" | "--lib" | "--no-default-features" | "--no-fail-fast" | "--all" => {
-            Some(ArgType::Flag)
-        }
-        "--bin" | "--example" | "--test" | "--bench" => Some(ArgType::Multiple),
-        _ => None,
-    }
-}
-
 impl Cmd {
     pub fn new<S: AsRef<OsStr>>(command: S, subcommand: &'static str) -> Self {
         let mut command = Command::new(command);
@@ -56,32 +159,6 @@ impl Cmd {
         self
     }
 
-    pub fn forward(mut self, matches: &ArgMatches, options: &[&'static str]) -> Self {
-        for option in options {
-            let opt_name = &option[2..];
-            match parse_arg_type(option).unwrap_or_else(|| panic!("Cannot forward {}", option)) {
-                ArgType::Flag => {
-                    if matches.is_present(opt_name) {
-                        self.cmd.arg(option);
-                    }
-                }
-                ArgType::Single => {
-                    if let Some(opt) = matches.value_of_os(opt_name) {
-                        self.cmd.arg(option).arg(opt);
-                    }
-                }
-                ArgType::Multiple => {
-                    if let Some(opts) = matches.values_of_os(opt_name) {
-                        for opt in opts {
-                            self.cmd.arg(option).arg(opt);
-                        }
-                    }
-                }
-            }
-        }
-        self
-    }
-
     pub fn env(mut self, key: &str, sep: &str, val: &str) -> Self {
         match var_os(key) {
             None => {
@@ -96,6 +173,12 @@ impl Cmd {
         self
     }
 
+    /// Sets an environment variable, replacing any inherited value.
+    pub fn set_env<S: AsRef<OsStr>>(mut self, key: &str, val: S) -> Self {
+        self.cmd.env(key, val);
+        self
+    }
+
     pub fn output(mut self) -> Result<(String, String), Error> {
         let output = match self.cmd.output() {
             Ok(o) => o,
@@ -114,6 +197,11 @@ impl Cmd {
         Ok((stdout, stderr))
     }
 
+    /// Runs the command, leaving the interpretation of the result to the caller.
+    pub fn output_raw(mut self) -> io::Result<Output> {
+        self.cmd.output()
+    }
+
     pub fn run_kcov(mut self) -> Result<(), Error> {
         match self.cmd.status() {
             Ok(ref s) if s.success() => Ok(()),
@@ -125,3 +213,94 @@ impl Cmd {
 pub fn cargo(subcommand: &'static str) -> Cmd {
     Cmd::new("cargo", subcommand)
 }
+
+#[test]
+fn test_cargo_options_args() {
+    fn strs(args: Vec<OsString>) -> Vec<String> {
+        args.into_iter().map(|a| a.into_string().unwrap()).collect()
+    }
+
+    let options = CargoOptions::default();
+    assert!(options.clean_args(None).is_empty());
+    assert!(options.test_args().is_empty());
+    assert!(options.metadata_args().is_empty());
+
+    let options = CargoOptions {
+        all: true,
+        lib: true,
+        bins: vec!["a".to_owned(), "b".to_owned()],
+        tests: vec!["c".to_owned()],
+        features: Some("serde std".to_owned()),
+        no_default_features: true,
+        release: true,
+        target: Some("i586-unknown-linux-gnu".to_owned()),
+        manifest_path: Some(PathBuf::from("inner/Cargo.toml")),
+        jobs: Some(4),
+        no_fail_fast: true,
+        ..CargoOptions::default()
+    };
+    assert_eq!(
+        strs(options.clean_args(Some("inner"))),
+        [
+            "--package",
+            "inner",
+            "--manifest-path",
+            "inner/Cargo.toml",
+            "--release",
+            "--target",
+            "i586-unknown-linux-gnu",
+        ]
+    );
+    assert_eq!(
+        strs(options.test_args()),
+        [
+            "--lib",
+            "--bin",
+            "a",
+            "--bin",
+            "b",
+            "--test",
+            "c",
+            "--jobs",
+            "4",
+            "--release",
+            "--target",
+            "i586-unknown-linux-gnu",
+            "--manifest-path",
+            "inner/Cargo.toml",
+            "--features",
+            "serde std",
+            "--no-default-features",
+            "--no-fail-fast",
+            "--all",
+        ]
+    );
+    assert_eq!(
+        strs(options.build_args()),
+        [
+            "--jobs",
+            "4",
+            "--release",
+            "--target",
+            "i586-unknown-linux-gnu",
+            "--manifest-path",
+            "inner/Cargo.toml",
+            "--features",
+            "serde std",
+            "--no-default-features",
+        ]
+    );
+    assert_eq!(
+        strs(options.metadata_args()),
+        ["--manifest-path", "inner/Cargo.toml"]
+    );
+    let options = CargoOptions {
+        packages: vec!["a".to_owned(), "b".to_owned()],
+        ..CargoOptions::default()
+    };
+    assert_eq!(
+        strs(options.test_args()),
+        ["--package", "a", "--package", "b"]
+    );
+    assert_eq!(strs(options.pkgid_args()), strs(options.metadata_args()));
+}
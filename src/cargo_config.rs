@@ -0,0 +1,321 @@
+//! Reads settings from the cargo configuration files (`.cargo/config.toml`).
+//!
+//! Like cargo, the files are searched from the current directory upwards, then in `$CARGO_HOME`,
+//! and the first file defining a key wins. Only the keys cargo-kcov needs are supported.
+
+use std::env::{var, var_os};
+use std::fs::read_to_string;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use toml::Value;
+
+use install::cargo_home;
+
+/// A cfg value of a target, e.g. `unix` or `target_os="linux"`.
+type Cfg = (String, Option<String>);
+
+/// Finds the runner of executables built for `triple`, from `CARGO_TARGET_<TRIPLE>_RUNNER`,
+/// `target.<triple>.runner` or a `target.'cfg(...)'.runner` matching the target. Returns the
+/// program followed by its arguments.
+pub fn find_target_runner(triple: &str, cwd: &Path) -> Option<Vec<String>> {
+    find_runner(
+        triple,
+        cwd,
+        |key| var(key).ok(),
+        cargo_home().as_deref(),
+        || target_cfgs(triple),
+    )
+}
+
+/// Implements `find_target_runner`, reading the environment variables by `env`. `cfgs` gives the
+/// cfg values of the target, and is only called if a configuration file has a `cfg(...)` target.
+///
+/// Like cargo, `target.<triple>` takes precedence over the `cfg(...)` targets.
+fn find_runner<E, C>(
+    triple: &str,
+    cwd: &Path,
+    env: E,
+    cargo_home: Option<&Path>,
+    cfgs: C,
+) -> Option<Vec<String>>
+where
+    E: Fn(&str) -> Option<String>,
+    C: Fn() -> Vec<Cfg>,
+{
+    let env_key = format!(
+        "CARGO_TARGET_{}_RUNNER",
+        triple.to_uppercase().replace(['-', '.'], "_")
+    );
+    if let Some(runner) = env(&env_key) {
+        return split_runner(&Value::String(runner), cwd);
+    }
+
+    let configs = config_files(cwd, cargo_home)
+        .into_iter()
+        .filter_map(|(path, dir)| {
+            let config = read_to_string(&path).ok()?.parse::<Value>().ok()?;
+            Some((config, dir))
+        })
+        .collect::<Vec<_>>();
+    for (config, dir) in &configs {
+        if let Some(runner) = config
+            .get("target")
+            .and_then(|t| t.get(triple))
+            .and_then(|t| t.get("runner"))
+        {
+            return split_runner(runner, dir);
+        }
+    }
+
+    let mut target_cfgs = None;
+    for (config, dir) in &configs {
+        let targets = match config.get("target").and_then(Value::as_table) {
+            Some(targets) => targets,
+            None => continue,
+        };
+        for (key, target) in targets {
+            let runner = match target.get("runner") {
+                Some(runner) if key.starts_with("cfg(") => runner,
+                _ => continue,
+            };
+            let target_cfgs = target_cfgs.get_or_insert_with(&cfgs);
+            if matches_cfg(key, target_cfgs) == Some(true) {
+                return split_runner(runner, dir);
+            }
+        }
+    }
+    None
+}
+
+/// Lists the configuration files in the order of precedence, each with the directory relative
+/// paths in it are resolved from.
+fn config_files(cwd: &Path, cargo_home: Option<&Path>) -> Vec<(PathBuf, PathBuf)> {
+    let mut dirs = cwd
+        .ancestors()
+        .map(|dir| (dir.join(".cargo"), dir.to_owned()))
+        .collect::<Vec<_>>();
+    if let Some(cargo_home) = cargo_home {
+        if !dirs.iter().any(|(dir, _)| dir == cargo_home) {
+            let parent = cargo_home.parent().unwrap_or(cargo_home).to_owned();
+            dirs.push((cargo_home.to_owned(), parent));
+        }
+    }
+
+    let mut files = Vec::new();
+    for (dir, base) in dirs {
+        for name in &["config.toml", "config"] {
+            let path = dir.join(name);
+            if path.is_file() {
+                files.push((path, base.clone()));
+                break;
+            }
+        }
+    }
+    files
+}
+
+/// The cfg values of `triple`, from `rustc --print cfg`. Empty if rustc fails.
+fn target_cfgs(triple: &str) -> Vec<Cfg> {
+    let rustc = var_os("RUSTC").unwrap_or_else(|| "rustc".into());
+    match Command::new(rustc)
+        .args(["--print", "cfg", "--target", triple])
+        .output()
+    {
+        Ok(ref output) if output.status.success() => {
+            parse_cfgs(&String::from_utf8_lossy(&output.stdout))
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Parses the output of `rustc --print cfg`.
+fn parse_cfgs(output: &str) -> Vec<Cfg> {
+    output
+        .lines()
+        .map(|line| match line.find('=') {
+            Some(i) => (
+                line[..i].to_owned(),
+                Some(line[i + 1..].trim_matches('"').to_owned()),
+            ),
+            None => (line.to_owned(), None),
+        })
+        .collect()
+}
+
+/// Evaluates a `cfg(...)` target key with the cfg values of the target. `None` if the key cannot
+/// be parsed.
+fn matches_cfg(key: &str, cfgs: &[Cfg]) -> Option<bool> {
+    let mut rest = key.strip_prefix("cfg(")?.trim_end().strip_suffix(')')?;
+    let result = eval_predicate(&mut rest, cfgs)?;
+    if rest.trim().is_empty() {
+        Some(result)
+    } else {
+        None
+    }
+}
+
+/// Evaluates the predicate at the start of `s`, i.e. `name`, `name = "value"`, `all(...)`,
+/// `any(...)` or `not(...)`, and advances `s` past it.
+fn eval_predicate(s: &mut &str, cfgs: &[Cfg]) -> Option<bool> {
+    let trimmed = s.trim_start();
+    let len = trimmed
+        .find(|c: char| !c.is_alphanumeric() && c != '_')
+        .unwrap_or(trimmed.len());
+    if len == 0 {
+        return None;
+    }
+    let name = &trimmed[..len];
+    *s = trimmed[len..].trim_start();
+
+    if let Some(rest) = s.strip_prefix('(') {
+        *s = rest;
+        let mut values = Vec::new();
+        loop {
+            *s = s.trim_start();
+            if let Some(rest) = s.strip_prefix(')') {
+                *s = rest;
+                break;
+            }
+            values.push(eval_predicate(s, cfgs)?);
+            *s = s.trim_start();
+            match s.strip_prefix(',') {
+                Some(rest) => *s = rest,
+                None if s.starts_with(')') => {}
+                None => return None,
+            }
+        }
+        match name {
+            "all" => Some(values.iter().all(|&v| v)),
+            "any" => Some(values.iter().any(|&v| v)),
+            "not" if values.len() == 1 => Some(!values[0]),
+            _ => None,
+        }
+    } else if let Some(rest) = s.strip_prefix('=') {
+        let rest = rest.trim_start().strip_prefix('"')?;
+        let end = rest.find('"')?;
+        let value = &rest[..end];
+        *s = &rest[end + 1..];
+        Some(
+            cfgs.iter()
+                .any(|(n, v)| n == name && v.as_deref() == Some(value)),
+        )
+    } else {
+        Some(cfgs.iter().any(|(n, v)| n == name && v.is_none()))
+    }
+}
+
+/// Splits a runner given as a string or an array. Like cargo, a program path containing a `/` is
+/// relative to the directory containing `.cargo`.
+fn split_runner(value: &Value, base: &Path) -> Option<Vec<String>> {
+    let mut words = match *value {
+        Value::String(ref s) => s.split_whitespace().map(|w| w.to_owned()).collect(),
+        Value::Array(ref values) => values
+            .iter()
+            .map(|v| v.as_str().map(|s| s.to_owned()))
+            .collect::<Option<Vec<_>>>()?,
+        _ => return None,
+    };
+    if words.is_empty() {
+        return None;
+    }
+    if words[0].contains('/') && Path::new(&words[0]).is_relative() {
+        words[0] = base.join(&words[0]).to_string_lossy().into_owned();
+    }
+    Some(words)
+}
+
+#[test]
+fn test_find_target_runner() {
+    use std::fs::{create_dir_all, write};
+    use tempdir::TempDir;
+
+    let root = TempDir::new("cargo-kcov-config").unwrap();
+    let home = root.path().join("home");
+    let project = root.path().join("project");
+    let member = project.join("member");
+    create_dir_all(&home).unwrap();
+    create_dir_all(project.join(".cargo")).unwrap();
+    create_dir_all(member.join(".cargo")).unwrap();
+    write(
+        project.join(".cargo/config.toml"),
+        r#"
+            [target.aarch64-unknown-linux-gnu]
+            runner = "qemu-aarch64 -L /usr/aarch64-linux-gnu"
+
+            [target.armv7-unknown-linux-gnueabihf]
+            runner = ["tools/run-arm", "--quiet"]
+        "#,
+    )
+    .unwrap();
+    write(
+        member.join(".cargo/config"),
+        "[target.aarch64-unknown-linux-gnu]\nrunner = 'qemu-aarch64-static'\n",
+    )
+    .unwrap();
+    write(
+        home.join("config.toml"),
+        r#"
+            [target.'cfg(all(target_arch = "riscv64", not(windows)))']
+            runner = "qemu-riscv64"
+        "#,
+    )
+    .unwrap();
+
+    let riscv_cfgs = parse_cfgs("target_arch=\"riscv64\"\ntarget_os=\"linux\"\nunix\n");
+    let find =
+        |triple, cwd: &Path| find_runner(triple, cwd, |_| None, Some(&home), || riscv_cfgs.clone());
+    assert_eq!(
+        find("aarch64-unknown-linux-gnu", &project).unwrap(),
+        ["qemu-aarch64", "-L", "/usr/aarch64-linux-gnu"]
+    );
+    assert_eq!(
+        find("aarch64-unknown-linux-gnu", &member).unwrap(),
+        ["qemu-aarch64-static"]
+    );
+    assert_eq!(
+        find("armv7-unknown-linux-gnueabihf", &member).unwrap(),
+        [&*project.join("tools/run-arm").to_string_lossy(), "--quiet"]
+    );
+    assert_eq!(
+        find("riscv64gc-unknown-linux-gnu", &member).unwrap(),
+        ["qemu-riscv64"]
+    );
+    assert_eq!(
+        find_runner(
+            "x86_64-unknown-linux-gnu",
+            &member,
+            |_| None,
+            Some(&home),
+            Vec::new
+        ),
+        None
+    );
+    let env = |key: &str| {
+        if key == "CARGO_TARGET_AARCH64_UNKNOWN_LINUX_GNU_RUNNER" {
+            Some("qemu-aarch64 -cpu max".to_owned())
+        } else {
+            None
+        }
+    };
+    assert_eq!(
+        find_runner("aarch64-unknown-linux-gnu", &member, env, None, Vec::new).unwrap(),
+        ["qemu-aarch64", "-cpu", "max"]
+    );
+
+    let cfgs = parse_cfgs("target_feature=\"sse\"\ntarget_feature=\"sse2\"\nunix\n");
+    assert_eq!(matches_cfg("cfg(unix)", &cfgs), Some(true));
+    assert_eq!(matches_cfg("cfg(windows)", &cfgs), Some(false));
+    assert_eq!(
+        matches_cfg("cfg(target_feature = \"sse2\")", &cfgs),
+        Some(true)
+    );
+    assert_eq!(
+        matches_cfg("cfg(any(windows, not(unix)))", &cfgs),
+        Some(false)
+    );
+    assert_eq!(matches_cfg("cfg(all())", &cfgs), Some(true));
+    assert_eq!(matches_cfg("cfg(unix", &cfgs), None);
+    assert_eq!(matches_cfg("cfg(unix windows)", &cfgs), None);
+    assert_eq!(matches_cfg("cfg(not(unix, windows))", &cfgs), None);
+}
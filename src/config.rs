@@ -0,0 +1,243 @@
+//! Project configuration of cargo-kcov.
+//!
+//! The configuration is read from `[workspace.metadata.kcov]` in the workspace manifest, or
+//! `[package.metadata.kcov]` of the root package if the former does not exist:
+//!
+//! ```toml
+//! [package.metadata.kcov]
+//! feature-sets = ["no-default-features", "default", "serde"]
+//! kcov = "tools/kcov"
+//! kcov-version = "v38"
+//! exclude-line = ["cov:ignore-line", "unreachable!"]
+//! exclude-region = ["cov:ignore-start", "cov:ignore-end"]
+//! exclude-no-coverage = true
+//! exclude-tests = true
+//! ```
+
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+use errors::Error;
+use exclude::Exclusions;
+use kcov::KcovVersion;
+
+#[derive(Default, Debug, PartialEq)]
+pub struct Config {
+    /// Feature sets used by `--feature-matrix`.
+    pub feature_sets: Vec<String>,
+    /// The kcov executable, relative to the workspace root if it contains a `/`.
+    pub kcov_path: Option<PathBuf>,
+    /// The kcov version required by the project, e.g. `v38`.
+    pub kcov_version: Option<String>,
+    /// Markers excluding code from the coverage.
+    pub exclusions: Exclusions,
+}
+
+impl Config {
+    /// Extracts the configuration from the output of `cargo metadata --format-version 1`.
+    pub fn from_metadata(metadata: &Value, workspace_root: &Path) -> Result<Config, Error> {
+        let root_manifest_path = workspace_root.join("Cargo.toml");
+        let table = match metadata["metadata"]["kcov"] {
+            Value::Null => metadata["packages"]
+                .as_array()
+                .and_then(|packages| {
+                    packages.iter().find(|package| {
+                        package["manifest_path"].as_str().map(Path::new)
+                            == Some(&root_manifest_path)
+                    })
+                })
+                .map_or(&Value::Null, |package| &package["metadata"]["kcov"]),
+            ref table => table,
+        };
+
+        let mut config = Config::default();
+        match *table {
+            Value::Null => return Ok(config),
+            Value::Object(_) => {}
+            _ => {
+                return Err(Error::Config(
+                    "`metadata.kcov` should be a table".to_owned(),
+                ))
+            }
+        }
+
+        config.feature_sets = get_string_array(table, "feature-sets")?;
+        config.kcov_path = get_string(table, "kcov")?.map(|path| {
+            if path.contains('/') {
+                workspace_root.join(path)
+            } else {
+                PathBuf::from(path)
+            }
+        });
+        config.kcov_version = get_string(table, "kcov-version")?;
+        if let Some(ref version) = config.kcov_version {
+            if KcovVersion::parse(&format!("kcov {}", version)).is_none() {
+                return Err(Error::Config(format!(
+                    "`kcov-version` should be a kcov version like \"v38\", found {:?}",
+                    version
+                )));
+            }
+        }
+        if !table["exclude-line"].is_null() {
+            config.exclusions.line_markers = get_string_array(table, "exclude-line")?;
+        }
+        if !table["exclude-region"].is_null() {
+            let mut markers = get_string_array(table, "exclude-region")?;
+            config.exclusions.region_markers = match markers.len() {
+                0 => None,
+                2 => {
+                    let end = markers.pop().unwrap();
+                    Some((markers.pop().unwrap(), end))
+                }
+                _ => return Err(Error::Config(
+                    "`exclude-region` should be an array of the start and end markers, or empty"
+                        .to_owned(),
+                )),
+            };
+        }
+        if let Some(no_coverage_items) = get_bool(table, "exclude-no-coverage")? {
+            config.exclusions.no_coverage_items = no_coverage_items;
+        }
+        if let Some(test_code) = get_bool(table, "exclude-tests")? {
+            config.exclusions.test_code = test_code;
+        }
+        Ok(config)
+    }
+}
+
+fn get_bool(table: &Value, key: &str) -> Result<Option<bool>, Error> {
+    match table[key] {
+        Value::Null => Ok(None),
+        Value::Bool(b) => Ok(Some(b)),
+        _ => Err(Error::Config(format!("`{}` should be a boolean", key))),
+    }
+}
+
+fn get_string(table: &Value, key: &str) -> Result<Option<String>, Error> {
+    match table[key] {
+        Value::Null => Ok(None),
+        Value::String(ref s) => Ok(Some(s.clone())),
+        _ => Err(Error::Config(format!("`{}` should be a string", key))),
+    }
+}
+
+fn get_string_array(table: &Value, key: &str) -> Result<Vec<String>, Error> {
+    let strings = match table[key] {
+        Value::Null => return Ok(Vec::new()),
+        Value::Array(ref values) => values
+            .iter()
+            .map(|v| v.as_str().map(|s| s.to_owned()))
+            .collect(),
+        _ => None,
+    };
+    strings.ok_or_else(|| Error::Config(format!("`{}` should be an array of strings", key)))
+}
+
+#[test]
+fn test_config_from_metadata() {
+    use serde_json::from_str;
+
+    let root = Path::new("/path/to/great-project");
+    let parse = |json: &str| Config::from_metadata(&from_str(json).unwrap(), root);
+
+    assert_eq!(
+        parse(r#"{"packages": [], "metadata": null}"#).unwrap(),
+        Config::default()
+    );
+
+    let package_config = r#"{
+        "packages": [
+            {
+                "manifest_path": "/path/to/great-project/inner/Cargo.toml",
+                "metadata": {"kcov": {"feature-sets": ["inner"]}}
+            },
+            {
+                "manifest_path": "/path/to/great-project/Cargo.toml",
+                "metadata": {"kcov": {"feature-sets": ["default", "serde"]}}
+            }
+        ],
+        "metadata": null
+    }"#;
+    assert_eq!(
+        parse(package_config).unwrap().feature_sets,
+        ["default", "serde"]
+    );
+
+    let workspace_config = r#"{
+        "packages": [
+            {
+                "manifest_path": "/path/to/great-project/Cargo.toml",
+                "metadata": {"kcov": {"feature-sets": ["default", "serde"]}}
+            }
+        ],
+        "metadata": {"kcov": {"feature-sets": ["all-features"]}}
+    }"#;
+    assert_eq!(
+        parse(workspace_config).unwrap().feature_sets,
+        ["all-features"]
+    );
+
+    match parse(r#"{"metadata": {"kcov": {"feature-sets": "serde"}}}"#) {
+        Err(Error::Config(_)) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+    match parse(r#"{"metadata": {"kcov": {"feature-sets": [1]}}}"#) {
+        Err(Error::Config(_)) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    let kcov_config =
+        parse(r#"{"metadata": {"kcov": {"kcov": "tools/kcov", "kcov-version": "v38"}}}"#).unwrap();
+    assert_eq!(kcov_config.kcov_path.unwrap(), root.join("tools/kcov"));
+    assert_eq!(kcov_config.kcov_version.unwrap(), "v38");
+    assert_eq!(
+        parse(r#"{"metadata": {"kcov": {"kcov": "kcov-38"}}}"#)
+            .unwrap()
+            .kcov_path
+            .unwrap(),
+        Path::new("kcov-38")
+    );
+    match parse(r#"{"metadata": {"kcov": {"kcov-version": "latest"}}}"#) {
+        Err(Error::Config(_)) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+    match parse(r#"{"metadata": {"kcov": {"kcov-version": 38}}}"#) {
+        Err(Error::Config(_)) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+
+    assert_eq!(
+        parse(r#"{"metadata": {"kcov": {}}}"#).unwrap().exclusions,
+        Exclusions::default()
+    );
+    let exclusions = parse(
+        r#"{"metadata": {"kcov": {
+            "exclude-line": ["LCOV_EXCL_LINE"],
+            "exclude-region": [],
+            "exclude-no-coverage": false,
+            "exclude-tests": true
+        }}}"#,
+    )
+    .unwrap()
+    .exclusions;
+    assert_eq!(exclusions.line_markers, ["LCOV_EXCL_LINE"]);
+    assert_eq!(exclusions.region_markers, None);
+    assert!(!exclusions.no_coverage_items);
+    assert!(exclusions.test_code);
+    assert_eq!(
+        parse(r#"{"metadata": {"kcov": {"exclude-region": ["START", "END"]}}}"#)
+            .unwrap()
+            .exclusions
+            .region_markers,
+        Some(("START".to_owned(), "END".to_owned()))
+    );
+    match parse(r#"{"metadata": {"kcov": {"exclude-region": ["START"]}}}"#) {
+        Err(Error::Config(_)) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+    match parse(r#"{"metadata": {"kcov": {"exclude-no-coverage": "no"}}}"#) {
+        Err(Error::Config(_)) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+}
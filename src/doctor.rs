@@ -0,0 +1,491 @@
+//! Diagnostics of the environment, used by `cargo kcov doctor`.
+//!
+//! Each prerequisite of collecting coverage is checked separately, so all problems are reported at
+//! once together with the commands fixing them.
+
+use std::env::{split_paths, var, var_os};
+use std::fs::{read_to_string, remove_dir_all, remove_file, File};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use term::color::{GREEN, RED, WHITE, YELLOW};
+use term::Attr;
+use toml;
+
+use backend::BackendKind;
+use config::Config;
+use errors::Error;
+use instrument::LlvmTools;
+use kcov::{check_kcov, KcovExecutable, KcovVersion};
+use options::CoverageOptions;
+use output_dir::{is_protected, MARKER_FILE};
+use terminal;
+use {find_workspace, get_runner, Workspace};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    Warning,
+    Error,
+}
+
+/// Result of checking one prerequisite.
+#[derive(Debug, Clone)]
+pub struct Check {
+    pub title: &'static str,
+    pub status: Status,
+    pub message: String,
+    /// Explanations of how to fix the problem.
+    pub notes: Vec<String>,
+    /// Commands fixing the problem.
+    pub commands: Vec<String>,
+}
+
+impl Check {
+    fn new<S: Into<String>>(title: &'static str, status: Status, message: S) -> Check {
+        Check {
+            title,
+            status,
+            message: message.into(),
+            notes: Vec::new(),
+            commands: Vec::new(),
+        }
+    }
+
+    fn note<S: Into<String>>(mut self, note: S) -> Check {
+        self.notes.push(note.into());
+        self
+    }
+
+    fn command<S: Into<String>>(mut self, command: S) -> Check {
+        self.commands.push(command.into());
+        self
+    }
+
+    /// Prints the result in the style of the error notes.
+    pub fn print(&self) {
+        let mut t = terminal::stderr();
+        let (color, label) = match self.status {
+            Status::Ok => (GREEN, "ok"),
+            Status::Warning => (YELLOW, "warning"),
+            Status::Error => (RED, "error"),
+        };
+        t.fg(color).unwrap();
+        t.attr(Attr::Bold).unwrap();
+        write!(t, "{:>12}", label).unwrap();
+        t.reset().unwrap();
+        writeln!(t, " {}: {}", self.title, self.message).unwrap();
+
+        for note in &self.notes {
+            t.fg(GREEN).unwrap();
+            t.attr(Attr::Bold).unwrap();
+            t.write_all(b"note: ").unwrap();
+            t.reset().unwrap();
+            writeln!(t, "{}", note).unwrap();
+        }
+        if !self.commands.is_empty() {
+            writeln!(t).unwrap();
+            for command in &self.commands {
+                t.fg(WHITE).unwrap();
+                t.write_all(b"    $ ").unwrap();
+                t.reset().unwrap();
+                writeln!(t, "{}\n", command).unwrap();
+            }
+        }
+    }
+}
+
+/// Checks every prerequisite of collecting coverage.
+pub fn diagnose(options: &CoverageOptions) -> Vec<Check> {
+    let mut checks = Vec::new();
+
+    // The workspace is checked last, but its configuration may choose the kcov executable.
+    let workspace = find_workspace(options);
+    let default_config = Config::default();
+    let config = workspace.as_ref().map_or(&default_config, |w| &w.config);
+    let runner = match workspace {
+        Ok(ref workspace) => get_runner(options, workspace),
+        Err(_) if options.runner.is_some() => Err(Error::InvalidRunner(
+            "the placeholders need the cargo workspace".to_owned(),
+        )),
+        Err(_) => Ok(None),
+    };
+    let uses_kcov = options.backend != Some(BackendKind::InstrumentCoverage);
+    let kcov = if !uses_kcov {
+        checks.push(check_llvm_tools());
+        None
+    } else {
+        match runner.and_then(|runner| check_kcov(options, config, runner.as_ref())) {
+            Ok(kcov) => {
+                checks.push(check_kcov_version(&kcov));
+                Some(kcov)
+            }
+            Err(e) => {
+                checks.push(check_kcov_error(&e));
+                None
+            }
+        }
+    };
+
+    // With a runner, kcov does not run on this host, so neither its libraries nor the ptrace
+    // restrictions of the host matter.
+    if cfg!(target_os = "linux") && uses_kcov && options.runner.is_none() {
+        if let Some(ref kcov) = kcov {
+            checks.push(check_libraries(&kcov.path));
+        }
+        let (has_cap_sys_ptrace, seccomp) =
+            parse_proc_status(&read_to_string("/proc/self/status").unwrap_or_default());
+        checks.push(check_ptrace_scope(has_cap_sys_ptrace));
+        checks.push(check_container(has_cap_sys_ptrace, seccomp));
+    }
+
+    match workspace {
+        Ok(workspace) => {
+            if let Some(ref kcov) = kcov {
+                checks.push(check_tracing(kcov, &workspace.target_path));
+            }
+            checks.push(check_output_dir(options, &workspace));
+            checks.push(check_debuginfo(options, &workspace));
+        }
+        Err(e) => checks.push(Check::new(
+            "workspace",
+            Status::Warning,
+            format!("cannot read the cargo workspace, {}", e),
+        )),
+    }
+
+    checks
+}
+
+fn install_kcov_check(check: Check) -> Check {
+    let check =
+        check.note("build dependencies of kcov are listed in https://users.rust-lang.org/t/650");
+    if cfg!(target_os = "macos") {
+        check.command("brew install cmake")
+    } else {
+        check.command(
+            "sudo apt-get install cmake g++ pkg-config libcurl4-openssl-dev libelf-dev \
+             libdw-dev binutils-dev libiberty-dev",
+        )
+    }
+    .command("cargo kcov install-kcov")
+}
+
+fn check_kcov_error(e: &Error) -> Check {
+    match *e {
+        Error::UnsupportedOS => Check::new("kcov", Status::Error, e.to_string())
+            .note("collect coverage on Linux or macOS, e.g. in a CI job"),
+        Error::KcovVersionMismatch(_, ref pin) => Check::new("kcov", Status::Error, e.to_string())
+            .note(format!("the project pins kcov {} with `kcov-version`", pin))
+            .command(format!("cargo kcov install-kcov {}", pin)),
+        _ => install_kcov_check(Check::new("kcov", Status::Error, e.to_string())),
+    }
+}
+
+fn check_kcov_version(kcov: &KcovExecutable) -> Check {
+    let message = format!("found {}", kcov);
+    match kcov.version.as_ref().and_then(KcovVersion::known_issue) {
+        Some(issue) => install_kcov_check(Check::new("kcov", Status::Warning, message).note(issue)),
+        None if kcov.version.is_none() => Check::new("kcov", Status::Warning, message)
+            .note("cannot parse the output of `kcov --version`, assuming a recent version"),
+        None => Check::new("kcov", Status::Ok, message),
+    }
+}
+
+/// Checks whether `llvm-profdata` and `llvm-cov` are installed for the instrument-coverage backend.
+fn check_llvm_tools() -> Check {
+    match LlvmTools::find() {
+        Ok(tools) => Check::new(
+            "llvm tools",
+            Status::Ok,
+            format!(
+                "found {} and {}",
+                tools.profdata.display(),
+                tools.cov.display()
+            ),
+        ),
+        Err(e) => Check::new("llvm tools", Status::Error, e.to_string())
+            .note("the tools must match the LLVM version of rustc")
+            .command("rustup component add llvm-tools-preview"),
+    }
+}
+
+/// Checks whether the shared libraries needed by kcov (e.g. `libdw`) can be loaded.
+fn check_libraries(kcov_path: &Path) -> Check {
+    let path = match find_in_path(kcov_path) {
+        Some(path) => path,
+        None => return Check::new("libraries", Status::Warning, "cannot locate kcov to check"),
+    };
+    let output = match Command::new("ldd").arg(&path).output() {
+        Ok(ref o) if o.status.success() => String::from_utf8_lossy(&o.stdout).into_owned(),
+        _ => return Check::new("libraries", Status::Warning, "cannot run `ldd` to check"),
+    };
+    let missing = missing_libraries(&output);
+    if missing.is_empty() {
+        Check::new(
+            "libraries",
+            Status::Ok,
+            "all shared libraries of kcov are found",
+        )
+    } else {
+        install_kcov_check(Check::new(
+            "libraries",
+            Status::Error,
+            format!("kcov cannot load {}", missing.join(", ")),
+        ))
+    }
+}
+
+/// Extracts the libraries reported as `not found` by `ldd`.
+fn missing_libraries(ldd_output: &str) -> Vec<&str> {
+    ldd_output
+        .lines()
+        .filter(|line| line.contains("not found"))
+        .filter_map(|line| line.split_whitespace().next())
+        .collect()
+}
+
+fn find_in_path(program: &Path) -> Option<PathBuf> {
+    if program.components().count() > 1 {
+        return Some(program.to_owned());
+    }
+    split_paths(&var_os("PATH")?)
+        .map(|dir| dir.join(program))
+        .find(|path| path.is_file())
+}
+
+/// Checks `kernel.yama.ptrace_scope`, which may forbid kcov to trace the tests.
+fn check_ptrace_scope(has_cap_sys_ptrace: bool) -> Check {
+    let scope = read_to_string("/proc/sys/kernel/yama/ptrace_scope")
+        .ok()
+        .and_then(|s| s.trim().parse::<u32>().ok());
+    match scope {
+        None | Some(0) | Some(1) => Check::new("ptrace", Status::Ok, "ptrace is permitted"),
+        Some(2) if has_cap_sys_ptrace => Check::new(
+            "ptrace",
+            Status::Ok,
+            "ptrace is permitted by CAP_SYS_PTRACE",
+        ),
+        Some(2) => Check::new(
+            "ptrace",
+            Status::Error,
+            "kernel.yama.ptrace_scope is 2, only processes with CAP_SYS_PTRACE can use ptrace",
+        )
+        .note("allow tracing child processes with")
+        .command("sudo sysctl kernel.yama.ptrace_scope=1"),
+        Some(_) => Check::new(
+            "ptrace",
+            Status::Error,
+            "kernel.yama.ptrace_scope is 3, ptrace is disabled until reboot",
+        )
+        .note("change `kernel.yama.ptrace_scope` in /etc/sysctl.d/ to 1, and reboot"),
+    }
+}
+
+/// Number of the `CAP_SYS_PTRACE` capability.
+const CAP_SYS_PTRACE: u32 = 19;
+
+/// Checks whether we are in a container which forbids ptrace.
+fn check_container(has_cap_sys_ptrace: bool, seccomp: Option<u32>) -> Check {
+    if has_cap_sys_ptrace || seccomp != Some(2) {
+        Check::new(
+            "container",
+            Status::Ok,
+            "ptrace is not restricted by seccomp",
+        )
+    } else {
+        Check::new(
+            "container",
+            Status::Warning,
+            "running under a seccomp filter without CAP_SYS_PTRACE, ptrace may be blocked",
+        )
+        .note("in Docker, run the container with")
+        .command("docker run --cap-add SYS_PTRACE --security-opt seccomp=unconfined ...")
+    }
+}
+
+/// Extracts whether `CAP_SYS_PTRACE` is effective and the seccomp mode from `/proc/self/status`.
+fn parse_proc_status(status: &str) -> (bool, Option<u32>) {
+    let mut has_cap_sys_ptrace = false;
+    let mut seccomp = None;
+    for line in status.lines() {
+        if let Some(caps) = line.strip_prefix("CapEff:") {
+            if let Ok(caps) = u64::from_str_radix(caps.trim(), 16) {
+                has_cap_sys_ptrace = caps & (1 << CAP_SYS_PTRACE) != 0;
+            }
+        } else if let Some(mode) = line.strip_prefix("Seccomp:") {
+            seccomp = mode.trim().parse().ok();
+        }
+    }
+    (has_cap_sys_ptrace, seccomp)
+}
+
+/// Runs kcov on a trivial command, to check it can really trace processes.
+fn check_tracing(kcov: &KcovExecutable, target_path: &Path) -> Check {
+    let out_path = target_path.join("kcov-doctor");
+    let status = kcov
+        .command(&[
+            out_path.as_os_str(),
+            "sh".as_ref(),
+            "-c".as_ref(),
+            "true".as_ref(),
+        ])
+        .output_raw();
+    let _ = remove_dir_all(&out_path);
+    match status {
+        Ok(ref o) if o.status.success() => {
+            Check::new("tracing", Status::Ok, "kcov can trace processes")
+        }
+        _ => Check::new(
+            "tracing",
+            Status::Error,
+            "kcov failed to trace `sh -c true`",
+        )
+        .note("ptrace is probably blocked, see the `ptrace` and `container` checks"),
+    }
+}
+
+/// Checks whether the output directory can be used.
+fn check_output_dir(options: &CoverageOptions, workspace: &Workspace) -> Check {
+    let cov_path = options.output_path(&workspace.target_path);
+    let home = var_os("HOME").map(PathBuf::from);
+    let mut protected: Vec<&Path> = vec![&workspace.root, &workspace.target_path];
+    if let Some(ref home) = home {
+        protected.push(home);
+    }
+
+    if is_protected(&cov_path, &protected) {
+        return Check::new(
+            "output",
+            Status::Error,
+            format!("`{}` contains protected files", cov_path.display()),
+        )
+        .note("please choose a dedicated directory with `--output`");
+    }
+
+    if cov_path.is_dir() {
+        let is_owned = cov_path.join(MARKER_FILE).is_file();
+        let is_empty = cov_path
+            .read_dir()
+            .map(|mut entries| entries.next().is_none())
+            .unwrap_or(false);
+        if !is_owned && !is_empty {
+            return Check::new(
+                "output",
+                Status::Warning,
+                format!("`{}` is not created by cargo-kcov", cov_path.display()),
+            )
+            .note("pass `--append` to accumulate coverage into it, or delete it manually:")
+            .command(format!("rm -r {}", cov_path.display()));
+        }
+    }
+
+    let existing = cov_path
+        .ancestors()
+        .find(|p| p.is_dir())
+        .unwrap_or(&cov_path);
+    let probe = existing.join(".cargo-kcov-doctor");
+    match File::create(&probe) {
+        Ok(_) => {
+            let _ = remove_file(&probe);
+            Check::new(
+                "output",
+                Status::Ok,
+                format!("`{}` is writable", cov_path.display()),
+            )
+        }
+        Err(e) => Check::new(
+            "output",
+            Status::Error,
+            format!("cannot write to `{}`, {}", existing.display(), e),
+        )
+        .note("choose a writable directory with `--output`"),
+    }
+}
+
+/// Checks whether the test executables are built with debuginfo, which kcov needs to map the
+/// machine code to source lines.
+fn check_debuginfo(options: &CoverageOptions, workspace: &Workspace) -> Check {
+    let (profile, parent) = if options.cargo.release {
+        ("bench", "release")
+    } else {
+        ("test", "dev")
+    };
+
+    let manifest = read_to_string(workspace.root.join("Cargo.toml"))
+        .ok()
+        .and_then(|s| s.parse::<toml::Value>().ok());
+    let debug = [profile, parent].iter().find_map(|name| {
+        let key = format!("CARGO_PROFILE_{}_DEBUG", name.to_uppercase());
+        match var(&key) {
+            Ok(value) => Some((key, value)),
+            Err(_) => manifest
+                .as_ref()
+                .and_then(|m| m.get("profile")?.get(name)?.get("debug").cloned())
+                .map(|value| (format!("[profile.{}] debug", name), value.to_string())),
+        }
+    });
+    let rustflags = var("RUSTFLAGS").unwrap_or_default();
+
+    let disabled_by = match debug {
+        Some((ref source, ref value)) if is_debuginfo_disabled(value) => {
+            Some(format!("{} = {}", source, value))
+        }
+        None if options.cargo.release => Some("the release profile".to_owned()),
+        _ if rustflags.contains("debuginfo=0") => Some("RUSTFLAGS".to_owned()),
+        _ => None,
+    };
+    match disabled_by {
+        None => Check::new(
+            "debuginfo",
+            Status::Ok,
+            format!("the `{}` profile has debuginfo", profile),
+        ),
+        Some(source) => Check::new(
+            "debuginfo",
+            Status::Warning,
+            format!(
+                "debuginfo of the `{}` profile is disabled by {}, kcov will find no lines",
+                profile, source
+            ),
+        )
+        .note(format!(
+            "add `debug = 1` to `[profile.{}]` in Cargo.toml",
+            profile
+        )),
+    }
+}
+
+/// Checks whether a `debug` value of a cargo profile disables debuginfo.
+fn is_debuginfo_disabled(value: &str) -> bool {
+    matches!(value.trim_matches('"'), "false" | "0" | "none")
+}
+
+#[test]
+fn test_missing_libraries() {
+    let output = "\tlinux-vdso.so.1 (0x00007ffd)\n\
+                  \tlibdw.so.1 => not found\n\
+                  \tlibz.so.1 => /lib/x86_64-linux-gnu/libz.so.1 (0x00007f12)\n\
+                  \tlibelf.so.1 => not found\n";
+    assert_eq!(missing_libraries(output), ["libdw.so.1", "libelf.so.1"]);
+    assert!(missing_libraries("\tlibc.so.6 => /lib/libc.so.6\n").is_empty());
+}
+
+#[test]
+fn test_parse_proc_status() {
+    let status = "Name:\tcat\nCapEff:\t00000000a80425fb\nSeccomp:\t2\n";
+    assert_eq!(parse_proc_status(status), (false, Some(2)));
+    let status = "Name:\tcat\nCapEff:\t000001ffffffffff\nSeccomp:\t0\n";
+    assert_eq!(parse_proc_status(status), (true, Some(0)));
+    assert_eq!(parse_proc_status(""), (false, None));
+}
+
+#[test]
+fn test_is_debuginfo_disabled() {
+    for value in &["false", "0", "\"none\""] {
+        assert!(is_debuginfo_disabled(value), "{}", value);
+    }
+    for value in &["true", "1", "2", "\"line-tables-only\"", "\"full\""] {
+        assert!(!is_debuginfo_disabled(value), "{}", value);
+    }
+}
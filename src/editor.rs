@@ -0,0 +1,217 @@
+//! Line coverage for editor plugins, e.g. to show coverage in the gutter.
+//!
+//! After each run, the output directory gets `lines.json` with the hit count of every instrumented
+//! line of each source file, and `lcov.info`, which the instrument-coverage backend writes anyway
+//! and which is derived from the kcov report otherwise. `cargo kcov lines` queries a single file.
+
+use std::env::current_dir;
+use std::fs::{canonicalize, write, File};
+use std::io::BufWriter;
+use std::path::Path;
+
+use serde_json::{json, Map, Value};
+
+use errors::Error;
+use lcov::{read_line_coverage, write_lcov, BranchCount, FileCoverage, LCOV_FILE};
+
+/// Name of the line coverage file for editor plugins in the output directory.
+pub const LINES_FILE: &str = "lines.json";
+
+/// Version of the format of `lines.json`, increased on incompatible changes.
+pub const LINES_FORMAT_VERSION: u32 = 1;
+
+/// Writes `lines.json`, and `lcov.info` for the kcov backend, into the output directory `cov_path`
+/// from the merged report. Nothing is written without line coverage, e.g. when kcov did not write
+/// a Cobertura report.
+pub fn write_line_files(cov_path: &Path, source_root: &Path) -> Result<(), Error> {
+    let files = match read_line_coverage(cov_path) {
+        Ok(files) => files,
+        Err(_) => return Ok(()),
+    };
+    if cov_path.join("kcov-merged").is_dir() {
+        let lcov =
+            File::create(cov_path.join(LCOV_FILE)).map_err(Error::CannotCreateCoverageDirectory)?;
+        write_lcov(BufWriter::new(lcov), &files).map_err(Error::CannotCreateCoverageDirectory)?;
+    }
+    let json = serde_json::to_string(&lines_json(&files, source_root))?;
+    write(cov_path.join(LINES_FILE), json).map_err(Error::CannotCreateCoverageDirectory)
+}
+
+/// The line coverage of `files` in the format of `lines.json`:
+///
+/// ```json
+/// {
+///   "version": 1,
+///   "source_root": "/path/to/workspace",
+///   "files": {
+///     "src/lib.rs": {
+///       "covered_lines": 2,
+///       "total_lines": 3,
+///       "lines": [[3, 1], [4, 2], [5, 0]],
+///       "branches": [[5, 0, 2]]
+///     }
+///   }
+/// }
+/// ```
+///
+/// The files are keyed by their path relative to `source_root`, or their absolute path outside of
+/// it. `lines` lists the instrumented lines with their hit counts, and `branches` the lines with
+/// branches, with the number of branches taken and the total.
+pub fn lines_json(files: &[FileCoverage], source_root: &Path) -> Value {
+    let mut entries = Map::new();
+    for file in files {
+        let path = file.path.strip_prefix(source_root).unwrap_or(&file.path);
+        entries.insert(path.to_string_lossy().into_owned(), file_json(file));
+    }
+    json!({
+        "version": LINES_FORMAT_VERSION,
+        "source_root": source_root,
+        "files": entries,
+    })
+}
+
+/// The entry of `file` in `lines.json`.
+pub fn file_json(file: &FileCoverage) -> Value {
+    json!({
+        "covered_lines": file.lines.values().filter(|&&hits| hits > 0).count(),
+        "total_lines": file.lines.len(),
+        "lines": file.lines.iter().map(|(&line, &hits)| (line, hits)).collect::<Vec<_>>(),
+        "branches": file
+            .branches
+            .iter()
+            .map(|(&line, count)| (line, count.taken, count.total))
+            .collect::<Vec<_>>(),
+    })
+}
+
+/// Reads the line coverage back from `json` in the format of `lines.json`, e.g. the report of a
+/// previous run to compare with. The relative paths are resolved against `source_root` instead of
+/// the recorded root, so reports of another checkout refer to the same files. Returns `None` for an
+/// unsupported version.
+pub fn parse_lines_json(json: &Value, source_root: &Path) -> Option<Vec<FileCoverage>> {
+    if json["version"].as_u64()? != u64::from(LINES_FORMAT_VERSION) {
+        return None;
+    }
+    let pairs = |value: &Value| -> Vec<Vec<u64>> {
+        value
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| entry.as_array())
+            .map(|entry| entry.iter().filter_map(|n| n.as_u64()).collect())
+            .collect()
+    };
+    let files = json["files"].as_object()?;
+    Some(
+        files
+            .iter()
+            .map(|(path, file)| FileCoverage {
+                path: source_root.join(path),
+                lines: pairs(&file["lines"])
+                    .iter()
+                    .filter(|entry| entry.len() == 2)
+                    .map(|entry| (entry[0] as u32, entry[1]))
+                    .collect(),
+                branches: pairs(&file["branches"])
+                    .iter()
+                    .filter(|entry| entry.len() == 3)
+                    .map(|entry| {
+                        let count = BranchCount {
+                            taken: entry[1],
+                            total: entry[2],
+                        };
+                        (entry[0] as u32, count)
+                    })
+                    .collect(),
+            })
+            .collect(),
+    )
+}
+
+/// Groups the covered lines of `file` (or the uncovered ones if `is_covered` is false) into ranges,
+/// e.g. `[(3, 4), (7, 7)]`. A range ends at the next instrumented line of the other kind, so it
+/// may span lines which are not instrumented, such as blank lines and comments.
+pub fn line_ranges(file: &FileCoverage, is_covered: bool) -> Vec<(u32, u32)> {
+    let mut ranges = Vec::<(u32, u32)>::new();
+    let mut is_extending = false;
+    for (&line, &hits) in &file.lines {
+        if (hits > 0) != is_covered {
+            is_extending = false;
+            continue;
+        }
+        match ranges.last_mut() {
+            Some(range) if is_extending => range.1 = line,
+            _ => ranges.push((line, line)),
+        }
+        is_extending = true;
+    }
+    ranges
+}
+
+/// Finds the coverage of the source file at `path`, relative to the current directory. If it is not
+/// in the report, the only file whose path ends with `path` is used, so paths relative to the
+/// workspace root work from anywhere.
+pub fn find_file<'a>(files: &'a [FileCoverage], path: &Path) -> Option<&'a FileCoverage> {
+    let absolute = current_dir().map(|dir| dir.join(path)).ok();
+    let canonical = absolute.as_ref().and_then(|p| canonicalize(p).ok());
+    let same = files.iter().find(|file| {
+        Some(&file.path) == absolute.as_ref()
+            || (canonical.is_some() && canonicalize(&file.path).ok() == canonical)
+    });
+    same.or_else(|| {
+        let mut suffixed = files.iter().filter(|file| file.path.ends_with(path));
+        match (suffixed.next(), suffixed.next()) {
+            (Some(file), None) => Some(file),
+            _ => None,
+        }
+    })
+}
+
+#[test]
+fn test_editor() {
+    use lcov::parse_lcov;
+    use serde_json::from_str;
+    use std::path::PathBuf;
+
+    let files = parse_lcov(
+        "SF:/ws/src/lib.rs\nDA:3,1\nDA:4,2\nDA:6,0\nDA:9,0\nDA:10,5\nDA:12,1\nBRDA:6,0,0,0\n\
+         BRDA:6,0,1,1\nend_of_record\nSF:/ws/tests/lib.rs\nDA:1,1\nend_of_record\n\
+         SF:/elsewhere/src/gen.rs\nDA:1,0\nend_of_record\n",
+    );
+
+    let json = lines_json(&files, Path::new("/ws"));
+    assert_eq!(json["version"], 1);
+    let lib = &json["files"]["src/lib.rs"];
+    assert_eq!(lib["covered_lines"], 4);
+    assert_eq!(lib["total_lines"], 6);
+    assert_eq!(lib["lines"][2], json!([6, 0]));
+    assert_eq!(lib["branches"], json!([[6, 1, 2]]));
+    assert!(json["files"]["/elsewhere/src/gen.rs"].is_object());
+
+    let parsed =
+        parse_lines_json(&from_str(&json.to_string()).unwrap(), Path::new("/other")).unwrap();
+    assert_eq!(parsed.len(), 3);
+    assert_eq!(parsed[1].path, Path::new("/other/src/lib.rs"));
+    assert_eq!(parsed[1].lines, files[1].lines);
+    assert_eq!(parsed[1].branches, files[1].branches);
+    assert_eq!(parsed[0].path, Path::new("/elsewhere/src/gen.rs"));
+    assert!(parse_lines_json(&json!({"version": 2, "files": {}}), Path::new("/ws")).is_none());
+
+    let lib = &files[1];
+    assert_eq!(lib.path, Path::new("/ws/src/lib.rs"));
+    assert_eq!(line_ranges(lib, true), [(3, 4), (10, 12)]);
+    assert_eq!(line_ranges(lib, false), [(6, 9)]);
+    assert!(line_ranges(&files[0], true).is_empty());
+
+    assert_eq!(
+        find_file(&files, Path::new("/ws/tests/lib.rs")).map(|f| &f.path),
+        Some(&PathBuf::from("/ws/tests/lib.rs"))
+    );
+    assert_eq!(
+        find_file(&files, Path::new("src/gen.rs")).map(|f| &f.path),
+        Some(&PathBuf::from("/elsewhere/src/gen.rs"))
+    );
+    // Ambiguous.
+    assert!(find_file(&files, Path::new("lib.rs")).is_none());
+    assert!(find_file(&files, Path::new("src/main.rs")).is_none());
+}
@@ -1,7 +1,8 @@
-use std::io;
-//use std::error;
 use std::convert::From;
-use std::fmt::Display;
+use std::error;
+use std::fmt::{self, Display};
+use std::io;
+use std::path::PathBuf;
 use std::process::{exit, ExitStatus};
 use std::str::Utf8Error;
 use std::string::FromUtf8Error;
@@ -10,13 +11,15 @@ use serde_json;
 use term::color::{GREEN, RED, WHITE, YELLOW};
 use term::Attr;
 
-use stderr;
+use kcov::{KcovExecutable, KcovVersion};
+use terminal;
 
 #[derive(Debug)]
 pub enum Error {
     UnsupportedOS,
-    KcovTooOld,
+    KcovTooOld(Option<KcovVersion>),
     KcovNotInstalled(io::Error),
+    KcovVersionMismatch(KcovExecutable, String),
     CannotRunCargo(io::Error),
     Utf8(Utf8Error),
     Json(Option<serde_json::Error>),
@@ -29,14 +32,40 @@ pub enum Error {
     KcovFailed(io::Result<ExitStatus>),
     NoCoverallsId,
     CannotFindTestTargets(Option<io::Error>),
+    Cache(io::Error),
+    DangerousOutputDirectory(PathBuf),
+    OutputDirectoryNotOwned(PathBuf),
+    CannotFindCoverageData(PathBuf, Option<io::Error>),
+    SourceFileNotInReport(PathBuf, PathBuf),
+    CannotReadSourceFile(String),
+    CannotReadBaseline(String),
+    Config(String),
+    InstallKcovFailed(io::Result<ExitStatus>),
+    ChecksumMismatch(String),
+    InvalidRunner(String),
+    LlvmToolsNotInstalled,
+    CommandFailed {
+        program: String,
+        status: io::Result<ExitStatus>,
+        /// The captured stderr, empty if it was not captured.
+        stderr: Vec<u8>,
+    },
+    CannotReadDebugInfo(String),
+    CannotWriteOutput(io::Error),
+    CannotStartServer(String),
+    CannotWatchSources(String),
+    DoctorFoundProblems(usize),
 }
 
 impl Error {
     fn description(&self) -> &str {
         match *self {
             Error::UnsupportedOS => "kcov cannot collect coverage on Windows.",
-            Error::KcovTooOld => "kcov is too old. v30 or above is required.",
+            Error::KcovTooOld(_) => "kcov is too old. v30 or above is required.",
             Error::KcovNotInstalled(_) => "kcov not installed.",
+            Error::KcovVersionMismatch(..) => {
+                "kcov does not match the version pinned by the project"
+            }
             Error::CannotRunCargo(_) => "cannot run cargo",
             Error::Utf8(_) => "output is not UTF-8 encoded",
             Error::Json(_) => "cannot parse JSON",
@@ -45,6 +74,28 @@ impl Error {
             Error::KcovFailed(_) => "failed to get coverage",
             Error::NoCoverallsId => "missing environment variable TRAVIS_JOB_ID for coveralls",
             Error::CannotFindTestTargets(_) => "cannot find test targets",
+            Error::Cache(_) => "cannot access the kcov result cache",
+            Error::DangerousOutputDirectory(_) => {
+                "refusing to use a coverage output directory which contains protected files"
+            }
+            Error::CannotFindCoverageData(..) => "cannot find coverage data to merge",
+            Error::SourceFileNotInReport(..) => "the source file is not in the coverage report",
+            Error::CannotReadSourceFile(_) => "cannot read a source file of the coverage report",
+            Error::CannotReadBaseline(_) => "cannot read the baseline coverage",
+            Error::Config(_) => "invalid cargo-kcov configuration in Cargo.toml",
+            Error::InstallKcovFailed(_) => "failed to install kcov",
+            Error::ChecksumMismatch(_) => "checksum of the kcov source does not match",
+            Error::InvalidRunner(_) => "invalid `--runner` command",
+            Error::LlvmToolsNotInstalled => "llvm-profdata and llvm-cov not installed",
+            Error::CommandFailed { .. } => "failed to get coverage",
+            Error::CannotReadDebugInfo(_) => "cannot read the debug info of a test executable",
+            Error::CannotWriteOutput(_) => "cannot write to stdout",
+            Error::CannotStartServer(_) => "cannot start the HTTP server",
+            Error::CannotWatchSources(_) => "cannot watch the source files for changes",
+            Error::DoctorFoundProblems(_) => "coverage cannot be collected in this environment",
+            Error::OutputDirectoryNotOwned(_) => {
+                "refusing to delete a non-empty coverage output directory not created by cargo-kcov"
+            }
         }
     }
 
@@ -53,16 +104,50 @@ impl Error {
             Error::KcovNotInstalled(ref e)
             | Error::CannotRunCargo(ref e)
             | Error::CannotCreateCoverageDirectory(ref e)
-            | Error::KcovFailed(Err(ref e)) => Some(e),
+            | Error::KcovFailed(Err(ref e))
+            | Error::InstallKcovFailed(Err(ref e))
+            | Error::CommandFailed {
+                status: Err(ref e), ..
+            }
+            | Error::CannotWriteOutput(ref e)
+            | Error::Cache(ref e) => Some(e),
             Error::Utf8(ref e) => Some(e),
+            Error::Config(ref e)
+            | Error::ChecksumMismatch(ref e)
+            | Error::InvalidRunner(ref e)
+            | Error::CannotReadDebugInfo(ref e)
+            | Error::CannotReadSourceFile(ref e)
+            | Error::CannotReadBaseline(ref e)
+            | Error::CannotStartServer(ref e)
+            | Error::CannotWatchSources(ref e) => Some(e),
             Error::Json(ref e) => e.as_ref().map(|a| a as &dyn Display),
-            Error::KcovFailed(Ok(ref e)) => Some(e),
-            Error::CannotFindTestTargets(ref e) => e.as_ref().map(|a| a as &dyn Display),
+            Error::KcovFailed(Ok(ref e))
+            | Error::InstallKcovFailed(Ok(ref e))
+            | Error::CommandFailed {
+                status: Ok(ref e), ..
+            } => Some(e),
+            Error::KcovTooOld(Some(ref v)) => Some(v),
+            Error::KcovVersionMismatch(ref kcov, _) => Some(kcov),
+            Error::CannotFindTestTargets(ref e) | Error::CannotFindCoverageData(_, ref e) => {
+                e.as_ref().map(|a| a as &dyn Display)
+            }
             _ => None,
         }
     }
 }
 
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.description().trim_end_matches('.'))?;
+        if let Some(cause) = self.cause() {
+            write!(f, ": {}", cause)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for Error {}
+
 impl From<FromUtf8Error> for Error {
     fn from(e: FromUtf8Error) -> Self {
         Error::Utf8(e.utf8_error())
@@ -78,7 +163,13 @@ impl From<serde_json::Error> for Error {
 impl Error {
     /// Prints the error message and quit.
     pub fn print_error_and_quit(&self) -> ! {
-        let mut t = stderr::new();
+        self.print_error();
+        exit(2);
+    }
+
+    /// Prints the error message, with notes on how to fix it.
+    pub fn print_error(&self) {
+        let mut t = terminal::stderr();
 
         t.fg(RED).unwrap();
         t.attr(Attr::Bold).unwrap();
@@ -109,7 +200,7 @@ impl Error {
         }
 
         match *self {
-            Error::KcovTooOld | Error::KcovNotInstalled(_) => {
+            Error::KcovTooOld(_) | Error::KcovNotInstalled(_) => {
                 t.fg(GREEN).unwrap();
                 t.attr(Attr::Bold).unwrap();
                 t.write_all(b"note: ").unwrap();
@@ -125,7 +216,7 @@ impl Error {
                     t.fg(WHITE).unwrap();
                     t.write_all(b"    $ ").unwrap();
                     t.reset().unwrap();
-                    writeln!(t, "sudo apt-get install cmake g++ pkg-config jq\n").unwrap();
+                    writeln!(t, "sudo apt-get install cmake g++ pkg-config\n").unwrap();
 
                     t.fg(WHITE).unwrap();
                     t.write_all(b"    $ ").unwrap();
@@ -137,13 +228,123 @@ impl Error {
                     t.fg(WHITE).unwrap();
                     t.write_all(b"    $ ").unwrap();
                     t.reset().unwrap();
-                    writeln!(t, "brew install cmake jq\n").unwrap();
+                    writeln!(t, "brew install cmake\n").unwrap();
                 }
 
                 t.fg(WHITE).unwrap();
                 t.write_all(b"    $ ").unwrap();
                 t.reset().unwrap();
-                writeln!(t, "cargo kcov --print-install-kcov-sh | sh").unwrap();
+                writeln!(t, "cargo kcov install-kcov").unwrap();
+            }
+            Error::KcovVersionMismatch(_, ref pin) => {
+                t.fg(GREEN).unwrap();
+                t.attr(Attr::Bold).unwrap();
+                t.write_all(b"note: ").unwrap();
+                t.reset().unwrap();
+                writeln!(
+                    t,
+                    "`kcov-version` in `[package.metadata.kcov]` requires kcov {}, install it with:\n",
+                    pin
+                )
+                .unwrap();
+                t.fg(WHITE).unwrap();
+                t.write_all(b"    $ ").unwrap();
+                t.reset().unwrap();
+                writeln!(t, "cargo kcov install-kcov {}", pin).unwrap();
+            }
+            Error::LlvmToolsNotInstalled => {
+                t.fg(GREEN).unwrap();
+                t.attr(Attr::Bold).unwrap();
+                t.write_all(b"note: ").unwrap();
+                t.reset().unwrap();
+                t.write_all(
+                    b"the instrument-coverage backend, also used for tests run by a \
+                      `target.<triple>.runner`, needs the LLVM tools of the toolchain:\n\n",
+                )
+                .unwrap();
+                t.fg(WHITE).unwrap();
+                t.write_all(b"    $ ").unwrap();
+                t.reset().unwrap();
+                writeln!(t, "rustup component add llvm-tools-preview").unwrap();
+            }
+            Error::CommandFailed {
+                ref program,
+                ref stderr,
+                ..
+            } => {
+                t.fg(GREEN).unwrap();
+                t.attr(Attr::Bold).unwrap();
+                t.write_all(b"note: ").unwrap();
+                t.reset().unwrap();
+                writeln!(t, "`{}` did not succeed", program).unwrap();
+                t.write_all(stderr).unwrap();
+            }
+            Error::DangerousOutputDirectory(ref path) => {
+                t.fg(GREEN).unwrap();
+                t.attr(Attr::Bold).unwrap();
+                t.write_all(b"note: ").unwrap();
+                t.reset().unwrap();
+                writeln!(
+                    t,
+                    "`{}` contains the workspace, the target directory, the home directory or the \
+                     directories to merge, please choose a dedicated directory with `--output`",
+                    path.display()
+                )
+                .unwrap();
+            }
+            Error::OutputDirectoryNotOwned(ref path) => {
+                t.fg(GREEN).unwrap();
+                t.attr(Attr::Bold).unwrap();
+                t.write_all(b"note: ").unwrap();
+                t.reset().unwrap();
+                writeln!(
+                    t,
+                    "pass `--append` to accumulate coverage into `{}`, \
+                     or delete it manually if it is no longer needed:\n",
+                    path.display()
+                )
+                .unwrap();
+                t.fg(WHITE).unwrap();
+                t.write_all(b"    $ ").unwrap();
+                t.reset().unwrap();
+                writeln!(t, "rm -r {}", path.display()).unwrap();
+            }
+            Error::CannotFindCoverageData(ref path, _) => {
+                t.fg(GREEN).unwrap();
+                t.attr(Attr::Bold).unwrap();
+                t.write_all(b"note: ").unwrap();
+                t.reset().unwrap();
+                writeln!(
+                    t,
+                    "`{}` should be the output directory of a previous `cargo kcov` run",
+                    path.display()
+                )
+                .unwrap();
+            }
+            Error::SourceFileNotInReport(ref path, ref cov_path) => {
+                t.fg(GREEN).unwrap();
+                t.attr(Attr::Bold).unwrap();
+                t.write_all(b"note: ").unwrap();
+                t.reset().unwrap();
+                writeln!(
+                    t,
+                    "`{}` has no instrumented lines in the report in `{}`, it may not be compiled \
+                     into any test, or be excluded",
+                    path.display(),
+                    cov_path.display()
+                )
+                .unwrap();
+            }
+            Error::CannotReadSourceFile(_) => {
+                t.fg(GREEN).unwrap();
+                t.attr(Attr::Bold).unwrap();
+                t.write_all(b"note: ").unwrap();
+                t.reset().unwrap();
+                t.write_all(
+                    b"the report may have been collected in another directory, \
+                      `cargo kcov merge --remap-path-prefix FROM=TO` rewrites its source paths\n",
+                )
+                .unwrap();
             }
             Error::CannotFindTestTargets(_) => {
                 t.fg(GREEN).unwrap();
@@ -166,7 +367,5 @@ impl Error {
             }
             _ => {}
         }
-
-        exit(2);
     }
 }
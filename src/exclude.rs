@@ -0,0 +1,501 @@
+//! Excluding code from the coverage with markers in the source.
+//!
+//! Lines containing `// cov:ignore-line`, regions between `// cov:ignore-start` and
+//! `// cov:ignore-end`, and items annotated with `#[cfg_attr(coverage, no_coverage)]` (or
+//! `coverage(off)`) are removed from the merged line data after the backend has written the
+//! report. The markers kcov can express are passed to it as `--exclude-line` and
+//! `--exclude-region` too, so its HTML report leaves them out as well.
+//!
+//! With `--exclude-tests`, the test code is removed as well: the `#[cfg(test)]` items and
+//! `#[test]` functions found by parsing the source with syn, and the files in `tests/`.
+
+use std::collections::{BTreeSet, HashMap};
+use std::ffi::OsString;
+use std::fs::{read_to_string, write, File};
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use regex::Regex;
+use serde_json::{from_str, to_string, Value};
+use syn::spanned::Spanned;
+use syn::{self, Attribute, ImplItem, Item};
+
+use errors::Error;
+use lcov::{
+    filter_lcov, read_line_coverage, write_cobertura, FileCoverage, COBERTURA_FILE, LCOV_FILE,
+};
+use summary::LLVM_COV_SUMMARY;
+
+/// The markers excluding code from the coverage, configured in `[package.metadata.kcov]`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Exclusions {
+    /// Lines containing any of these are excluded.
+    pub line_markers: Vec<String>,
+    /// Lines from one containing the first marker to one containing the second are excluded.
+    pub region_markers: Option<(String, String)>,
+    /// Whether items annotated with `no_coverage` or `coverage(off)` are excluded.
+    pub no_coverage_items: bool,
+    /// Whether the test code is excluded.
+    pub test_code: bool,
+}
+
+impl Default for Exclusions {
+    fn default() -> Self {
+        Exclusions {
+            line_markers: vec!["cov:ignore-line".to_owned()],
+            region_markers: Some(("cov:ignore-start".to_owned(), "cov:ignore-end".to_owned())),
+            no_coverage_items: true,
+            test_code: false,
+        }
+    }
+}
+
+impl Exclusions {
+    /// Whether nothing is excluded.
+    pub fn is_empty(&self) -> bool {
+        self.line_markers.is_empty()
+            && self.region_markers.is_none()
+            && !self.no_coverage_items
+            && !self.test_code
+    }
+
+    /// The kcov arguments excluding the markers, skipping those kcov cannot parse: it splits the
+    /// lists on `,`, and the regions on `:`. Flags already in `kcov_args` are not repeated.
+    pub fn kcov_args(&self, kcov_args: &[OsString]) -> Vec<OsString> {
+        let has_flag = |flag: &str| {
+            kcov_args
+                .iter()
+                .any(|arg| arg.to_string_lossy().starts_with(flag))
+        };
+        let mut args = Vec::new();
+        let line_markers = self
+            .line_markers
+            .iter()
+            .filter(|m| !m.contains(','))
+            .map(|m| &**m)
+            .collect::<Vec<_>>();
+        if !line_markers.is_empty() && !has_flag("--exclude-line") {
+            args.push(format!("--exclude-line={}", line_markers.join(",")).into());
+        }
+        if let Some((ref start, ref end)) = self.region_markers {
+            let is_valid = |m: &str| !m.contains(',') && !m.contains(':');
+            if is_valid(start) && is_valid(end) && !has_flag("--exclude-region") {
+                args.push(format!("--exclude-region={}:{}", start, end).into());
+            }
+        }
+        args
+    }
+
+    /// The line numbers (starting from 1) of `source` to exclude.
+    pub fn excluded_lines(&self, source: &str) -> BTreeSet<u32> {
+        let attribute = no_coverage_attribute();
+        let lines = source.lines().collect::<Vec<_>>();
+        let mut excluded = BTreeSet::new();
+        let mut region_start = None;
+        for (i, line) in lines.iter().enumerate() {
+            let number = i as u32 + 1;
+            if self.line_markers.iter().any(|m| line.contains(&**m)) {
+                excluded.insert(number);
+            }
+            if let Some((ref start, ref end)) = self.region_markers {
+                match region_start {
+                    None if line.contains(&**start) => region_start = Some(number),
+                    Some(first) if line.contains(&**end) => {
+                        excluded.extend(first..=number);
+                        region_start = None;
+                    }
+                    _ => {}
+                }
+            }
+            if self.no_coverage_items {
+                // Attributes mentioned in comments are skipped.
+                if let Some(m) = attribute
+                    .find(line)
+                    .filter(|m| !line[..m.start()].contains("//"))
+                {
+                    // The brackets opened by the attribute, e.g. 2 in `#[cfg_attr(coverage, no_coverage`.
+                    let open =
+                        m.as_str().matches(['[', '(']).count() - m.as_str().matches(')').count();
+                    let end = item_end(&lines, i, m.end(), open);
+                    excluded.extend(number..=end as u32 + 1);
+                }
+            }
+        }
+        // An unterminated region extends to the end of the file.
+        if let Some(first) = region_start {
+            excluded.extend(first..=lines.len() as u32);
+        }
+        if self.test_code {
+            for (first, last) in test_regions(source) {
+                excluded.extend(first..=last);
+            }
+        }
+        excluded
+    }
+
+    /// The excluded lines of each file of `files`, read relative to `source_root`. Files which
+    /// cannot be read are left out.
+    pub fn excluded_files(
+        &self,
+        files: &[FileCoverage],
+        source_root: &Path,
+    ) -> HashMap<PathBuf, BTreeSet<u32>> {
+        files
+            .iter()
+            .filter_map(|file| {
+                let source = read_to_string(source_root.join(&file.path)).ok()?;
+                let relative = file.path.strip_prefix(source_root).unwrap_or(&file.path);
+                let excluded = if self.test_code && is_test_file(relative) {
+                    (1..=source.lines().count() as u32).collect()
+                } else {
+                    self.excluded_lines(&source)
+                };
+                Some((file.path.clone(), excluded))
+            })
+            .collect()
+    }
+
+    /// Removes the excluded lines of each file, as returned by `excluded_files`, from `files`.
+    /// Files without an entry are kept as is. Returns whether anything was removed.
+    pub fn apply(
+        files: &mut [FileCoverage],
+        excluded_files: &HashMap<PathBuf, BTreeSet<u32>>,
+    ) -> bool {
+        let mut is_changed = false;
+        for file in files {
+            let excluded = match excluded_files.get(&file.path) {
+                Some(excluded) => excluded,
+                None => continue,
+            };
+            let before = (file.lines.len(), file.branches.len());
+            file.lines.retain(|line, _| !excluded.contains(line));
+            file.branches.retain(|line, _| !excluded.contains(line));
+            is_changed |= before != (file.lines.len(), file.branches.len());
+        }
+        is_changed
+    }
+}
+
+/// The attribute excluding an item, `no_coverage` or `coverage(off)`, possibly in a `cfg_attr`.
+fn no_coverage_attribute() -> &'static Regex {
+    static ATTRIBUTE: OnceLock<Regex> = OnceLock::new();
+    ATTRIBUTE.get_or_init(|| {
+        Regex::new(
+            r"#\[\s*(?:cfg_attr\s*\(\s*coverage(?:_nightly)?\s*,\s*)?(?:no_coverage|coverage\s*\(\s*off\s*\))",
+        )
+        .unwrap()
+    })
+}
+
+/// Whether the file at `relative_path` (from the workspace root) is in a `tests/` directory,
+/// i.e. an integration test or one of its modules.
+fn is_test_file(relative_path: &Path) -> bool {
+    relative_path
+        .parent()
+        .is_some_and(|dir| dir.components().any(|c| c.as_os_str() == "tests"))
+}
+
+/// The first and last lines of the `#[cfg(test)]` items and `#[test]` functions in `source`,
+/// including their attributes. Sources syn cannot parse have none.
+fn test_regions(source: &str) -> Vec<(u32, u32)> {
+    let mut regions = Vec::new();
+    if let Ok(file) = syn::parse_file(source) {
+        find_test_items(&file.items, &mut regions);
+    }
+    regions
+}
+
+fn find_test_items(items: &[Item], regions: &mut Vec<(u32, u32)>) {
+    for item in items {
+        let attrs = match *item {
+            Item::Fn(ref f) => &f.attrs,
+            Item::Mod(ref m) => &m.attrs,
+            Item::Impl(ref i) => &i.attrs,
+            Item::Use(ref u) => &u.attrs,
+            Item::Const(ref c) => &c.attrs,
+            Item::Static(ref s) => &s.attrs,
+            Item::Struct(ref s) => &s.attrs,
+            Item::Enum(ref e) => &e.attrs,
+            Item::Trait(ref t) => &t.attrs,
+            Item::Macro(ref m) => &m.attrs,
+            _ => continue,
+        };
+        if attrs.iter().any(is_test_attribute) {
+            regions.push(span_lines(item));
+            continue;
+        }
+        match *item {
+            Item::Mod(ref m) => {
+                if let Some((_, ref items)) = m.content {
+                    find_test_items(items, regions);
+                }
+            }
+            Item::Impl(ref i) => {
+                for impl_item in &i.items {
+                    if let ImplItem::Fn(ref f) = *impl_item {
+                        if f.attrs.iter().any(is_test_attribute) {
+                            regions.push(span_lines(f));
+                        }
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Whether `attr` is `#[cfg(test)]`, or a test attribute like `#[test]` or `#[tokio::test]`.
+fn is_test_attribute(attr: &Attribute) -> bool {
+    let path = attr.path();
+    if path.is_ident("cfg") {
+        attr.parse_args::<syn::Ident>()
+            .is_ok_and(|cfg| cfg == "test")
+    } else {
+        path.segments.last().is_some_and(|s| s.ident == "test")
+    }
+}
+
+fn span_lines<T: Spanned>(node: &T) -> (u32, u32) {
+    let span = node.span();
+    (span.start().line as u32, span.end().line as u32)
+}
+
+/// Finds the index of the line ending the item whose attribute continues at
+/// `lines[first][offset..]` with `attribute_depth` brackets still open: the line with the brace
+/// closing its body, or its `;`.
+fn item_end(lines: &[&str], first: usize, offset: usize, mut attribute_depth: usize) -> usize {
+    let mut depth = 0;
+    for (i, line) in lines.iter().enumerate().skip(first) {
+        let text = if i == first { &line[offset..] } else { line };
+        let mut chars = text.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '/' if chars.peek() == Some(&'/') => break,
+                '"' => {
+                    while let Some(c) = chars.next() {
+                        match c {
+                            '\\' => {
+                                chars.next();
+                            }
+                            '"' => break,
+                            _ => {}
+                        }
+                    }
+                }
+                '[' | '(' if attribute_depth > 0 => attribute_depth += 1,
+                ']' | ')' if attribute_depth > 0 => attribute_depth -= 1,
+                _ if attribute_depth > 0 => {}
+                '{' => depth += 1,
+                '}' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return i;
+                    }
+                }
+                ';' if depth == 0 => return i,
+                _ => {}
+            }
+        }
+    }
+    lines.len().saturating_sub(1)
+}
+
+/// Applies `exclusions` to the merged report in `cov_path`: `lcov.info`, `cobertura.xml` and the
+/// summary of the instrument-coverage backend, or `kcov-merged/cobertura.xml` and
+/// `kcov-merged/coverage.json` of kcov. The HTML reports are left as is. Relative paths are
+/// resolved from `source_root`.
+pub fn apply_exclusions(
+    cov_path: &Path,
+    exclusions: &Exclusions,
+    source_root: &Path,
+) -> Result<(), Error> {
+    if exclusions.is_empty() {
+        return Ok(());
+    }
+    let mut files = match read_line_coverage(cov_path) {
+        Ok(files) => files,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(Error::CannotFindCoverageData(cov_path.to_owned(), Some(e))),
+    };
+    let excluded_files = exclusions.excluded_files(&files, source_root);
+    if !Exclusions::apply(&mut files, &excluded_files) {
+        return Ok(());
+    }
+    let error = Error::CannotCreateCoverageDirectory;
+
+    let lcov_path = cov_path.join(LCOV_FILE);
+    let (cobertura_path, summary_path) = if lcov_path.exists() {
+        let content = read_to_string(&lcov_path).map_err(error)?;
+        let filtered = filter_lcov(&content, |path, line| {
+            excluded_files
+                .get(path)
+                .is_some_and(|excluded| excluded.contains(&line))
+        });
+        write(&lcov_path, filtered).map_err(error)?;
+        (
+            cov_path.join(COBERTURA_FILE),
+            cov_path.join(LLVM_COV_SUMMARY),
+        )
+    } else {
+        let kcov_merged = cov_path.join("kcov-merged");
+        (
+            kcov_merged.join(COBERTURA_FILE),
+            kcov_merged.join("coverage.json"),
+        )
+    };
+
+    let cobertura = File::create(&cobertura_path).map_err(error)?;
+    write_cobertura(BufWriter::new(cobertura), &files, source_root).map_err(error)?;
+    update_summary(&summary_path, &files)
+}
+
+/// Replaces the line and branch totals of the summary at `path` with those of `files`.
+fn update_summary(path: &Path, files: &[FileCoverage]) -> Result<(), Error> {
+    let mut json = match read_to_string(path) {
+        Ok(content) => from_str::<Value>(&content)?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(Error::CannotCreateCoverageDirectory(e)),
+    };
+    let total_lines = files.iter().map(|f| f.lines.len() as u64).sum::<u64>();
+    let covered_lines = files
+        .iter()
+        .map(|f| f.lines.values().filter(|&&hits| hits > 0).count() as u64)
+        .sum::<u64>();
+    let (covered_branches, total_branches) = files
+        .iter()
+        .flat_map(|f| f.branches.values())
+        .fold((0, 0), |(taken, total), c| {
+            (taken + c.taken, total + c.total)
+        });
+    let percent = |covered: u64, total: u64| {
+        if total == 0 {
+            0.0
+        } else {
+            covered as f64 * 100.0 / total as f64
+        }
+    };
+
+    if json.get("data").is_some() {
+        // `llvm-cov export -summary-only`
+        let totals = &mut json["data"][0]["totals"];
+        totals["lines"]["count"] = total_lines.into();
+        totals["lines"]["covered"] = covered_lines.into();
+        totals["lines"]["percent"] = percent(covered_lines, total_lines).into();
+        if total_branches > 0 || totals["branches"].is_object() {
+            totals["branches"]["count"] = total_branches.into();
+            totals["branches"]["covered"] = covered_branches.into();
+            totals["branches"]["percent"] = percent(covered_branches, total_branches).into();
+        }
+    } else {
+        // kcov writes the numbers as strings.
+        json["covered_lines"] = covered_lines.to_string().into();
+        json["total_lines"] = total_lines.to_string().into();
+        json["percent_covered"] = format!("{:.2}", percent(covered_lines, total_lines)).into();
+    }
+    write(path, to_string(&json)?).map_err(Error::CannotCreateCoverageDirectory)
+}
+
+#[test]
+fn test_exclusions() {
+    let exclusions = Exclusions::default();
+    let source = r#"fn covered() {
+    let x = 1;
+    unreachable!(); // cov:ignore-line
+}
+
+// cov:ignore-start
+fn ignored() {}
+// cov:ignore-end
+
+#[cfg_attr(coverage, no_coverage)]
+fn debug_only() {
+    let s = "}";
+    if s.is_empty() {
+        println!("{}", s);
+    }
+}
+
+#[cfg_attr(coverage_nightly, coverage(off))]
+const X: u32 = 1;
+
+fn after() {}
+"#;
+    assert_eq!(
+        exclusions
+            .excluded_lines(source)
+            .into_iter()
+            .collect::<Vec<_>>(),
+        [3, 6, 7, 8, 10, 11, 12, 13, 14, 15, 16, 18, 19]
+    );
+    assert!(Exclusions {
+        line_markers: Vec::new(),
+        region_markers: None,
+        no_coverage_items: false,
+        test_code: false,
+    }
+    .excluded_lines(source)
+    .is_empty());
+
+    // kcov splits the regions on `:`.
+    assert_eq!(
+        exclusions.kcov_args(&[]),
+        [OsString::from("--exclude-line=cov:ignore-line")]
+    );
+    let custom = Exclusions {
+        line_markers: vec!["LCOV_EXCL_LINE".to_owned(), "a,b".to_owned()],
+        region_markers: Some(("LCOV_EXCL_START".to_owned(), "LCOV_EXCL_STOP".to_owned())),
+        no_coverage_items: false,
+        test_code: false,
+    };
+    assert_eq!(
+        custom.kcov_args(&[]),
+        [
+            OsString::from("--exclude-line=LCOV_EXCL_LINE"),
+            OsString::from("--exclude-region=LCOV_EXCL_START:LCOV_EXCL_STOP"),
+        ]
+    );
+    assert_eq!(
+        custom.kcov_args(&[OsString::from("--exclude-line=kcov-ignore")]),
+        [OsString::from(
+            "--exclude-region=LCOV_EXCL_START:LCOV_EXCL_STOP"
+        )]
+    );
+
+    let tests = Exclusions {
+        line_markers: Vec::new(),
+        region_markers: None,
+        no_coverage_items: false,
+        test_code: true,
+    };
+    let source = r#"pub fn add(a: u32, b: u32) -> u32 {
+    a + b
+}
+
+#[test]
+fn test_add() {
+    assert_eq!(add(1, 2), 3);
+}
+
+#[cfg(test)]
+mod tests {
+    #[tokio::test]
+    async fn test_async() {}
+}
+
+mod inner {
+    #[cfg(test)]
+    use super::add;
+
+    pub fn covered() {}
+}
+"#;
+    assert_eq!(
+        tests.excluded_lines(source).into_iter().collect::<Vec<_>>(),
+        [5, 6, 7, 8, 10, 11, 12, 13, 14, 17, 18]
+    );
+    assert!(tests.excluded_lines("fn broken(").is_empty());
+    assert!(is_test_file(Path::new("tests/integration.rs")));
+    assert!(is_test_file(Path::new("crates/core/tests/common/mod.rs")));
+    assert!(!is_test_file(Path::new("src/tests.rs")));
+}
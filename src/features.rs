@@ -0,0 +1,126 @@
+//! Feature sets used by `--feature-matrix`.
+
+/// One combination of cargo features to collect coverage for.
+///
+/// A feature set is written as a comma- or space-separated list of features. The pseudo-features
+/// `no-default-features` and `all-features` translate to the corresponding cargo flags, and
+/// `default` (or an empty list) stands for the default features.
+#[derive(Debug, PartialEq, Eq)]
+pub struct FeatureSet {
+    label: String,
+    no_default_features: bool,
+    all_features: bool,
+    features: Vec<String>,
+}
+
+impl FeatureSet {
+    pub fn parse(spec: &str) -> FeatureSet {
+        let mut set = FeatureSet {
+            label: spec.trim().to_owned(),
+            no_default_features: false,
+            all_features: false,
+            features: Vec::new(),
+        };
+        for feature in spec.split(|c: char| c == ',' || c.is_whitespace()) {
+            match feature {
+                "" | "default" => {}
+                "no-default-features" => set.no_default_features = true,
+                "all-features" => set.all_features = true,
+                _ => set.features.push(feature.to_owned()),
+            }
+        }
+        if set.label.is_empty() {
+            set.label.push_str("default");
+        }
+        set
+    }
+
+    /// The feature sets used when `--feature-matrix` is given without any explicit sets.
+    pub fn default_matrix() -> Vec<FeatureSet> {
+        ["no-default-features", "default", "all-features"]
+            .iter()
+            .map(|spec| FeatureSet::parse(spec))
+            .collect()
+    }
+
+    /// Human readable name of this set, shown in the summary.
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    /// Name of the subdirectory of the output directory collecting coverage of this set.
+    pub fn dir_name(&self) -> String {
+        let mut name = String::from("features-");
+        let mut last_is_separator = false;
+        for c in self.label.chars() {
+            if c.is_alphanumeric() || c == '-' || c == '_' {
+                name.push(c);
+                last_is_separator = false;
+            } else if !last_is_separator {
+                name.push('+');
+                last_is_separator = true;
+            }
+        }
+        name
+    }
+
+    /// Arguments passed to `cargo test` to build with this set.
+    pub fn cargo_args(&self) -> Vec<String> {
+        self.args(self.features.iter())
+    }
+
+    /// Arguments passed to `cargo test -p` to build a single package with this set. Only the
+    /// features among `available`, the features of the package, are activated, and `dep/feature`
+    /// only if `dep` is one of them.
+    pub fn package_args(&self, available: &[String]) -> Vec<String> {
+        self.args(self.features.iter().filter(|f| {
+            let name = f.split('/').next().unwrap();
+            available.iter().any(|a| a == name)
+        }))
+    }
+
+    fn args<'a, I: Iterator<Item = &'a String>>(&self, features: I) -> Vec<String> {
+        let features = features.map(|f| &**f).collect::<Vec<_>>();
+        let mut args = Vec::new();
+        if self.no_default_features {
+            args.push("--no-default-features".to_owned());
+        }
+        if self.all_features {
+            args.push("--all-features".to_owned());
+        }
+        if !features.is_empty() {
+            args.push("--features".to_owned());
+            args.push(features.join(" "));
+        }
+        args
+    }
+}
+
+#[test]
+fn test_feature_set() {
+    let set = FeatureSet::parse("default");
+    assert_eq!(set.label(), "default");
+    assert_eq!(set.dir_name(), "features-default");
+    assert!(set.cargo_args().is_empty());
+    assert_eq!(FeatureSet::parse(""), set);
+
+    let set = FeatureSet::parse("no-default-features, serde chrono");
+    assert_eq!(set.label(), "no-default-features, serde chrono");
+    assert_eq!(set.dir_name(), "features-no-default-features+serde+chrono");
+    assert_eq!(
+        set.cargo_args(),
+        ["--no-default-features", "--features", "serde chrono"]
+    );
+    assert_eq!(
+        set.package_args(&["chrono".to_owned(), "std".to_owned()]),
+        ["--no-default-features", "--features", "chrono"]
+    );
+    assert_eq!(set.package_args(&[]), ["--no-default-features"]);
+
+    let set = FeatureSet::parse("all-features");
+    assert_eq!(set.cargo_args(), ["--all-features"]);
+
+    let set = FeatureSet::parse("serde/std");
+    assert_eq!(set.dir_name(), "features-serde+std");
+    assert_eq!(set.cargo_args(), ["--features", "serde/std"]);
+}
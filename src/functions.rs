@@ -0,0 +1,279 @@
+//! Function-level coverage, from the debug info of the test executables.
+//!
+//! The functions are read from the `DW_TAG_subprogram` entries of the DWARF debug info, and their
+//! symbols demangled (both the legacy and the v0 Rust mangling). A function is matched to the
+//! merged line coverage by its declaration: its hit count is that of the first instrumented line
+//! from the declaration up to the next function declared in the same file.
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::read;
+use std::path::{Path, PathBuf};
+
+use gimli::{self, AttributeValue, Dwarf, DwarfSections, EndianSlice, Reader, RunTimeEndian, Unit};
+use object::{self, Object, ObjectSection};
+use rustc_demangle::demangle;
+use serde_json::{json, Value};
+
+use errors::Error;
+use lcov::FileCoverage;
+
+/// Name of the JSON report of the functions in the output directory.
+pub const FUNCTIONS_FILE: &str = "functions.json";
+
+/// A function defined by a test executable.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FunctionSymbol {
+    pub file: PathBuf,
+    pub line: u32,
+    /// The demangled name without the hash, e.g. `my_crate::parser::parse`.
+    pub name: String,
+}
+
+/// Hit count of a function.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionCoverage {
+    pub symbol: FunctionSymbol,
+    pub hits: u64,
+}
+
+/// Reads the functions defined in `executable`, with their declarations. Functions without a
+/// linkage name or declaration, e.g. closures inlined everywhere, are skipped.
+pub fn read_functions(executable: &Path) -> Result<BTreeSet<FunctionSymbol>, Error> {
+    let error = |e: &dyn ToString| {
+        Error::CannotReadDebugInfo(format!("{}: {}", executable.display(), e.to_string()))
+    };
+    let data = read(executable).map_err(|e| error(&e))?;
+    let object = object::File::parse(&*data).map_err(|e| error(&e))?;
+    let endian = if object.is_little_endian() {
+        RunTimeEndian::Little
+    } else {
+        RunTimeEndian::Big
+    };
+    let load_section = |id: gimli::SectionId| -> Result<Cow<[u8]>, object::Error> {
+        match object.section_by_name(id.name()) {
+            Some(section) => section.uncompressed_data(),
+            None => Ok(Cow::Borrowed(&[][..])),
+        }
+    };
+    let sections = DwarfSections::load(load_section).map_err(|e| error(&e))?;
+    let dwarf = sections.borrow(|section| EndianSlice::new(section, endian));
+
+    let mut functions = BTreeSet::new();
+    let mut units = dwarf.units();
+    while let Some(header) = units.next().map_err(|e| error(&e))? {
+        let unit = dwarf.unit(header).map_err(|e| error(&e))?;
+        read_unit_functions(&dwarf, &unit, &mut functions).map_err(|e| error(&e))?;
+    }
+    Ok(functions)
+}
+
+fn read_unit_functions<R: Reader>(
+    dwarf: &Dwarf<R>,
+    unit: &Unit<R>,
+    functions: &mut BTreeSet<FunctionSymbol>,
+) -> gimli::Result<()> {
+    let mut entries = unit.entries();
+    while let Some((_, entry)) = entries.next_dfs()? {
+        // Only the entries with code, not the declarations or abstract instances.
+        if entry.tag() != gimli::DW_TAG_subprogram || entry.attr(gimli::DW_AT_low_pc)?.is_none() {
+            continue;
+        }
+        // Generic and inlined functions refer to the entry holding their name and declaration.
+        let origin = match entry
+            .attr_value(gimli::DW_AT_specification)?
+            .or(entry.attr_value(gimli::DW_AT_abstract_origin)?)
+        {
+            Some(AttributeValue::UnitRef(offset)) => Some(unit.entry(offset)?),
+            _ => None,
+        };
+        let attr = |name| -> gimli::Result<Option<AttributeValue<R>>> {
+            match entry.attr_value(name)? {
+                Some(value) => Ok(Some(value)),
+                None => match origin {
+                    Some(ref origin) => origin.attr_value(name),
+                    None => Ok(None),
+                },
+            }
+        };
+
+        let linkage_name = match attr(gimli::DW_AT_linkage_name)? {
+            Some(value) => dwarf
+                .attr_string(unit, value)?
+                .to_string_lossy()?
+                .into_owned(),
+            None => continue,
+        };
+        let line = match attr(gimli::DW_AT_decl_line)?.and_then(|v| v.udata_value()) {
+            Some(line) => line as u32,
+            None => continue,
+        };
+        let file = match attr(gimli::DW_AT_decl_file)? {
+            Some(AttributeValue::FileIndex(index)) => file_path(dwarf, unit, index)?,
+            _ => None,
+        };
+        if let Some(file) = file {
+            functions.insert(FunctionSymbol {
+                file,
+                line,
+                name: demangle_name(&linkage_name),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Demangles a legacy or v0 Rust symbol, without the hash. Other symbols are kept as is.
+fn demangle_name(symbol: &str) -> String {
+    format!("{:#}", demangle(symbol))
+}
+
+/// Resolves the file `index` of the line program, relative to the compilation directory.
+fn file_path<R: Reader>(
+    dwarf: &Dwarf<R>,
+    unit: &Unit<R>,
+    index: u64,
+) -> gimli::Result<Option<PathBuf>> {
+    let program = match unit.line_program {
+        Some(ref program) => program,
+        None => return Ok(None),
+    };
+    let header = program.header();
+    let file = match header.file(index) {
+        Some(file) => file,
+        None => return Ok(None),
+    };
+    let mut path = match unit.comp_dir {
+        Some(ref dir) => PathBuf::from(&*dir.to_string_lossy()?),
+        None => PathBuf::new(),
+    };
+    if let Some(dir) = file.directory(header) {
+        path.push(&*dwarf.attr_string(unit, dir)?.to_string_lossy()?);
+    }
+    path.push(
+        &*dwarf
+            .attr_string(unit, file.path_name())?
+            .to_string_lossy()?,
+    );
+    Ok(Some(path))
+}
+
+/// Matches the functions to the line coverage. Functions in files without coverage (e.g. the
+/// dependencies) or without any instrumented line are left out.
+pub fn function_coverage<'a, I>(functions: I, files: &[FileCoverage]) -> Vec<FunctionCoverage>
+where
+    I: IntoIterator<Item = &'a FunctionSymbol>,
+{
+    let mut by_file = BTreeMap::<&Path, BTreeSet<&FunctionSymbol>>::new();
+    for function in functions {
+        by_file.entry(&function.file).or_default().insert(function);
+    }
+
+    let mut result = Vec::new();
+    for file in files {
+        let functions = match by_file.get(&*file.path) {
+            Some(functions) => functions.iter().collect::<Vec<_>>(),
+            None => continue,
+        };
+        for (i, function) in functions.iter().enumerate() {
+            // Functions declared on the same line (e.g. by macros) share the next boundary.
+            let end = functions[i..]
+                .iter()
+                .map(|f| f.line)
+                .find(|&line| line > function.line)
+                .unwrap_or(u32::MAX);
+            if let Some((_, &hits)) = file.lines.range(function.line..end).next() {
+                result.push(FunctionCoverage {
+                    symbol: (**function).clone(),
+                    hits,
+                });
+            }
+        }
+    }
+    result
+}
+
+/// Creates the JSON report listing every function with its hit count, and the functions never
+/// called separately.
+pub fn functions_json(functions: &[FunctionCoverage]) -> Value {
+    let entry = |f: &FunctionCoverage| {
+        json!({
+            "name": f.symbol.name,
+            "file": f.symbol.file,
+            "line": f.symbol.line,
+            "hits": f.hits,
+        })
+    };
+    let called = functions.iter().filter(|f| f.hits > 0).count();
+    json!({
+        "total_functions": functions.len(),
+        "called_functions": called,
+        "functions": functions.iter().map(entry).collect::<Vec<_>>(),
+        "never_called": functions
+            .iter()
+            .filter(|f| f.hits == 0)
+            .map(entry)
+            .collect::<Vec<_>>(),
+    })
+}
+
+#[test]
+fn test_functions() {
+    use std::env::current_exe;
+
+    let functions = read_functions(&current_exe().unwrap()).unwrap();
+    let this = functions
+        .iter()
+        .find(|f| f.name == "cargo_kcov::functions::test_functions")
+        .unwrap();
+    assert!(this.file.ends_with("src/functions.rs"));
+    assert!(functions.iter().any(|f| f
+        .name
+        .starts_with("cargo_kcov::functions::function_coverage")));
+
+    assert_eq!(
+        demangle_name("_ZN10cargo_kcov4kcov4Kcov9run_tests17h0123456789abcdefE"),
+        "cargo_kcov::kcov::Kcov::run_tests"
+    );
+    assert_eq!(
+        demangle_name("_RNvMs_NtCs4fqI2P2rA04_10cargo_kcov4kcovNtB4_4Kcov9run_tests"),
+        "<cargo_kcov::kcov::Kcov>::run_tests"
+    );
+    assert_eq!(demangle_name("main"), "main");
+
+    let file = |name: &str, lines: &[(u32, u64)]| FileCoverage {
+        path: PathBuf::from(name),
+        lines: lines.iter().cloned().collect(),
+        branches: BTreeMap::new(),
+    };
+    let symbol = |file: &str, line, name: &str| FunctionSymbol {
+        file: PathBuf::from(file),
+        line,
+        name: name.to_owned(),
+    };
+    let symbols = [
+        symbol("/ws/src/lib.rs", 1, "lib::a"),
+        symbol("/ws/src/lib.rs", 5, "lib::b"),
+        symbol("/ws/src/lib.rs", 9, "lib::c"),
+        symbol("/ws/src/lib.rs", 20, "lib::d"),
+        symbol("/rustc/src/vec.rs", 1, "alloc::vec::Vec::new"),
+    ];
+    let files = [file(
+        "/ws/src/lib.rs",
+        &[(1, 3), (2, 3), (6, 0), (7, 0), (21, 1)],
+    )];
+    let coverage = function_coverage(&symbols, &files);
+    assert_eq!(
+        coverage
+            .iter()
+            .map(|f| (&*f.symbol.name, f.hits))
+            .collect::<Vec<_>>(),
+        [("lib::a", 3), ("lib::b", 0), ("lib::d", 1)]
+    );
+
+    let json = functions_json(&coverage);
+    assert_eq!(json["total_functions"], 3);
+    assert_eq!(json["called_functions"], 2);
+    assert_eq!(json["never_called"][0]["name"], "lib::b");
+    assert_eq!(json["never_called"][0]["line"], 5);
+}
@@ -0,0 +1,562 @@
+//! A self-contained HTML report rendered from the merged line coverage.
+//!
+//! kcov's report needs the scripts and styles next to `index.html`, and lists the files flat. This
+//! report is a single file, with the styles and the search script inlined: the files are shown as a
+//! tree of crates and modules following the Rust module structure, and each file is rendered with
+//! syntax highlighting and the hit count of every line.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::fs::read_to_string;
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+
+use toml;
+
+use lcov::FileCoverage;
+
+/// Name of the single-file HTML report in the output directory.
+pub const HTML_REPORT_FILE: &str = "report.html";
+
+const STYLE: &str = r#"
+body { margin: 0; font: 14px/1.4 -apple-system, "Segoe UI", Helvetica, Arial, sans-serif; color: #24292e; display: flex; height: 100vh; }
+nav { width: 320px; min-width: 320px; overflow: auto; border-right: 1px solid #d1d5da; padding: 8px; box-sizing: border-box; background: #f6f8fa; }
+main { flex: 1; overflow: auto; padding: 0 16px; }
+#search { width: 100%; box-sizing: border-box; padding: 4px 6px; margin-bottom: 8px; }
+details { margin-left: 12px; }
+nav > details { margin-left: 0; }
+summary { cursor: pointer; white-space: nowrap; }
+nav a { display: block; margin-left: 24px; white-space: nowrap; color: #0366d6; text-decoration: none; }
+nav a:hover { text-decoration: underline; }
+.pct { float: right; font-size: 12px; padding: 0 4px; border-radius: 3px; margin-left: 8px; }
+.high { background: #c6efce; } .medium { background: #ffeb9c; } .low { background: #ffc7ce; }
+table { border-collapse: collapse; font: 12px/1.5 SFMono-Regular, Consolas, Menlo, monospace; width: 100%; }
+td { padding: 0 8px; vertical-align: top; }
+td.n, td.h { text-align: right; color: #6a737d; user-select: none; width: 1%; white-space: nowrap; }
+td.s { white-space: pre; }
+tr.hit td.h { background: #c6efce; } tr.miss td.h, tr.miss td.s { background: #ffeef0; }
+tr.partial td.h { background: #ffeb9c; }
+.kw { color: #d73a49; } .ty { color: #6f42c1; } .st { color: #032f62; } .cm { color: #6a737d; font-style: italic; }
+.nu { color: #005cc5; } .ma { color: #e36209; } .at { color: #22863a; } .lt { color: #d73a49; }
+"#;
+
+const SCRIPT: &str = r#"
+function show() {
+  var id = location.hash.slice(1) || "summary";
+  document.querySelectorAll("main > section").forEach(function (s) { s.hidden = s.id !== id; });
+}
+window.addEventListener("hashchange", show);
+show();
+document.getElementById("search").addEventListener("input", function () {
+  var query = this.value.toLowerCase();
+  document.querySelectorAll("nav a").forEach(function (a) {
+    var section = document.getElementById(a.getAttribute("href").slice(1));
+    a.hidden = query !== "" && a.dataset.name.toLowerCase().indexOf(query) < 0
+      && section.textContent.toLowerCase().indexOf(query) < 0;
+  });
+  Array.prototype.slice.call(document.querySelectorAll("nav details")).reverse().forEach(function (d) {
+    var visible = d.querySelector("a:not([hidden])") !== null;
+    d.hidden = !visible;
+    if (query !== "") { d.open = visible; }
+  });
+});
+"#;
+
+const KEYWORDS: &[&str] = &[
+    "as", "async", "await", "break", "const", "continue", "crate", "dyn", "else", "enum", "extern",
+    "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub",
+    "ref", "return", "self", "Self", "static", "struct", "super", "trait", "true", "type",
+    "unsafe", "use", "where", "while",
+];
+
+/// A crate or module in the tree, with the files defining it.
+#[derive(Default)]
+struct Node {
+    children: BTreeMap<String, Node>,
+    files: Vec<usize>,
+}
+
+impl Node {
+    fn insert(&mut self, path: &[String], file: usize) {
+        match path.split_first() {
+            Some((first, rest)) => self
+                .children
+                .entry(first.clone())
+                .or_default()
+                .insert(rest, file),
+            None => self.files.push(file),
+        }
+    }
+
+    /// Covered and total lines of the files in this node and below.
+    fn totals(&self, files: &[FileCoverage]) -> (usize, usize) {
+        let mut totals = self.files.iter().fold((0, 0), |t, &i| {
+            let (covered, total) = file_totals(&files[i]);
+            (t.0 + covered, t.1 + total)
+        });
+        for child in self.children.values() {
+            let (covered, total) = child.totals(files);
+            totals.0 += covered;
+            totals.1 += total;
+        }
+        totals
+    }
+}
+
+fn file_totals(file: &FileCoverage) -> (usize, usize) {
+    let covered = file.lines.values().filter(|&&hits| hits > 0).count();
+    (covered, file.lines.len())
+}
+
+/// Writes the report of `files`, with the paths relative to `source_root`.
+pub fn write_html<W: Write>(
+    mut w: W,
+    files: &[FileCoverage],
+    source_root: &Path,
+) -> io::Result<()> {
+    let mut root = Node::default();
+    let mut crate_names = BTreeMap::new();
+    for (i, file) in files.iter().enumerate() {
+        let (crate_name, module) = module_path(&file.path, source_root, &mut crate_names);
+        let mut path = vec![crate_name];
+        path.extend(module);
+        root.insert(&path, i);
+    }
+
+    let (covered, total) = root.totals(files);
+    writeln!(w, "<!DOCTYPE html>")?;
+    writeln!(w, r#"<html lang="en"><head><meta charset="utf-8">"#)?;
+    writeln!(w, "<title>Coverage report</title>")?;
+    writeln!(w, "<style>{}</style></head><body>", STYLE)?;
+    writeln!(w, "<nav>")?;
+    writeln!(
+        w,
+        r#"<input id="search" type="search" placeholder="Search files, modules and code">"#
+    )?;
+    let mut nav = String::new();
+    for (name, node) in &root.children {
+        write_node(&mut nav, name, node, files, source_root);
+    }
+    w.write_all(nav.as_bytes())?;
+    writeln!(w, "</nav><main>")?;
+
+    writeln!(w, r#"<section id="summary"><h1>Coverage report</h1>"#)?;
+    writeln!(
+        w,
+        "<p>{} of {} lines covered ({}) in {} files.</p></section>",
+        covered,
+        total,
+        percent_label(covered, total),
+        files.len()
+    )?;
+    for (i, file) in files.iter().enumerate() {
+        write_file(&mut w, i, file, source_root)?;
+    }
+    writeln!(w, "</main><script>{}</script></body></html>", SCRIPT)
+}
+
+fn write_node(out: &mut String, name: &str, node: &Node, files: &[FileCoverage], root: &Path) {
+    let (covered, total) = node.totals(files);
+    let _ = write!(
+        out,
+        "<details open><summary>{}{}</summary>",
+        escape(name),
+        percent_badge(covered, total)
+    );
+    for &i in &node.files {
+        let file = &files[i];
+        let relative = file.path.strip_prefix(root).unwrap_or(&file.path);
+        let (covered, total) = file_totals(file);
+        let _ = write!(
+            out,
+            r##"<a href="#f{}" data-name="{}">{}{}</a>"##,
+            i,
+            escape(&relative.to_string_lossy()),
+            escape(&relative.file_name().unwrap_or_default().to_string_lossy()),
+            percent_badge(covered, total)
+        );
+    }
+    for (name, child) in &node.children {
+        write_node(out, name, child, files, root);
+    }
+    out.push_str("</details>");
+}
+
+fn write_file<W: Write>(w: &mut W, i: usize, file: &FileCoverage, root: &Path) -> io::Result<()> {
+    let relative = file.path.strip_prefix(root).unwrap_or(&file.path);
+    let (covered, total) = file_totals(file);
+    writeln!(
+        w,
+        r#"<section id="f{}" hidden><h2>{} {}</h2><table>"#,
+        i,
+        escape(&relative.to_string_lossy()),
+        percent_badge(covered, total)
+    )?;
+    let source = match read_to_string(root.join(&file.path)) {
+        Ok(source) => source,
+        Err(_) => {
+            return writeln!(
+                w,
+                "</table><p>The source file cannot be read.</p></section>"
+            )
+        }
+    };
+    let mut state = State::Code;
+    for (n, line) in source.lines().enumerate() {
+        let number = n as u32 + 1;
+        let (class, hits) = match file.lines.get(&number) {
+            Some(&0) => ("miss", "0".to_owned()),
+            Some(&hits) => match file.branches.get(&number) {
+                Some(count) if count.is_partial() => ("partial", hits.to_string()),
+                _ => ("hit", hits.to_string()),
+            },
+            None => ("", String::new()),
+        };
+        writeln!(
+            w,
+            r#"<tr class="{}"><td class="n">{}</td><td class="h">{}</td><td class="s">{}</td></tr>"#,
+            class,
+            number,
+            hits,
+            highlight(line, &mut state)
+        )?;
+    }
+    writeln!(w, "</table></section>")
+}
+
+/// Finds the crate defining the file at `path`, named after the package of the closest
+/// `Cargo.toml`, and the module path of the file within it: `src/parser/mod.rs` is `parser`, and
+/// `tests/cli.rs` is `tests::cli`. The package names are cached in `crate_names`.
+fn module_path(
+    path: &Path,
+    source_root: &Path,
+    crate_names: &mut BTreeMap<PathBuf, Option<String>>,
+) -> (String, Vec<String>) {
+    let path = source_root.join(path);
+    let mut dir = path.parent();
+    while let Some(d) = dir {
+        let name = crate_names
+            .entry(d.to_owned())
+            .or_insert_with(|| package_name(&d.join("Cargo.toml")))
+            .clone();
+        if let Some(name) = name {
+            return (name, module_segments(path.strip_prefix(d).unwrap()));
+        }
+        if d == source_root {
+            break;
+        }
+        dir = d.parent();
+    }
+    let relative = path.strip_prefix(source_root).unwrap_or(&path);
+    let dir = relative.parent().unwrap_or_else(|| Path::new(""));
+    (
+        dir.to_string_lossy().into_owned(),
+        vec![relative
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned()],
+    )
+}
+
+fn package_name(manifest_path: &Path) -> Option<String> {
+    let manifest = read_to_string(manifest_path).ok()?;
+    let manifest = manifest.parse::<toml::Value>().ok()?;
+    manifest
+        .get("package")?
+        .get("name")?
+        .as_str()
+        .map(|s| s.to_owned())
+}
+
+/// The module path of a file relative to its package directory.
+fn module_segments(relative: &Path) -> Vec<String> {
+    let mut segments = relative
+        .components()
+        .filter_map(|c| match c {
+            Component::Normal(s) => Some(s.to_string_lossy().into_owned()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    if segments.first().is_some_and(|s| s == "src") {
+        segments.remove(0);
+    }
+    if let Some(last) = segments.pop() {
+        let stem = last.strip_suffix(".rs").unwrap_or(&last);
+        let is_root = segments.is_empty() && (stem == "lib" || stem == "main");
+        if stem != "mod" && !is_root {
+            segments.push(stem.to_owned());
+        }
+    }
+    segments
+}
+
+/// The lexer state carried over to the next line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Code,
+    /// Inside a block comment, with the nesting depth.
+    Comment(usize),
+    /// Inside a string literal, with the number of `#` of a raw string.
+    Str(Option<usize>),
+}
+
+/// Renders one line of Rust source as HTML with `<span>`s classifying the tokens.
+fn highlight(line: &str, state: &mut State) -> String {
+    let chars = line.chars().collect::<Vec<_>>();
+    let mut out = String::new();
+    let mut i = 0;
+    // Start of a comment or string opened on this line, rendered with its content.
+    let mut opened = None;
+    let span = |out: &mut String, class: &str, text: &[char]| {
+        let text = text.iter().collect::<String>();
+        let _ = write!(out, r#"<span class="{}">{}</span>"#, class, escape(&text));
+    };
+    while i < chars.len() {
+        let start = i;
+        match *state {
+            State::Comment(depth) => {
+                let start = opened.take().unwrap_or(start);
+                let mut depth = depth;
+                while i < chars.len() && depth > 0 {
+                    if chars[i] == '*' && chars.get(i + 1) == Some(&'/') {
+                        depth -= 1;
+                        i += 2;
+                    } else if chars[i] == '/' && chars.get(i + 1) == Some(&'*') {
+                        depth += 1;
+                        i += 2;
+                    } else {
+                        i += 1;
+                    }
+                }
+                *state = if depth > 0 {
+                    State::Comment(depth)
+                } else {
+                    State::Code
+                };
+                span(&mut out, "cm", &chars[start..i]);
+                continue;
+            }
+            State::Str(hashes) => {
+                let start = opened.take().unwrap_or(start);
+                while i < chars.len() {
+                    match (chars[i], hashes) {
+                        ('\\', None) => i += 2,
+                        ('"', None) => {
+                            i += 1;
+                            *state = State::Code;
+                            break;
+                        }
+                        ('"', Some(n))
+                            if chars[i + 1..].iter().take(n).filter(|&&c| c == '#').count()
+                                == n =>
+                        {
+                            i += 1 + n;
+                            *state = State::Code;
+                            break;
+                        }
+                        _ => i += 1,
+                    }
+                }
+                let end = i.min(chars.len());
+                span(&mut out, "st", &chars[start..end]);
+                i = end;
+                continue;
+            }
+            State::Code => {}
+        }
+
+        let c = chars[i];
+        let next = chars.get(i + 1).cloned();
+        if c == '/' && next == Some('/') {
+            span(&mut out, "cm", &chars[i..]);
+            break;
+        } else if c == '/' && next == Some('*') {
+            *state = State::Comment(1);
+            opened = Some(start);
+            i += 2;
+        } else if c == '"' {
+            *state = State::Str(None);
+            opened = Some(start);
+            i += 1;
+        } else if c == 'r' && (next == Some('"') || next == Some('#')) && {
+            let hashes = chars[i + 1..].iter().take_while(|&&c| c == '#').count();
+            chars.get(i + 1 + hashes) == Some(&'"')
+        } {
+            let hashes = chars[i + 1..].iter().take_while(|&&c| c == '#').count();
+            *state = State::Str(Some(hashes));
+            opened = Some(start);
+            i += hashes + 2;
+        } else if c == '\'' {
+            // A char literal like `'a'` or `'\n'`, otherwise a lifetime.
+            let end = if next == Some('\\') {
+                chars[i + 2..]
+                    .iter()
+                    .position(|&c| c == '\'')
+                    .map(|p| i + p + 3)
+            } else if chars.get(i + 2) == Some(&'\'') {
+                Some(i + 3)
+            } else {
+                None
+            };
+            match end {
+                Some(end) => {
+                    span(&mut out, "st", &chars[i..end]);
+                    i = end;
+                }
+                None => {
+                    i += 1;
+                    while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                        i += 1;
+                    }
+                    span(&mut out, "lt", &chars[start..i]);
+                }
+            }
+        } else if c == '#' && (next == Some('[') || next == Some('!')) {
+            let mut depth = 0;
+            while i < chars.len() {
+                match chars[i] {
+                    '[' => depth += 1,
+                    ']' => {
+                        depth -= 1;
+                        if depth == 0 {
+                            i += 1;
+                            break;
+                        }
+                    }
+                    _ => {}
+                }
+                i += 1;
+            }
+            span(&mut out, "at", &chars[start..i]);
+        } else if c.is_ascii_digit() {
+            while i < chars.len()
+                && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '.')
+            {
+                // `0..10` is a range, not a float.
+                if chars[i] == '.' && chars.get(i + 1) == Some(&'.') {
+                    break;
+                }
+                i += 1;
+            }
+            span(&mut out, "nu", &chars[start..i]);
+        } else if c.is_alphabetic() || c == '_' {
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let word = chars[start..i].iter().collect::<String>();
+            if chars.get(i) == Some(&'!') && chars.get(i + 1) != Some(&'=') {
+                i += 1;
+                span(&mut out, "ma", &chars[start..i]);
+            } else if KEYWORDS.contains(&&*word) {
+                span(&mut out, "kw", &chars[start..i]);
+            } else if c.is_uppercase() {
+                span(&mut out, "ty", &chars[start..i]);
+            } else {
+                out.push_str(&escape(&word));
+            }
+        } else {
+            let _ = write!(out, "{}", escape(&c.to_string()));
+            i += 1;
+        }
+    }
+    // A comment or string opened at the end of the line.
+    if let Some(start) = opened {
+        let class = if let State::Comment(_) = *state {
+            "cm"
+        } else {
+            "st"
+        };
+        span(&mut out, class, &chars[start..]);
+    }
+    out
+}
+
+fn percent_label(covered: usize, total: usize) -> String {
+    if total == 0 {
+        "-".to_owned()
+    } else {
+        format!("{:.1}%", covered as f64 * 100.0 / total as f64)
+    }
+}
+
+fn percent_badge(covered: usize, total: usize) -> String {
+    let class = match (covered * 100).checked_div(total) {
+        None => "",
+        Some(p) if p >= 90 => "high",
+        Some(p) if p >= 75 => "medium",
+        Some(_) => "low",
+    };
+    format!(
+        r#"<span class="pct {}" title="{}/{} lines">{}</span>"#,
+        class,
+        covered,
+        total,
+        percent_label(covered, total)
+    )
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[test]
+fn test_html() {
+    assert_eq!(
+        module_segments(Path::new("src/lib.rs")),
+        Vec::<String>::new()
+    );
+    assert_eq!(
+        module_segments(Path::new("src/main.rs")),
+        Vec::<String>::new()
+    );
+    assert_eq!(module_segments(Path::new("src/parser/mod.rs")), ["parser"]);
+    assert_eq!(
+        module_segments(Path::new("src/parser/expr.rs")),
+        ["parser", "expr"]
+    );
+    assert_eq!(module_segments(Path::new("tests/cli.rs")), ["tests", "cli"]);
+
+    let mut state = State::Code;
+    assert_eq!(
+        highlight(r#"let s: &'a str = "<a>"; // note"#, &mut state),
+        r#"<span class="kw">let</span> s: &amp;<span class="lt">'a</span> str = <span class="st">&quot;&lt;a&gt;&quot;</span>; <span class="cm">// note</span>"#
+    );
+    assert_eq!(
+        highlight("/* start", &mut state),
+        r#"<span class="cm">/* start</span>"#
+    );
+    assert_eq!(state, State::Comment(1));
+    assert_eq!(
+        highlight("end */ assert!(x == 'c');", &mut state),
+        "<span class=\"cm\">end */</span> <span class=\"ma\">assert!</span>(x == \
+         <span class=\"st\">'c'</span>);"
+    );
+    assert_eq!(state, State::Code);
+    assert_eq!(
+        highlight(r##"let raw = r#""##, &mut state),
+        r##"<span class="kw">let</span> raw = <span class="st">r#&quot;</span>"##
+    );
+    assert_eq!(state, State::Str(Some(1)));
+    assert_eq!(
+        highlight(r##"a"b"#;"##, &mut state),
+        r##"<span class="st">a&quot;b&quot;#</span>;"##
+    );
+
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let file = FileCoverage {
+        path: root.join("src/html.rs"),
+        lines: vec![(1, 0), (8, 2)].into_iter().collect(),
+        branches: BTreeMap::new(),
+    };
+    let mut html = Vec::new();
+    write_html(&mut html, &[file], root).unwrap();
+    let html = String::from_utf8(html).unwrap();
+    assert!(html.contains(r##"<summary>cargo-kcov<span class="pct low" title="1/2 lines">50.0%</span></summary><details open><summary>html"##));
+    assert!(html.contains(r##"<a href="#f0" data-name="src/html.rs">html.rs"##));
+    assert!(html.contains(r#"<tr class="miss"><td class="n">1</td><td class="h">0</td>"#));
+    assert!(html.contains(r#"<tr class="hit"><td class="n">8</td><td class="h">2</td>"#));
+}
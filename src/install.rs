@@ -0,0 +1,454 @@
+//! Building kcov from source into a directory managed by cargo-kcov.
+//!
+//! Each version is installed into its own directory `$CARGO_HOME/cargo-kcov/kcov-<version>`, which
+//! `check_kcov` searches when no kcov executable is given explicitly. The source can be a local
+//! tarball or directory, so kcov can be installed without network access. Otherwise the release
+//! tarball is downloaded once and kept in `$CARGO_HOME/cargo-kcov/downloads`.
+
+use std::env::var_os;
+use std::fs::{copy, create_dir_all, read_dir, read_to_string, remove_dir_all, rename, write};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread::available_parallelism;
+
+use sha2::{Digest, Sha256};
+
+use cache::hash_file;
+use errors::Error;
+use kcov::KcovVersion;
+use stderr::{write_msg, write_warning};
+
+/// The kcov release installed when neither a version nor a source is given.
+pub const DEFAULT_KCOV_VERSION: &str = "v42";
+
+/// SHA-256 of the release tarballs verified when downloaded without `--sha256`. The release of
+/// `DEFAULT_KCOV_VERSION` belongs here, so the default installation is always verified.
+const RELEASE_SHA256: &[(&str, &str)] = &[];
+
+/// Name of the file recording the SHA-256 of the source tarball an installation was built from.
+const SOURCE_CHECKSUM_FILE: &str = "source.sha256";
+
+/// Options of `cargo kcov install-kcov`.
+#[derive(Default, Debug, Clone)]
+pub struct InstallOptions {
+    pub(crate) version: Option<String>,
+    pub(crate) source: Option<PathBuf>,
+    pub(crate) sha256: Option<String>,
+    pub(crate) jobs: Option<u32>,
+    pub(crate) verbose: bool,
+}
+
+impl InstallOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The version to install, e.g. `v38`. Defaults to `DEFAULT_KCOV_VERSION` when downloading,
+    /// and to the version reported by the built executable otherwise.
+    pub fn version<S: Into<String>>(mut self, version: S) -> Self {
+        self.version = Some(version.into());
+        self
+    }
+
+    /// Builds from a local source tarball or directory instead of downloading the release.
+    pub fn source<P: Into<PathBuf>>(mut self, source: P) -> Self {
+        self.source = Some(source.into());
+        self
+    }
+
+    /// The expected SHA-256 of the source tarball, as a hexadecimal string.
+    pub fn sha256<S: Into<String>>(mut self, sha256: S) -> Self {
+        self.sha256 = Some(sha256.into());
+        self
+    }
+
+    /// The number of jobs to build kcov with, default to the number of CPUs.
+    pub fn jobs(mut self, jobs: u32) -> Self {
+        self.jobs = Some(jobs);
+        self
+    }
+
+    /// Prints the commands being run to stderr.
+    pub fn verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+}
+
+/// A kcov installation in the managed directory.
+#[derive(Debug, Clone)]
+pub struct InstalledKcov {
+    /// Path of the kcov executable.
+    pub path: PathBuf,
+    pub version: KcovVersion,
+    /// SHA-256 of the source tarball, `None` if built from a directory.
+    pub sha256: Option<String>,
+}
+
+/// The cargo home directory, `$CARGO_HOME` or `~/.cargo`.
+pub(crate) fn cargo_home() -> Option<PathBuf> {
+    match var_os("CARGO_HOME") {
+        Some(cargo_home) => Some(PathBuf::from(cargo_home)),
+        None => Some(Path::new(&var_os("HOME")?).join(".cargo")),
+    }
+}
+
+/// The directory containing the kcov installations, `$CARGO_HOME/cargo-kcov`.
+pub fn tools_dir() -> Option<PathBuf> {
+    cargo_home().map(|cargo_home| cargo_home.join("cargo-kcov"))
+}
+
+/// Lists the kcov installations in `tools_dir`, from the oldest to the newest version.
+pub fn installed_kcovs(tools_dir: &Path) -> Vec<InstalledKcov> {
+    let mut result = Vec::new();
+    let entries = match read_dir(tools_dir) {
+        Ok(entries) => entries,
+        Err(_) => return result,
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+        let name = entry.file_name();
+        let version = match name
+            .to_str()
+            .filter(|n| !n.ends_with(".tmp"))
+            .and_then(|n| n.strip_prefix("kcov-"))
+            .and_then(|v| KcovVersion::parse(&format!("kcov {}", v)))
+        {
+            Some(version) => version,
+            None => continue,
+        };
+        let dir = entry.path();
+        let path = dir.join("kcov");
+        if path.is_file() {
+            result.push(InstalledKcov {
+                path,
+                version,
+                sha256: read_to_string(dir.join(SOURCE_CHECKSUM_FILE))
+                    .ok()
+                    .map(|s| s.trim().to_owned()),
+            });
+        }
+    }
+    result.sort_by_key(|k| k.version);
+    result
+}
+
+/// Builds kcov from source and installs it into `tools_dir`.
+///
+/// Note that the build dependencies of kcov (cmake, a C++ compiler, libdw, ...) must already be
+/// installed.
+pub fn install_kcov(options: &InstallOptions) -> Result<InstalledKcov, Error> {
+    let tools_dir = tools_dir().ok_or_else(|| {
+        install_error(
+            io::ErrorKind::NotFound,
+            "cannot find the cargo home directory",
+        )
+    })?;
+    let version = options.version.as_deref().unwrap_or(DEFAULT_KCOV_VERSION);
+    let download_sha256 = match options.source {
+        Some(_) => None,
+        None => Some(expected_sha256(version, options.sha256.as_deref())?),
+    };
+    let build_dir = tools_dir.join("build");
+    let _ = remove_dir_all(&build_dir);
+    create_dir_all(&build_dir).map_err(|e| Error::InstallKcovFailed(Err(e)))?;
+
+    let (source_dir, sha256) = match options.source {
+        Some(ref source) if source.is_dir() => {
+            if options.sha256.is_some() {
+                return Err(install_error(
+                    io::ErrorKind::InvalidInput,
+                    "checksums can only be verified for a source tarball",
+                ));
+            }
+            (source.clone(), None)
+        }
+        Some(ref source) => {
+            let sha256 = verify_checksum(source, options.sha256.as_deref())?;
+            (extract(source, &build_dir, options.verbose)?, Some(sha256))
+        }
+        None => {
+            let tarball = download(&tools_dir, version, options.verbose)?;
+            let sha256 = verify_checksum(&tarball, download_sha256)?;
+            (
+                extract(&tarball, &build_dir, options.verbose)?,
+                Some(sha256),
+            )
+        }
+    };
+
+    let artifacts = build(&source_dir, &build_dir.join("build"), options)?;
+    let version = built_version(&artifacts[0], options.version.as_deref())?;
+
+    let dest = tools_dir.join(format!("kcov-{}", version));
+    let tmp = tools_dir.join(format!("kcov-{}.tmp", version));
+    let _ = remove_dir_all(&tmp);
+    create_dir_all(&tmp)
+        .and_then(|_| {
+            for artifact in &artifacts {
+                copy(artifact, tmp.join(artifact.file_name().unwrap()))?;
+            }
+            if let Some(ref sha256) = sha256 {
+                write(tmp.join(SOURCE_CHECKSUM_FILE), format!("{}\n", sha256))?;
+            }
+            let _ = remove_dir_all(&dest);
+            rename(&tmp, &dest)
+        })
+        .map_err(|e| Error::InstallKcovFailed(Err(e)))?;
+    let _ = remove_dir_all(&build_dir);
+
+    let installed = InstalledKcov {
+        path: dest.join("kcov"),
+        version,
+        sha256,
+    };
+    write_msg(
+        "Installed",
+        &format!("kcov {} to `{}`", version, installed.path.display()),
+    );
+    Ok(installed)
+}
+
+fn install_error(kind: io::ErrorKind, msg: &str) -> Error {
+    Error::InstallKcovFailed(Err(io::Error::new(kind, msg)))
+}
+
+/// Runs a command of the build, with its output shown to the user.
+fn run(cmd: &mut Command, verbose: bool) -> Result<(), Error> {
+    if verbose {
+        write_msg("Running", &format!("{:?}", cmd));
+    }
+    match cmd.status() {
+        Ok(ref s) if s.success() => Ok(()),
+        s => Err(Error::InstallKcovFailed(s)),
+    }
+}
+
+/// The SHA-256 a downloaded release tarball of `version` must have: `sha256` if given, otherwise
+/// the known checksum of the release. Unverified downloads are refused.
+fn expected_sha256<'a>(version: &str, sha256: Option<&'a str>) -> Result<&'a str, Error> {
+    let known = || {
+        RELEASE_SHA256
+            .iter()
+            .find(|&&(release, _)| release == version)
+            .map(|&(_, sha256)| sha256)
+    };
+    sha256.or_else(known).ok_or_else(|| {
+        install_error(
+            io::ErrorKind::InvalidInput,
+            &format!(
+                "the checksum of kcov {} is unknown, pass `--sha256` to verify the download",
+                version
+            ),
+        )
+    })
+}
+
+/// Computes the SHA-256 of `path`, and compares it with the expected checksum if given.
+fn verify_checksum(path: &Path, expected: Option<&str>) -> Result<String, Error> {
+    let mut hasher = Sha256::new();
+    hash_file(&mut hasher, path).map_err(|e| Error::InstallKcovFailed(Err(e)))?;
+    let actual = to_hex(&hasher.finalize());
+    match expected {
+        Some(expected) if !expected.trim().eq_ignore_ascii_case(&actual) => {
+            Err(Error::ChecksumMismatch(format!(
+                "expected sha256 {} for `{}`, found {}",
+                expected.trim(),
+                path.display(),
+                actual
+            )))
+        }
+        _ => Ok(actual),
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Downloads the release tarball of `version`, unless it was already downloaded before.
+fn download(tools_dir: &Path, version: &str, verbose: bool) -> Result<PathBuf, Error> {
+    let downloads = tools_dir.join("downloads");
+    let tarball = downloads.join(format!("kcov-{}.tar.gz", version));
+    if tarball.is_file() {
+        return Ok(tarball);
+    }
+    create_dir_all(&downloads).map_err(|e| Error::InstallKcovFailed(Err(e)))?;
+
+    let url = format!(
+        "https://github.com/SimonKagstrom/kcov/archive/{}.tar.gz",
+        version
+    );
+    write_msg("Downloading", &url);
+    let tmp = tarball.with_extension("tmp");
+    run(
+        Command::new("curl")
+            .args(["--location", "--fail", "--retry", "3", "--output"])
+            .arg(&tmp)
+            .arg(&url),
+        verbose,
+    )?;
+    rename(&tmp, &tarball).map_err(|e| Error::InstallKcovFailed(Err(e)))?;
+    Ok(tarball)
+}
+
+/// Extracts a source tarball into `build_dir/source`.
+fn extract(tarball: &Path, build_dir: &Path, verbose: bool) -> Result<PathBuf, Error> {
+    let source_dir = build_dir.join("source");
+    create_dir_all(&source_dir).map_err(|e| Error::InstallKcovFailed(Err(e)))?;
+    write_msg("Extracting", &tarball.to_string_lossy());
+    run(
+        Command::new("tar")
+            .arg("-xf")
+            .arg(tarball)
+            .arg("-C")
+            .arg(&source_dir)
+            .args(["--strip-components", "1"]),
+        verbose,
+    )?;
+    Ok(source_dir)
+}
+
+/// Builds kcov with cmake, returning the executable followed by the libraries it loads at runtime.
+fn build(
+    source_dir: &Path,
+    build_dir: &Path,
+    options: &InstallOptions,
+) -> Result<Vec<PathBuf>, Error> {
+    create_dir_all(build_dir).map_err(|e| Error::InstallKcovFailed(Err(e)))?;
+    write_msg("Building", &format!("kcov in `{}`", build_dir.display()));
+
+    let mut cmake = Command::new("cmake");
+    cmake
+        .current_dir(build_dir)
+        .arg("-DCMAKE_BUILD_TYPE=RelWithDebInfo");
+    let (output_dir, libraries) = if cfg!(target_os = "macos") {
+        run(cmake.arg("-GXcode").arg(source_dir), options.verbose)?;
+        run(
+            Command::new("xcodebuild")
+                .current_dir(build_dir)
+                .args(["-configuration", "Release"]),
+            options.verbose,
+        )?;
+        (build_dir.join("src/Release"), ["libkcov_system_lib.so"])
+    } else {
+        let jobs = options.jobs.map_or_else(
+            || available_parallelism().map_or(1, |n| n.get()),
+            |j| j as usize,
+        );
+        run(cmake.arg(source_dir), options.verbose)?;
+        run(
+            Command::new("make")
+                .current_dir(build_dir)
+                .arg(format!("-j{}", jobs)),
+            options.verbose,
+        )?;
+        (build_dir.join("src"), ["libkcov_sowrapper.so"])
+    };
+
+    let mut artifacts = vec![output_dir.join("kcov")];
+    artifacts.extend(
+        libraries
+            .iter()
+            .map(|lib| output_dir.join(lib))
+            .filter(|lib| lib.is_file()),
+    );
+    Ok(artifacts)
+}
+
+/// Determines the version of the built kcov, preferring what the executable reports.
+fn built_version(kcov: &Path, requested: Option<&str>) -> Result<KcovVersion, Error> {
+    let reported = Command::new(kcov)
+        .arg("--version")
+        .output()
+        .map_err(|e| Error::InstallKcovFailed(Err(e)))?;
+    let reported = KcovVersion::parse(&String::from_utf8_lossy(&reported.stdout));
+    let requested = requested.and_then(|v| KcovVersion::parse(&format!("kcov {}", v)));
+    match (reported, requested) {
+        (Some(reported), Some(requested)) => {
+            if (reported.major, reported.minor) != (requested.major, requested.minor) {
+                write_warning(&format!(
+                    "requested kcov {}, but the source builds kcov {}",
+                    requested, reported
+                ));
+            }
+            Ok(reported)
+        }
+        (Some(version), None) | (None, Some(version)) => Ok(version),
+        (None, None) => Err(install_error(
+            io::ErrorKind::InvalidData,
+            "cannot determine the version of the built kcov, please specify it",
+        )),
+    }
+}
+
+#[test]
+fn test_installed_kcovs() {
+    use std::fs::write;
+    use tempdir::TempDir;
+
+    let root = TempDir::new("cargo-kcov-install").unwrap();
+    for (dir, has_kcov) in &[
+        ("kcov-v38", true),
+        ("kcov-v40.1", true),
+        ("kcov-v36", true),
+        ("kcov-v41.tmp", true),
+        ("kcov-v42", false),
+        ("downloads", false),
+    ] {
+        let dir = root.path().join(dir);
+        create_dir_all(&dir).unwrap();
+        if *has_kcov {
+            write(dir.join("kcov"), "").unwrap();
+        }
+    }
+    write(
+        root.path().join("kcov-v38").join(SOURCE_CHECKSUM_FILE),
+        "abcd\n",
+    )
+    .unwrap();
+
+    let installed = installed_kcovs(root.path());
+    let versions = installed
+        .iter()
+        .map(|k| k.version.to_string())
+        .collect::<Vec<_>>();
+    assert_eq!(versions, ["v36", "v38", "v40.1"]);
+    assert_eq!(installed[1].sha256.as_deref(), Some("abcd"));
+    assert_eq!(installed[2].path, root.path().join("kcov-v40.1/kcov"));
+
+    assert!(installed_kcovs(&root.path().join("missing")).is_empty());
+}
+
+#[test]
+fn test_verify_checksum() {
+    use std::fs::write;
+    use tempdir::TempDir;
+
+    let root = TempDir::new("cargo-kcov-install").unwrap();
+    let path = root.path().join("kcov.tar.gz");
+    write(&path, "abc").unwrap();
+    let sha256 = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    assert_eq!(verify_checksum(&path, None).unwrap(), sha256);
+    assert_eq!(
+        verify_checksum(&path, Some(&sha256.to_uppercase())).unwrap(),
+        sha256
+    );
+    match verify_checksum(&path, Some("0123")) {
+        Err(Error::ChecksumMismatch(msg)) => assert!(msg.contains(sha256)),
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn test_expected_sha256() {
+    assert_eq!(expected_sha256("v38", Some("abcd")).unwrap(), "abcd");
+    for &(version, sha256) in RELEASE_SHA256 {
+        assert_eq!(expected_sha256(version, None).unwrap(), sha256);
+    }
+    match expected_sha256("v1", None) {
+        Err(Error::InstallKcovFailed(Err(e))) => assert!(e.to_string().contains("kcov v1")),
+        r => panic!("unexpected result {:?}", r),
+    }
+}
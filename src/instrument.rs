@@ -0,0 +1,390 @@
+//! Collecting coverage with rustc's source-based coverage.
+//!
+//! kcov traces the tests with ptrace, which misses inlined code and cannot see into cross-compiled
+//! tests run by an emulator such as qemu-user. Instead, the tests are built with
+//! `-C instrument-coverage`, which makes them write raw profiles on exit, and the profiles are
+//! turned into a report by `llvm-profdata` and `llvm-cov` of the `llvm-tools-preview` rustup
+//! component.
+
+use std::ffi::OsString;
+use std::fs::{create_dir_all, read_dir, remove_file, write, File};
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use regex::escape;
+
+use backend::Backend;
+use errors::Error;
+use install::cargo_home;
+use kcov::TestRun;
+use lcov::{read_lcov, write_cobertura, COBERTURA_FILE, LCOV_FILE};
+use stderr::write_msg;
+use summary::LLVM_COV_SUMMARY;
+
+/// `RUSTFLAGS` used to build the tests.
+pub const INSTRUMENT_RUSTFLAGS: &str = "-C instrument-coverage";
+
+/// Further `RUSTFLAGS` instrumenting the branches, which requires a nightly compiler.
+pub const BRANCH_RUSTFLAGS: &str = "-Z coverage-options=branch";
+
+/// The LLVM tools turning raw profiles into reports.
+#[derive(Debug, Clone)]
+pub struct LlvmTools {
+    pub profdata: PathBuf,
+    pub cov: PathBuf,
+}
+
+impl LlvmTools {
+    /// Finds the tools installed with the active toolchain, since the profile format changes with
+    /// the LLVM version. Falls back to `PATH` if the rustup component is not installed.
+    pub fn find() -> Result<LlvmTools, Error> {
+        let bin_dir = rustlib_bin_dir();
+        let find = |name: &str| match bin_dir {
+            Some(ref dir) if dir.join(name).is_file() => Some(dir.join(name)),
+            _ => Command::new(name)
+                .arg("--version")
+                .output()
+                .ok()
+                .filter(|o| o.status.success())
+                .map(|_| PathBuf::from(name)),
+        };
+        match (find("llvm-profdata"), find("llvm-cov")) {
+            (Some(profdata), Some(cov)) => Ok(LlvmTools { profdata, cov }),
+            _ => Err(Error::LlvmToolsNotInstalled),
+        }
+    }
+}
+
+/// `$(rustc --print sysroot)/lib/rustlib/<host>/bin`, where `llvm-tools-preview` is installed.
+fn rustlib_bin_dir() -> Option<PathBuf> {
+    let sysroot = Command::new("rustc")
+        .args(["--print", "sysroot"])
+        .output()
+        .ok()?;
+    let version = Command::new("rustc").arg("-vV").output().ok()?;
+    let version = String::from_utf8(version.stdout).ok()?;
+    let host = version.lines().find_map(|l| l.strip_prefix("host: "))?;
+    let sysroot = String::from_utf8(sysroot.stdout).ok()?;
+    Some(
+        Path::new(sysroot.trim())
+            .join("lib/rustlib")
+            .join(host)
+            .join("bin"),
+    )
+}
+
+/// Runs the instrumented tests, and creates the report with the LLVM tools.
+pub struct InstrumentBackend {
+    tools: LlvmTools,
+    rustflags: String,
+    runner: Vec<String>,
+    source_root: PathBuf,
+    is_branch: bool,
+    is_verbose: bool,
+}
+
+impl InstrumentBackend {
+    pub fn new(tools: LlvmTools) -> Self {
+        InstrumentBackend {
+            tools,
+            rustflags: INSTRUMENT_RUSTFLAGS.to_owned(),
+            runner: Vec::new(),
+            source_root: PathBuf::new(),
+            is_branch: false,
+            is_verbose: false,
+        }
+    }
+
+    /// Collects branch coverage too. The tests must be built by a nightly compiler.
+    pub fn branch(mut self, is_branch: bool) -> Self {
+        self.rustflags = INSTRUMENT_RUSTFLAGS.to_owned();
+        if is_branch {
+            self.rustflags.push(' ');
+            self.rustflags.push_str(BRANCH_RUSTFLAGS);
+        }
+        self.is_branch = is_branch;
+        self
+    }
+
+    /// The directory the paths in `cobertura.xml` are relative to, usually the workspace root.
+    pub fn source_root<P: Into<PathBuf>>(mut self, source_root: P) -> Self {
+        self.source_root = source_root.into();
+        self
+    }
+
+    /// Runs the tests with this command, e.g. the `target.<triple>.runner` of a cross target. The
+    /// first word is the program.
+    pub fn runner(mut self, runner: Vec<String>) -> Self {
+        self.runner = runner;
+        self
+    }
+
+    /// Prints the commands being run to stderr.
+    pub fn verbose(mut self, is_verbose: bool) -> Self {
+        self.is_verbose = is_verbose;
+        self
+    }
+
+    /// Runs an LLVM tool, returning its stdout.
+    fn run_tool(&self, cmd: &mut Command) -> Result<Vec<u8>, Error> {
+        if self.is_verbose {
+            write_msg("Running", &format!("{:?}", cmd));
+        }
+        let program = cmd.get_program().to_string_lossy().into_owned();
+        match cmd.output() {
+            Ok(o) if o.status.success() => Ok(o.stdout),
+            Ok(o) => Err(Error::CommandFailed {
+                program,
+                status: Ok(o.status),
+                stderr: o.stderr,
+            }),
+            Err(e) => Err(Error::CommandFailed {
+                program,
+                status: Err(e),
+                stderr: Vec::new(),
+            }),
+        }
+    }
+}
+
+impl Backend for InstrumentBackend {
+    fn rustflags(&self) -> &str {
+        &self.rustflags
+    }
+
+    /// Runs each test executable, writing the raw profiles into `cov_path/profraw`.
+    fn run_tests(&self, cov_path: &Path, tests: Vec<PathBuf>) -> Result<Vec<TestRun>, Error> {
+        let profraw_path = cov_path.join("profraw");
+        create_dir_all(&profraw_path).map_err(Error::CannotCreateCoverageDirectory)?;
+
+        let mut runs = Vec::with_capacity(tests.len());
+        for test in tests {
+            let mut profile_file = profraw_path
+                .join(test.file_name().unwrap())
+                .into_os_string();
+            profile_file.push("-%p-%m.profraw");
+            let mut cmd = match self.runner.split_first() {
+                Some((program, args)) => {
+                    let mut cmd = Command::new(program);
+                    cmd.args(args).arg(&test);
+                    cmd
+                }
+                None => Command::new(&test),
+            };
+            cmd.env("LLVM_PROFILE_FILE", profile_file);
+            if self.is_verbose {
+                write_msg("Running", &format!("{:?}", cmd));
+            }
+            match cmd.status() {
+                Ok(ref s) if s.success() => {}
+                s => {
+                    let program = cmd.get_program().to_string_lossy().into_owned();
+                    return Err(Error::CommandFailed {
+                        program,
+                        status: s,
+                        stderr: Vec::new(),
+                    });
+                }
+            }
+            runs.push(TestRun {
+                executable: test,
+                cov_path: profraw_path.clone(),
+                cached: false,
+            });
+        }
+        Ok(runs)
+    }
+
+    /// Merges the raw profiles of `test_runs`, and writes the HTML report, `lcov.info`,
+    /// `cobertura.xml` and the summary into `cov_path`.
+    fn merge(&self, cov_path: &Path, test_runs: &[TestRun]) -> Result<(), Error> {
+        let mut profraw_paths = test_runs.iter().map(|r| &r.cov_path).collect::<Vec<_>>();
+        profraw_paths.sort();
+        profraw_paths.dedup();
+        let mut profraws = Vec::new();
+        for path in profraw_paths {
+            if let Ok(entries) = read_dir(path) {
+                profraws.extend(
+                    entries
+                        .filter_map(|e| e.ok())
+                        .map(|e| e.path())
+                        .filter(|p| p.extension().is_some_and(|e| e == "profraw")),
+                );
+            }
+        }
+        let mut objects = test_runs.iter().map(|r| &r.executable).collect::<Vec<_>>();
+        objects.sort();
+        objects.dedup();
+        if profraws.is_empty() || objects.is_empty() {
+            return Err(Error::CannotFindCoverageData(
+                cov_path.join("profraw"),
+                None,
+            ));
+        }
+
+        let profdata = cov_path.join("coverage.profdata");
+        self.run_tool(
+            Command::new(&self.tools.profdata)
+                .args(["merge", "-sparse", "-o"])
+                .arg(&profdata)
+                .args(&profraws),
+        )?;
+
+        let mut common_args = vec![
+            OsString::from("-instr-profile"),
+            profdata.into_os_string(),
+            OsString::from(format!(
+                "-ignore-filename-regex={}",
+                ignore_regex(cargo_home().as_deref())
+            )),
+            objects[0].clone().into_os_string(),
+        ];
+        for object in &objects[1..] {
+            common_args.push("-object".into());
+            common_args.push(object.into());
+        }
+
+        let mut output_dir = OsString::from("-output-dir=");
+        output_dir.push(cov_path);
+        let mut show = Command::new(&self.tools.cov);
+        show.args(["show", "-format=html"]).arg(output_dir);
+        if self.is_branch {
+            show.arg("-show-branches=count");
+        }
+        self.run_tool(show.args(&common_args))?;
+        for &(args, file) in &[
+            (&["export", "-format=lcov"][..], LCOV_FILE),
+            (&["export", "-summary-only"][..], LLVM_COV_SUMMARY),
+        ] {
+            let stdout =
+                self.run_tool(Command::new(&self.tools.cov).args(args).args(&common_args))?;
+            write(cov_path.join(file), stdout).map_err(Error::CannotCreateCoverageDirectory)?;
+        }
+
+        let files =
+            read_lcov(&cov_path.join(LCOV_FILE)).map_err(Error::CannotCreateCoverageDirectory)?;
+        let cobertura = File::create(cov_path.join(COBERTURA_FILE))
+            .map_err(Error::CannotCreateCoverageDirectory)?;
+        write_cobertura(BufWriter::new(cobertura), &files, &self.source_root)
+            .map_err(Error::CannotCreateCoverageDirectory)
+    }
+
+    /// Each run writes new profiles named after the process ID, so the old ones are deleted.
+    fn remove_results(&self, cov_path: &Path, test: &Path) -> Result<(), Error> {
+        let mut prefix = test.file_name().unwrap().to_os_string();
+        prefix.push("-");
+        let prefix = prefix.to_string_lossy().into_owned();
+        let entries = match read_dir(cov_path.join("profraw")) {
+            Ok(entries) => entries,
+            Err(_) => return Ok(()),
+        };
+        for entry in entries.filter_map(|e| e.ok()) {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name.starts_with(&prefix) && name.ends_with(".profraw") {
+                remove_file(entry.path()).map_err(Error::CannotCreateCoverageDirectory)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Excludes dependencies in `cargo_home` and the standard library, like the default
+/// `--exclude-pattern` of kcov.
+fn ignore_regex(cargo_home: Option<&Path>) -> String {
+    let mut regex = String::from("^/rustc/");
+    if let Some(cargo_home) = cargo_home {
+        regex.push_str("|^");
+        regex.push_str(&escape(&cargo_home.to_string_lossy()));
+        regex.push('/');
+    }
+    regex
+}
+
+#[test]
+fn test_run_tests() {
+    use std::fs::read_to_string;
+    use tempdir::TempDir;
+
+    let root = TempDir::new("cargo-kcov-instrument").unwrap();
+    let cov_path = root.path().join("cov");
+    let tools = LlvmTools {
+        profdata: PathBuf::from("llvm-profdata"),
+        cov: PathBuf::from("llvm-cov"),
+    };
+    let tests = vec![
+        root.path().join("first-d5d6293fc6d22a93"),
+        root.path().join("second-f0ac3ec8d3d3bcd5"),
+    ];
+
+    // The runner records the profile file pattern next to each test.
+    let script = "printf %s \"$LLVM_PROFILE_FILE\" > \"$0.profile\"";
+    let backend = InstrumentBackend::new(tools.clone()).runner(vec![
+        "sh".to_owned(),
+        "-c".to_owned(),
+        script.to_owned(),
+    ]);
+    let runs = backend.run_tests(&cov_path, tests.clone()).unwrap();
+    assert_eq!(runs.len(), 2);
+    for (run, test) in runs.iter().zip(&tests) {
+        assert_eq!(run.executable, *test);
+        assert_eq!(run.cov_path, cov_path.join("profraw"));
+        assert!(!run.cached);
+        let name = test.file_name().unwrap().to_str().unwrap();
+        assert_eq!(
+            read_to_string(test.with_extension("profile")).unwrap(),
+            format!("{}/profraw/{}-%p-%m.profraw", cov_path.display(), name)
+        );
+    }
+
+    let backend = InstrumentBackend::new(tools).runner(vec!["false".to_owned()]);
+    match backend.run_tests(&cov_path, tests) {
+        Err(Error::CommandFailed { ref program, .. }) if program == "false" => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+}
+
+#[test]
+fn test_remove_results() {
+    use tempdir::TempDir;
+
+    let root = TempDir::new("cargo-kcov-instrument").unwrap();
+    let profraw_path = root.path().join("profraw");
+    create_dir_all(&profraw_path).unwrap();
+    let files = [
+        "first-d5d6293fc6d22a93-1234-5678.profraw",
+        "first-d5d6293fc6d22a93-4321-8765.profraw",
+        "first-d5d6293fc6d22a93.log",
+        "first-d5d6293fc6d22a93x-1234-5678.profraw",
+        "second-f0ac3ec8d3d3bcd5-1234-5678.profraw",
+    ];
+    for file in &files {
+        write(profraw_path.join(file), "").unwrap();
+    }
+    let backend = InstrumentBackend::new(LlvmTools {
+        profdata: PathBuf::from("llvm-profdata"),
+        cov: PathBuf::from("llvm-cov"),
+    });
+    let test = Path::new("/ws/target/debug/deps/first-d5d6293fc6d22a93");
+    backend.remove_results(root.path(), test).unwrap();
+    let kept = files
+        .iter()
+        .filter(|file| profraw_path.join(file).exists())
+        .collect::<Vec<_>>();
+    assert_eq!(kept, [&files[2], &files[3], &files[4]]);
+
+    // Nothing to remove before the first run.
+    backend
+        .remove_results(&root.path().join("missing"), test)
+        .unwrap();
+}
+
+#[test]
+fn test_ignore_regex() {
+    assert_eq!(ignore_regex(None), "^/rustc/");
+    assert_eq!(
+        ignore_regex(Some(Path::new("/home/a.b/.cargo+1"))),
+        "^/rustc/|^/home/a\\.b/\\.cargo\\+1/"
+    );
+}
@@ -0,0 +1,544 @@
+//! Running kcov.
+
+use std::env::var_os;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use cache::Cache;
+use cargo::Cmd;
+use config::Config;
+use errors::Error;
+use install::{cargo_home, installed_kcovs, tools_dir};
+use options::CoverageOptions;
+use runner::Runner;
+use stderr::write_msg;
+
+/// Result of running one test executable under kcov.
+#[derive(Debug, Clone)]
+pub struct TestRun {
+    /// Path of the test executable.
+    pub executable: PathBuf,
+    /// Directory containing the kcov output of this executable.
+    pub cov_path: PathBuf,
+    /// Whether the output was reused from the cache instead of running the executable.
+    pub cached: bool,
+}
+
+/// The oldest kcov supported, since earlier versions do not report their version number.
+pub const MIN_KCOV_VERSION: u32 = 30;
+
+/// The oldest kcov known to work on macOS.
+pub const MIN_KCOV_VERSION_MACOS: u32 = 35;
+
+/// Version of kcov, parsed from the output of `kcov --version`.
+///
+/// Releases print `kcov v38`, builds from git print the output of `git describe`, e.g.
+/// `kcov 38-12-g6e0bba7`, and distributions may append their own suffix, e.g. `kcov 36+dfsg-1`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct KcovVersion {
+    pub major: u32,
+    pub minor: u32,
+    /// Number of commits after the release, for builds from git.
+    pub commits: u32,
+}
+
+impl KcovVersion {
+    pub fn parse(output: &str) -> Option<KcovVersion> {
+        let line = output.lines().next()?.trim();
+        let rest = line.strip_prefix("kcov")?.trim_start();
+        let rest = rest.strip_prefix('v').unwrap_or(rest);
+
+        let (major, rest) = split_number(rest)?;
+        let (minor, rest) = match rest.strip_prefix('.').and_then(split_number) {
+            Some((minor, rest)) => (minor, rest),
+            None => (0, rest),
+        };
+        let commits = rest
+            .strip_prefix('-')
+            .and_then(split_number)
+            .filter(|&(_, rest)| rest.starts_with("-g"))
+            .map_or(0, |(commits, _)| commits);
+        Some(KcovVersion {
+            major,
+            minor,
+            commits,
+        })
+    }
+
+    /// Whether this version satisfies the version pinned in the configuration, e.g. `v38`. Builds
+    /// from git match their release, and a pin without the minor version matches all of them.
+    pub fn matches_pin(&self, pin: &str) -> bool {
+        match KcovVersion::parse(&format!("kcov {}", pin)) {
+            Some(pinned) => {
+                self.major == pinned.major && (!pin.contains('.') || self.minor == pinned.minor)
+            }
+            None => false,
+        }
+    }
+
+    /// Whether `--replace-src-path` is available, used when merging coverage from another checkout.
+    pub fn supports_replace_src_path(&self) -> bool {
+        self.major >= 34
+    }
+
+    /// Describes a known problem of this version on the current platform, if any.
+    pub fn known_issue(&self) -> Option<&'static str> {
+        if cfg!(target_os = "macos") && self.major < MIN_KCOV_VERSION_MACOS {
+            Some("kcov before v35 may not collect coverage correctly on macOS, please upgrade")
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for KcovVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "v{}", self.major)?;
+        if self.minor != 0 {
+            write!(f, ".{}", self.minor)?;
+        }
+        if self.commits != 0 {
+            write!(f, "-{}", self.commits)?;
+        }
+        Ok(())
+    }
+}
+
+/// Splits the leading decimal number from `s`.
+fn split_number(s: &str) -> Option<(u32, &str)> {
+    let end = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    Some((s[..end].parse().ok()?, &s[end..]))
+}
+
+/// Where `check_kcov` found the kcov executable, in the order of preference.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KcovSource {
+    /// The `--kcov` option.
+    Flag,
+    /// The `KCOV` environment variable.
+    Env,
+    /// The `kcov` key in `[package.metadata.kcov]`.
+    Config,
+    /// The directory managed by `cargo kcov install-kcov`.
+    Managed,
+    /// `$CARGO_HOME/bin`.
+    CargoHome,
+    /// The `PATH` environment variable.
+    Path,
+    /// The `PATH` seen by the `--runner` command.
+    Runner,
+}
+
+impl fmt::Display for KcovSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            KcovSource::Flag => "--kcov",
+            KcovSource::Env => "$KCOV",
+            KcovSource::Config => "the project configuration",
+            KcovSource::Managed => "the cargo-kcov install directory",
+            KcovSource::CargoHome => "$CARGO_HOME/bin",
+            KcovSource::Path => "$PATH",
+            KcovSource::Runner => "the runner",
+        })
+    }
+}
+
+/// A kcov executable found by `check_kcov`.
+#[derive(Debug, Clone)]
+pub struct KcovExecutable {
+    pub path: PathBuf,
+    /// The version, `None` if kcov reports a version which cannot be parsed.
+    pub version: Option<KcovVersion>,
+    pub source: KcovSource,
+    /// The command wrapping kcov, if any.
+    pub runner: Option<Runner>,
+}
+
+impl KcovExecutable {
+    /// Prepares the command running kcov with `args`, through the runner if any.
+    pub(crate) fn command<S: AsRef<OsStr>>(&self, args: &[S]) -> Cmd {
+        match self.runner {
+            Some(ref runner) => runner.wrap(&self.path, args),
+            None => Cmd::new(&self.path, "").args(args),
+        }
+    }
+
+    /// Like `command`, with `val` added to the environment variable `key`. Runners such as
+    /// `docker run` do not pass the environment on, so through a runner the variable is set to
+    /// `val` inside it by `env` instead.
+    pub(crate) fn command_with_env<S: AsRef<OsStr>>(
+        &self,
+        args: &[S],
+        key: &str,
+        val: &str,
+    ) -> Cmd {
+        match self.runner {
+            Some(ref runner) => {
+                let mut env_args = vec![
+                    OsString::from(format!("{}={}", key, val)),
+                    self.path.clone().into_os_string(),
+                ];
+                env_args.extend(args.iter().map(|arg| arg.as_ref().to_owned()));
+                runner.wrap(Path::new("env"), &env_args)
+            }
+            None => self.command(args).env(key, ":", val),
+        }
+    }
+
+    /// Whether `--replace-src-path` is available. Unknown versions are assumed to be recent.
+    pub fn supports_replace_src_path(&self) -> bool {
+        self.version.is_none_or(|v| v.supports_replace_src_path())
+    }
+}
+
+impl fmt::Display for KcovExecutable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "`{}`", self.path.display())?;
+        match self.version {
+            Some(version) => write!(f, " ({})", version)?,
+            None => f.write_str(" (unknown version)")?,
+        }
+        write!(f, " from {}", self.source)
+    }
+}
+
+/// Runs test executables under kcov, and merges the results.
+pub struct Kcov {
+    kcov: KcovExecutable,
+    kcov_args: Vec<OsString>,
+    cache: Option<Cache>,
+    is_verbose: bool,
+}
+
+impl Kcov {
+    /// Creates a runner using the kcov executable obtained from `check_kcov`.
+    pub fn new(kcov: KcovExecutable, kcov_args: Vec<OsString>) -> Self {
+        Kcov {
+            kcov,
+            kcov_args,
+            cache: None,
+            is_verbose: false,
+        }
+    }
+
+    /// Reuses the results of unchanged test executables, cached in `target_path/kcov-cache`.
+    pub fn cache(mut self, target_path: &Path) -> Self {
+        self.cache = Some(Cache::new(target_path));
+        self
+    }
+
+    /// Prints the commands being run to stderr.
+    pub fn verbose(mut self, is_verbose: bool) -> Self {
+        self.is_verbose = is_verbose;
+        self
+    }
+
+    /// The kcov executable used.
+    pub fn executable(&self) -> &KcovExecutable {
+        &self.kcov
+    }
+
+    /// Appends further arguments passed to kcov.
+    pub fn args<I: IntoIterator<Item = OsString>>(mut self, args: I) -> Self {
+        self.kcov_args.extend(args);
+        self
+    }
+
+    /// Runs each test executable with kcov, writing the results into subdirectories of
+    /// `cov_path`.
+    pub fn run_tests(&self, cov_path: &Path, tests: Vec<PathBuf>) -> Result<Vec<TestRun>, Error> {
+        let no_args: &[OsString] = &[];
+        let mut runs = Vec::with_capacity(tests.len());
+        for test in tests {
+            let pre_cov_path = cov_path.join(test.file_name().unwrap());
+            let cache_entry = match self.cache {
+                Some(ref cache) => Some((
+                    cache,
+                    cache.key(self.kcov.path.as_os_str(), &self.kcov_args, &test, no_args)?,
+                )),
+                None => None,
+            };
+            let mut cached = false;
+            if let Some((cache, ref key)) = cache_entry {
+                cached = cache.restore(key, &pre_cov_path)?;
+            }
+            if cached {
+                if self.is_verbose {
+                    write_msg("Cached", &test.to_string_lossy());
+                }
+            } else {
+                self.run_command(&pre_cov_path, test.as_os_str(), no_args)?;
+                if let Some((cache, ref key)) = cache_entry {
+                    cache.store(key, &pre_cov_path)?;
+                }
+            }
+            runs.push(TestRun {
+                executable: test,
+                cov_path: pre_cov_path,
+                cached,
+            });
+        }
+        Ok(runs)
+    }
+
+    /// Runs a single command with kcov, writing the result into `pre_cov_path`.
+    pub fn run_command(
+        &self,
+        pre_cov_path: &Path,
+        program: &OsStr,
+        args: &[OsString],
+    ) -> Result<(), Error> {
+        let cmd = self.command(pre_cov_path, program, args);
+        if self.is_verbose {
+            write_msg("Running", &cmd.to_string());
+        }
+        cmd.run_kcov()
+    }
+
+    /// Prepares the command of `run_command`. The dynamic libraries of the workspace are found in
+    /// `target/debug/deps`, also inside the runner.
+    fn command(&self, pre_cov_path: &Path, program: &OsStr, args: &[OsString]) -> Cmd {
+        let mut kcov_args = self.kcov_args.clone();
+        kcov_args.push(pre_cov_path.into());
+        kcov_args.push(program.into());
+        kcov_args.extend_from_slice(args);
+        self.kcov
+            .command_with_env(&kcov_args, "LD_LIBRARY_PATH", "target/debug/deps")
+    }
+
+    /// Runs `kcov --merge` to combine the per-executable results into the final report in
+    /// `cov_path`.
+    pub fn merge(
+        &self,
+        cov_path: &Path,
+        coveralls_option: Option<OsString>,
+        merge_cov_paths: &[PathBuf],
+    ) -> Result<(), Error> {
+        let mut merge_args = vec![OsString::from("--merge")];
+        merge_args.extend_from_slice(&self.kcov_args);
+        merge_args.push(cov_path.into());
+        merge_args.extend(coveralls_option);
+        merge_args.extend(merge_cov_paths.iter().map(OsString::from));
+        let merge_cmd = self.kcov.command(&merge_args);
+        if self.is_verbose {
+            write_msg("Running", &merge_cmd.to_string());
+        }
+        merge_cmd.run_kcov()
+    }
+}
+
+/// Checks whether kcov is installed and recent enough, and returns the executable.
+///
+/// The executable is searched in the order of `KcovSource`. The first three are explicit choices,
+/// and fail if they do not satisfy the `kcov-version` pinned in `config`. The other locations are
+/// skipped if they do not satisfy the pin. With a `runner`, the host locations are not searched,
+/// and kcov is checked by running it through the runner.
+pub fn check_kcov(
+    options: &CoverageOptions,
+    config: &Config,
+    runner: Option<&Runner>,
+) -> Result<KcovExecutable, Error> {
+    if cfg!(any(target_os = "windows")) && runner.is_none() {
+        return Err(Error::UnsupportedOS);
+    }
+
+    let pin = config.kcov_version.as_deref();
+    let explicit = options
+        .kcov_path
+        .clone()
+        .map(|path| (path, KcovSource::Flag))
+        .or_else(|| {
+            var_os("KCOV")
+                .filter(|path| !path.is_empty() && runner.is_none())
+                .map(|path| (PathBuf::from(path), KcovSource::Env))
+        })
+        .or_else(|| {
+            config
+                .kcov_path
+                .clone()
+                .map(|path| (path, KcovSource::Config))
+        });
+    if let Some((path, source)) = explicit {
+        let kcov = probe_kcov(path, source, runner)?;
+        return match pin {
+            Some(pin) if !kcov.version.is_some_and(|v| v.matches_pin(pin)) => {
+                Err(Error::KcovVersionMismatch(kcov, pin.to_owned()))
+            }
+            _ => Ok(kcov),
+        };
+    }
+
+    let mut candidates = Vec::new();
+    if runner.is_some() {
+        candidates.push((PathBuf::from("kcov"), KcovSource::Runner));
+    } else if let Some(tools_dir) = tools_dir() {
+        let mut installed = installed_kcovs(&tools_dir);
+        installed.retain(|k| pin.is_none_or(|pin| k.version.matches_pin(pin)));
+        if let Some(newest) = installed.pop() {
+            candidates.push((newest.path, KcovSource::Managed));
+        }
+    }
+    if runner.is_none() {
+        if let Some(cargo_home) = cargo_home() {
+            let path = cargo_home.join("bin").join("kcov");
+            if path.is_file() {
+                candidates.push((path, KcovSource::CargoHome));
+            }
+        }
+        candidates.push((PathBuf::from("kcov"), KcovSource::Path));
+    }
+
+    let mut error = None;
+    for (path, source) in candidates {
+        match (probe_kcov(path, source, runner), pin) {
+            (Ok(kcov), Some(pin)) if !kcov.version.is_some_and(|v| v.matches_pin(pin)) => {
+                error = Some(Error::KcovVersionMismatch(kcov, pin.to_owned()));
+            }
+            (Ok(kcov), _) => return Ok(kcov),
+            // A version mismatch is more useful to report than the absence of later candidates.
+            (Err(e), _) => {
+                if !matches!(error, Some(Error::KcovVersionMismatch(..))) {
+                    error = Some(e);
+                }
+            }
+        }
+    }
+    Err(error.unwrap())
+}
+
+/// Runs `kcov --version` to check the executable at `path`.
+fn probe_kcov(
+    path: PathBuf,
+    source: KcovSource,
+    runner: Option<&Runner>,
+) -> Result<KcovExecutable, Error> {
+    let mut kcov = KcovExecutable {
+        path,
+        version: None,
+        source,
+        runner: runner.cloned(),
+    };
+    let output = match kcov.command(&["--version"]).output_raw() {
+        Ok(o) => o,
+        Err(e) => return Err(Error::KcovNotInstalled(e)),
+    };
+    // kcov before v30 does not understand `--version`, and prints the usage instead.
+    if !output.stdout.starts_with(b"kcov ") {
+        return Err(Error::KcovTooOld(None));
+    }
+
+    kcov.version = KcovVersion::parse(&String::from_utf8_lossy(&output.stdout));
+    match kcov.version {
+        Some(v) if v.major < MIN_KCOV_VERSION => Err(Error::KcovTooOld(Some(v))),
+        _ => Ok(kcov),
+    }
+}
+
+/// Arguments passed to kcov, either given by the user or the defaults.
+pub fn kcov_args(options: &CoverageOptions) -> Vec<OsString> {
+    match options.kcov_args {
+        Some(ref args) => args.clone(),
+        None => default_kcov_args(),
+    }
+}
+
+/// Default arguments passed to kcov when the user did not supply any.
+pub fn default_kcov_args() -> Vec<OsString> {
+    let mut exclude_pattern = OsString::from("--exclude-pattern=");
+    exclude_pattern.push(
+        var_os("CARGO_HOME")
+            .as_ref()
+            .map_or(OsStr::new("/.cargo"), |s| s),
+    );
+    vec![
+        exclude_pattern,
+        OsString::from(if cfg!(target_os = "macos") {
+            // Exclude the standard library symbols, otherwise kcov will take forever to run.
+            "--exclude-pattern=/Users/travis/build/rust-lang/rust/"
+        } else {
+            "--verify"
+        }),
+    ]
+}
+
+/// Gets the kcov argument for uploading to coveralls, if requested.
+pub fn get_coveralls_option(options: &CoverageOptions) -> Result<Option<OsString>, Error> {
+    if !options.coveralls {
+        Ok(None)
+    } else {
+        match var_os("TRAVIS_JOB_ID") {
+            None => Err(Error::NoCoverallsId),
+            Some(id) => {
+                let mut res = OsString::from("--coveralls-id=");
+                res.push(id);
+                Ok(Some(res))
+            }
+        }
+    }
+}
+
+#[test]
+fn test_kcov_version_parse() {
+    let parse = |s| KcovVersion::parse(s).map(|v| (v.major, v.minor, v.commits));
+    assert_eq!(parse("kcov v38\n"), Some((38, 0, 0)));
+    assert_eq!(parse("kcov 38"), Some((38, 0, 0)));
+    assert_eq!(parse("kcov 38-12-gabc1234"), Some((38, 0, 12)));
+    assert_eq!(parse("kcov v35-5-g6e0bba7-dirty"), Some((35, 0, 5)));
+    assert_eq!(parse("kcov 36+dfsg-1"), Some((36, 0, 0)));
+    assert_eq!(parse("kcov v36-1ubuntu1"), Some((36, 0, 0)));
+    assert_eq!(parse("kcov 34~bpo9+1"), Some((34, 0, 0)));
+    assert_eq!(parse("kcov v40.1"), Some((40, 1, 0)));
+    assert_eq!(parse("kcov unknown"), None);
+    assert_eq!(
+        parse("Usage: kcov [OPTIONS] out-dir in-file [args...]"),
+        None
+    );
+    assert_eq!(parse(""), None);
+
+    let v = KcovVersion::parse("kcov 38-12-gabc1234").unwrap();
+    assert_eq!(v.to_string(), "v38-12");
+    assert!(v > KcovVersion::parse("kcov v38").unwrap());
+    assert!(v < KcovVersion::parse("kcov v39").unwrap());
+    assert!(v.supports_replace_src_path());
+    assert!(!KcovVersion::parse("kcov v33")
+        .unwrap()
+        .supports_replace_src_path());
+
+    assert!(v.matches_pin("v38"));
+    assert!(v.matches_pin("38"));
+    assert!(!v.matches_pin("v38.1"));
+    assert!(!v.matches_pin("v39"));
+    assert!(!v.matches_pin("latest"));
+    let v = KcovVersion::parse("kcov v40.1").unwrap();
+    assert!(v.matches_pin("v40"));
+    assert!(v.matches_pin("v40.1"));
+    assert!(!v.matches_pin("v40.2"));
+}
+
+#[test]
+fn test_kcov_command() {
+    let vars = [("workspace", Path::new("/ws"))];
+    let runner = Runner::parse("docker run --rm -v {workspace}:/src kcov/kcov", &vars)
+        .unwrap()
+        .map("{workspace}=/src", &vars)
+        .unwrap();
+    let kcov = KcovExecutable {
+        path: PathBuf::from("/usr/bin/kcov"),
+        version: None,
+        source: KcovSource::Runner,
+        runner: Some(runner),
+    };
+    let cmd = Kcov::new(kcov, vec!["--verify".into()]).command(
+        Path::new("/ws/target/cov/kcov-test"),
+        OsStr::new("/ws/target/debug/deps/test"),
+        &["--quiet".into()],
+    );
+    // The library path is set inside the container, not on the docker command.
+    assert_eq!(
+        cmd.to_string(),
+        "\"docker\" \"run\" \"--rm\" \"-v\" \"/ws:/src\" \"kcov/kcov\" \
+         \"env\" \"LD_LIBRARY_PATH=target/debug/deps\" \"/usr/bin/kcov\" \"--verify\" \
+         \"/src/target/cov/kcov-test\" \"/src/target/debug/deps/test\" \"--quiet\""
+    );
+}
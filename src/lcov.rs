@@ -0,0 +1,441 @@
+//! Reads the line coverage of a report: the lcov tracefile written by the instrument-coverage
+//! backend, or the Cobertura XML written by kcov. The lcov data can be converted to Cobertura too.
+
+use std::collections::BTreeMap;
+use std::fs::read_to_string;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use regex::Regex;
+
+/// Name of the lcov tracefile in the output directory.
+pub const LCOV_FILE: &str = "lcov.info";
+
+/// Name of the Cobertura report in the output directory.
+pub const COBERTURA_FILE: &str = "cobertura.xml";
+
+/// Number of branches of a line, and how many of them were taken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct BranchCount {
+    pub taken: u64,
+    pub total: u64,
+}
+
+impl BranchCount {
+    /// Whether some, but not all branches of the line were taken.
+    pub fn is_partial(&self) -> bool {
+        self.taken < self.total
+    }
+}
+
+/// Coverage of one source file.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct FileCoverage {
+    pub path: PathBuf,
+    /// Hit count of each instrumented line.
+    pub lines: BTreeMap<u32, u64>,
+    /// Branches of each line containing any.
+    pub branches: BTreeMap<u32, BranchCount>,
+}
+
+impl FileCoverage {
+    /// Lines where not every branch was taken, with their branch counts.
+    pub fn partial_lines(&self) -> Vec<(u32, BranchCount)> {
+        self.branches
+            .iter()
+            .filter(|&(_, count)| count.is_partial())
+            .map(|(&line, &count)| (line, count))
+            .collect()
+    }
+}
+
+/// Reads the lcov tracefile at `path`. Records of the same file are combined.
+pub fn read_lcov(path: &Path) -> io::Result<Vec<FileCoverage>> {
+    read_to_string(path).map(|s| parse_lcov(&s))
+}
+
+/// Parses an lcov tracefile. Only the `SF`, `DA` and `BRDA` records are used.
+pub fn parse_lcov(content: &str) -> Vec<FileCoverage> {
+    // The hits of each branch, identified by the line, block and branch numbers.
+    type Branches<'a> = BTreeMap<(u32, &'a str, &'a str), u64>;
+
+    let mut files = BTreeMap::<&str, (BTreeMap<u32, u64>, Branches)>::new();
+    let mut current = None;
+    for line in content.lines() {
+        let (key, value) = match line.find(':') {
+            Some(i) => (&line[..i], &line[i + 1..]),
+            None => {
+                if line == "end_of_record" {
+                    current = None;
+                }
+                continue;
+            }
+        };
+        if key == "SF" {
+            files.entry(value).or_default();
+            current = Some(value);
+            continue;
+        }
+        let (lines, branches) = match current {
+            Some(path) => files.get_mut(path).unwrap(),
+            None => continue,
+        };
+        match (key, &*value.split(',').collect::<Vec<_>>()) {
+            ("DA", &[line, hits, ..]) => {
+                if let (Ok(line), Ok(hits)) = (line.parse(), hits.parse::<u64>()) {
+                    *lines.entry(line).or_insert(0) += hits;
+                }
+            }
+            ("BRDA", &[line, block, branch, taken]) => {
+                if let Ok(line) = line.parse() {
+                    // `-` means the branch was never reached.
+                    *branches.entry((line, block, branch)).or_insert(0) +=
+                        taken.parse().unwrap_or(0);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    files
+        .into_iter()
+        .map(|(path, (lines, raw_branches))| {
+            let mut branches = BTreeMap::<u32, BranchCount>::new();
+            for ((line, _, _), hits) in raw_branches {
+                let count = branches.entry(line).or_default();
+                count.total += 1;
+                if hits > 0 {
+                    count.taken += 1;
+                }
+            }
+            FileCoverage {
+                path: PathBuf::from(path),
+                lines,
+                branches,
+            }
+        })
+        .collect()
+}
+
+/// Removes the `DA` and `BRDA` records of the lines for which `is_excluded(file, line)` is true
+/// from an lcov tracefile. The `LF`, `LH`, `BRF` and `BRH` totals are recomputed.
+pub fn filter_lcov<F>(content: &str, is_excluded: F) -> String
+where
+    F: Fn(&Path, u32) -> bool,
+{
+    let mut output = String::with_capacity(content.len());
+    let mut current = None;
+    let mut totals = [0u64; 4];
+    for line in content.lines() {
+        let (key, value) = match line.find(':') {
+            Some(i) => (&line[..i], &line[i + 1..]),
+            None => (line, ""),
+        };
+        let fields = value.split(',').collect::<Vec<_>>();
+        match key {
+            "SF" => {
+                current = Some(Path::new(value));
+                totals = [0; 4];
+            }
+            "DA" | "BRDA" => {
+                let number = fields[0].parse().ok();
+                if let (Some(path), Some(number)) = (current, number) {
+                    if is_excluded(path, number) {
+                        continue;
+                    }
+                }
+                let hit = match fields.get(if key == "DA" { 1 } else { 3 }) {
+                    Some(&hits) => hits.parse::<u64>().is_ok_and(|hits| hits > 0),
+                    None => false,
+                };
+                let i = if key == "DA" { 0 } else { 2 };
+                totals[i] += 1;
+                if hit {
+                    totals[i + 1] += 1;
+                }
+            }
+            "LF" | "LH" | "BRF" | "BRH" => continue,
+            "end_of_record" => {
+                output.push_str(&format!("LF:{}\nLH:{}\n", totals[0], totals[1]));
+                if totals[2] > 0 {
+                    output.push_str(&format!("BRF:{}\nBRH:{}\n", totals[2], totals[3]));
+                }
+                current = None;
+            }
+            _ => {}
+        }
+        output.push_str(line);
+        output.push('\n');
+    }
+    output
+}
+
+/// Writes `files` as an lcov tracefile. Only the number of branches taken is known, so each line
+/// gets that many `BRDA` records with a hit, and the others without.
+pub fn write_lcov<W: Write>(mut w: W, files: &[FileCoverage]) -> io::Result<()> {
+    for file in files {
+        writeln!(w, "SF:{}", file.path.display())?;
+        for (line, count) in &file.branches {
+            for branch in 0..count.total {
+                let hits = if branch < count.taken { "1" } else { "0" };
+                writeln!(w, "BRDA:{},0,{},{}", line, branch, hits)?;
+            }
+        }
+        if !file.branches.is_empty() {
+            let (taken, total) = file.branches.values().fold((0, 0), |(taken, total), c| {
+                (taken + c.taken, total + c.total)
+            });
+            writeln!(w, "BRF:{}\nBRH:{}", total, taken)?;
+        }
+        for (line, hits) in &file.lines {
+            writeln!(w, "DA:{},{}", line, hits)?;
+        }
+        let covered = file.lines.values().filter(|&&hits| hits > 0).count();
+        writeln!(w, "LF:{}\nLH:{}", file.lines.len(), covered)?;
+        writeln!(w, "end_of_record")?;
+    }
+    Ok(())
+}
+
+/// Reads the merged line coverage in the output directory `cov_path`, from
+/// `kcov-merged/cobertura.xml` for the kcov backend, or `lcov.info`.
+pub fn read_line_coverage(cov_path: &Path) -> io::Result<Vec<FileCoverage>> {
+    // The kcov report comes first, since `lcov.info` is derived from it for the kcov backend.
+    match read_to_string(cov_path.join("kcov-merged").join(COBERTURA_FILE)) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => read_lcov(&cov_path.join(LCOV_FILE)),
+        result => result.map(|s| parse_cobertura(&s)),
+    }
+}
+
+/// Parses the line hits of a Cobertura report. The file names are joined to the first source
+/// directory.
+pub fn parse_cobertura(content: &str) -> Vec<FileCoverage> {
+    let re = Regex::new(
+        r#"<source>([^<]*)</source>|<class [^>]*filename="([^"]*)"|<line [^>]*number="(\d+)" [^>]*hits="(\d+)""#,
+    )
+    .unwrap();
+    let mut source = None;
+    let mut files = BTreeMap::<PathBuf, FileCoverage>::new();
+    let mut current = None;
+    for caps in re.captures_iter(content) {
+        if let Some(m) = caps.get(1) {
+            source = source.or_else(|| Some(PathBuf::from(unescape(m.as_str()))));
+        } else if let Some(m) = caps.get(2) {
+            let file_name = unescape(m.as_str());
+            let path = match source {
+                Some(ref source) => source.join(file_name),
+                None => PathBuf::from(file_name),
+            };
+            files.entry(path.clone()).or_insert_with(|| FileCoverage {
+                path: path.clone(),
+                ..FileCoverage::default()
+            });
+            current = Some(path);
+        } else if let Some(ref path) = current {
+            if let (Ok(line), Ok(hits)) = (caps[3].parse(), caps[4].parse::<u64>()) {
+                *files.get_mut(path).unwrap().lines.entry(line).or_insert(0) += hits;
+            }
+        }
+    }
+    files.into_values().collect()
+}
+
+/// Writes `files` as a Cobertura report, with the paths relative to `source_root`. Lines with
+/// branches carry the `condition-coverage` attribute, like the reports of gcovr.
+pub fn write_cobertura<W: Write>(
+    mut w: W,
+    files: &[FileCoverage],
+    source_root: &Path,
+) -> io::Result<()> {
+    let totals = |files: &[&FileCoverage]| {
+        let mut t = (0, 0, 0, 0);
+        for file in files {
+            t.0 += file.lines.values().filter(|&&hits| hits > 0).count() as u64;
+            t.1 += file.lines.len() as u64;
+            for count in file.branches.values() {
+                t.2 += count.taken;
+                t.3 += count.total;
+            }
+        }
+        t
+    };
+    let rate = |covered: u64, total: u64| {
+        if total == 0 {
+            1.0
+        } else {
+            covered as f64 / total as f64
+        }
+    };
+
+    let all = files.iter().collect::<Vec<_>>();
+    let (covered_lines, total_lines, covered_branches, total_branches) = totals(&all);
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    writeln!(w, r#"<?xml version="1.0" ?>"#)?;
+    writeln!(
+        w,
+        r#"<!DOCTYPE coverage SYSTEM "http://cobertura.sourceforge.net/xml/coverage-04.dtd">"#
+    )?;
+    writeln!(
+        w,
+        r#"<coverage line-rate="{:.4}" branch-rate="{:.4}" lines-covered="{}" lines-valid="{}" branches-covered="{}" branches-valid="{}" complexity="0" version="1.9" timestamp="{}">"#,
+        rate(covered_lines, total_lines),
+        rate(covered_branches, total_branches),
+        covered_lines,
+        total_lines,
+        covered_branches,
+        total_branches,
+        timestamp,
+    )?;
+    writeln!(w, "  <sources>")?;
+    writeln!(
+        w,
+        "    <source>{}</source>",
+        escape(&source_root.to_string_lossy())
+    )?;
+    writeln!(w, "  </sources>")?;
+    writeln!(w, "  <packages>")?;
+
+    // Each directory is a package, like the reports of kcov.
+    let mut packages = BTreeMap::<PathBuf, Vec<&FileCoverage>>::new();
+    for file in files {
+        let relative = file.path.strip_prefix(source_root).unwrap_or(&file.path);
+        let dir = relative.parent().unwrap_or_else(|| Path::new(""));
+        packages.entry(dir.to_owned()).or_default().push(file);
+    }
+    for (dir, files) in &packages {
+        let (covered_lines, total_lines, covered_branches, total_branches) = totals(files);
+        writeln!(
+            w,
+            r#"    <package name="{}" line-rate="{:.4}" branch-rate="{:.4}" complexity="0">"#,
+            escape(&dir.to_string_lossy()),
+            rate(covered_lines, total_lines),
+            rate(covered_branches, total_branches),
+        )?;
+        writeln!(w, "      <classes>")?;
+        for file in files {
+            let relative = file.path.strip_prefix(source_root).unwrap_or(&file.path);
+            let (covered_lines, total_lines, covered_branches, total_branches) = totals(&[file]);
+            writeln!(
+                w,
+                r#"        <class name="{}" filename="{}" line-rate="{:.4}" branch-rate="{:.4}" complexity="0">"#,
+                escape(&relative.file_name().unwrap_or_default().to_string_lossy()),
+                escape(&relative.to_string_lossy()),
+                rate(covered_lines, total_lines),
+                rate(covered_branches, total_branches),
+            )?;
+            writeln!(w, "          <methods/>")?;
+            writeln!(w, "          <lines>")?;
+            for (line, hits) in &file.lines {
+                match file.branches.get(line) {
+                    Some(count) => writeln!(
+                        w,
+                        r#"            <line number="{}" hits="{}" branch="true" condition-coverage="{}% ({}/{})"/>"#,
+                        line,
+                        hits,
+                        count.taken * 100 / count.total,
+                        count.taken,
+                        count.total,
+                    )?,
+                    None => writeln!(
+                        w,
+                        r#"            <line number="{}" hits="{}" branch="false"/>"#,
+                        line, hits
+                    )?,
+                }
+            }
+            writeln!(w, "          </lines>")?;
+            writeln!(w, "        </class>")?;
+        }
+        writeln!(w, "      </classes>")?;
+        writeln!(w, "    </package>")?;
+    }
+    writeln!(w, "  </packages>")?;
+    writeln!(w, "</coverage>")
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("&quot;", "\"")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&amp;", "&")
+}
+
+#[test]
+fn test_lcov() {
+    let files = parse_lcov(
+        "SF:/ws/src/lib.rs\n\
+         FN:3,_RNvCs1_3lib3foo\n\
+         DA:3,2\n\
+         DA:4,2\n\
+         DA:5,0\n\
+         BRDA:4,0,0,2\n\
+         BRDA:4,0,1,0\n\
+         BRDA:5,0,0,-\n\
+         BRDA:5,0,1,-\n\
+         BRF:4\n\
+         BRH:1\n\
+         end_of_record\n\
+         SF:/ws/src/main.rs\n\
+         DA:1,1\n\
+         end_of_record\n\
+         SF:/ws/src/lib.rs\n\
+         DA:3,1\n\
+         BRDA:4,0,1,1\n\
+         end_of_record\n",
+    );
+    assert_eq!(files.len(), 2);
+    let lib = &files[0];
+    assert_eq!(lib.path, Path::new("/ws/src/lib.rs"));
+    assert_eq!(
+        lib.lines.iter().map(|(&l, &h)| (l, h)).collect::<Vec<_>>(),
+        [(3, 3), (4, 2), (5, 0)]
+    );
+    assert_eq!(lib.branches[&4], BranchCount { taken: 2, total: 2 });
+    assert_eq!(
+        lib.partial_lines(),
+        [(5, BranchCount { taken: 0, total: 2 })]
+    );
+    assert!(files[1].branches.is_empty());
+
+    let mut xml = Vec::new();
+    write_cobertura(&mut xml, &files, Path::new("/ws")).unwrap();
+    let xml = String::from_utf8(xml).unwrap();
+    assert!(xml
+        .contains(r#"lines-covered="3" lines-valid="4" branches-covered="2" branches-valid="4""#));
+    assert!(xml.contains(
+        r#"<class name="lib.rs" filename="src/lib.rs" line-rate="0.6667" branch-rate="0.5000""#
+    ));
+    assert!(xml
+        .contains(r#"<line number="4" hits="2" branch="true" condition-coverage="100% (2/2)"/>"#));
+    assert!(xml.contains(r#"<line number="1" hits="1" branch="false"/>"#));
+
+    let parsed = parse_cobertura(&xml);
+    assert_eq!(parsed.len(), 2);
+    assert_eq!(parsed[0].path, Path::new("/ws/src/lib.rs"));
+    assert_eq!(parsed[0].lines, lib.lines);
+
+    let mut lcov = Vec::new();
+    write_lcov(&mut lcov, &files).unwrap();
+    assert_eq!(parse_lcov(&String::from_utf8(lcov).unwrap()), files);
+
+    let filtered = filter_lcov(
+        "SF:/ws/src/lib.rs\nDA:3,2\nDA:5,0\nBRDA:5,0,0,1\nBRDA:5,0,1,0\nLF:2\nLH:1\n\
+         BRF:2\nBRH:1\nend_of_record\nSF:/ws/src/main.rs\nDA:5,1\nend_of_record\n",
+        |path, line| path.ends_with("lib.rs") && line == 5,
+    );
+    assert_eq!(
+        filtered,
+        "SF:/ws/src/lib.rs\nDA:3,2\nLF:1\nLH:1\nend_of_record\n\
+         SF:/ws/src/main.rs\nDA:5,1\nLF:1\nLH:1\nend_of_record\n"
+    );
+}
@@ -0,0 +1,1140 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2017 Kenny Chan
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy of this software
+// and associated documentation files (the "Software"), to deal in the Software without
+// restriction, including without limitation the rights to use, copy, modify, merge, publish,
+// distribute, sublicense, and/or sell copies of the Software, and to permit persons to whom the
+// Software is furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all copies or
+// substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR IMPLIED, INCLUDING
+// BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY, FITNESS FOR A PARTICULAR PURPOSE AND
+// NONINFRINGEMENT. IN NO EVENT SHALL THE AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM,
+// DAMAGES OR OTHER LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
+
+//! The coverage pipeline behind `cargo kcov`, usable from build scripts such as an `xtask`.
+//!
+//! `collect_coverage` performs the whole run like the command line tool. The individual stages
+//! (`find_workspace`, `clean`, `build_tests`, `find_tests`, `Kcov::run_tests` and `Kcov::merge`)
+//! are exposed too, for callers that need to customize the pipeline. Another way of collecting
+//! coverage can be plugged in by implementing `Backend` and calling `collect_coverage_with`.
+
+extern crate gimli;
+extern crate notify;
+extern crate object;
+extern crate regex;
+extern crate rustc_demangle;
+extern crate serde_json;
+extern crate sha2;
+extern crate shlex;
+extern crate syn;
+#[cfg(test)]
+extern crate tempdir;
+extern crate term;
+extern crate tiny_http;
+extern crate toml;
+
+mod backend;
+mod cache;
+mod cargo;
+mod cargo_config;
+mod config;
+mod doctor;
+mod editor;
+mod errors;
+mod exclude;
+mod features;
+mod functions;
+mod html;
+mod install;
+mod instrument;
+mod kcov;
+mod lcov;
+mod markdown;
+mod options;
+mod output_dir;
+mod runner;
+mod serve;
+mod show;
+#[doc(hidden)]
+pub mod stderr;
+mod summary;
+pub mod target_finder;
+#[doc(hidden)]
+pub mod terminal;
+mod watch;
+
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashSet};
+use std::env::{current_dir, var_os};
+use std::ffi::{OsStr, OsString};
+use std::fs::{create_dir_all, write, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use backend::KCOV_RUSTFLAGS;
+use cache::Cache;
+use cargo::{cargo, Cmd};
+use cargo_config::find_target_runner;
+use editor::{find_file, parse_lines_json, write_line_files};
+use output_dir::{
+    find_merge_inputs_into, find_result_dirs_into, prepare_output_dir, remove_output_dir,
+};
+use stderr::{write_msg, write_warning};
+use target_finder::*;
+use watch::affected_members;
+
+pub use backend::{Backend, BackendKind, KcovBackend};
+pub use cargo::CargoOptions;
+pub use config::Config;
+pub use doctor::{diagnose, Check, Status};
+pub use editor::{file_json, line_ranges, lines_json, LINES_FILE, LINES_FORMAT_VERSION};
+pub use errors::Error;
+pub use exclude::{apply_exclusions, Exclusions};
+pub use features::FeatureSet;
+pub use functions::{
+    function_coverage, functions_json, read_functions, FunctionCoverage, FunctionSymbol,
+    FUNCTIONS_FILE,
+};
+pub use html::{write_html, HTML_REPORT_FILE};
+pub use install::{
+    install_kcov, installed_kcovs, tools_dir, InstallOptions, InstalledKcov, DEFAULT_KCOV_VERSION,
+};
+pub use instrument::{InstrumentBackend, LlvmTools, INSTRUMENT_RUSTFLAGS};
+pub use kcov::{
+    check_kcov, default_kcov_args, get_coveralls_option, kcov_args, Kcov, KcovExecutable,
+    KcovSource, KcovVersion, TestRun,
+};
+pub use lcov::{
+    parse_cobertura, parse_lcov, read_lcov, read_line_coverage, BranchCount, FileCoverage,
+    COBERTURA_FILE, LCOV_FILE,
+};
+pub use markdown::{markdown_summary, MARKDOWN_REPORT_FILE};
+pub use options::CoverageOptions;
+pub use runner::Runner;
+pub use serve::{Reloader, ReportServer};
+pub use show::{annotate, print_annotated, AnnotatedLine};
+pub use summary::Summary;
+pub use watch::SourceWatcher;
+
+/// Result of a coverage run.
+#[derive(Debug, Clone)]
+pub struct Report {
+    /// The output directory. The HTML report is `index.html` inside it.
+    pub cov_path: PathBuf,
+    /// Total line coverage, if kcov wrote it.
+    pub summary: Option<Summary>,
+    /// The executables run under kcov. Empty for merge-only runs.
+    pub test_runs: Vec<TestRun>,
+    /// Reports of the individual feature sets when using the feature matrix.
+    pub feature_sets: Vec<FeatureSetReport>,
+}
+
+/// Result of collecting coverage for one feature set.
+#[derive(Debug, Clone)]
+pub struct FeatureSetReport {
+    pub label: String,
+    pub cov_path: PathBuf,
+    pub summary: Option<Summary>,
+}
+
+/// Runs the full pipeline of `cargo kcov`: rebuilds the test executables (unless
+/// `no_clean_rebuild` is set), runs them with the backend chosen by `create_backend`, and merges
+/// the results into the output directory.
+pub fn collect_coverage(options: &CoverageOptions) -> Result<Report, Error> {
+    let workspace = find_workspace(options)?;
+    let backend = create_backend(options, &workspace)?;
+    collect_coverage_in(options, workspace, &*backend)
+}
+
+/// Like `collect_coverage`, using a custom backend.
+pub fn collect_coverage_with(
+    options: &CoverageOptions,
+    backend: &dyn Backend,
+) -> Result<Report, Error> {
+    collect_coverage_in(options, find_workspace(options)?, backend)
+}
+
+fn collect_coverage_in(
+    options: &CoverageOptions,
+    workspace: Workspace,
+    backend: &dyn Backend,
+) -> Result<Report, Error> {
+    let is_verbose = options.is_verbose();
+    let Workspace {
+        target_path,
+        root: workspace_root,
+        config,
+        members,
+    } = workspace;
+
+    let pkgid = get_pkgid(options)?;
+    let pkgid = pkgid.as_deref();
+
+    let feature_sets = get_feature_sets(options, &config);
+    let exclusions = get_exclusions(options, &config);
+
+    // Cleaned once for all feature sets, before creating the output directory inside the target
+    // directory, so the results already written are kept.
+    if !options.no_clean_rebuild {
+        if is_verbose {
+            write_msg("Clean", pkgid.unwrap_or("all"));
+        }
+        clean_members(options, pkgid, &members)?;
+    }
+    let cov_path = create_cov_path(options, &target_path, &[&workspace_root], options.append)?;
+    let rustflags = backend.rustflags();
+
+    let mut test_runs = Vec::new();
+    if feature_sets.is_empty() {
+        let tests = find_or_build_tests(options, pkgid, &target_path, None, &members, rustflags)?;
+        test_runs = backend.run_tests(&cov_path, tests)?;
+    } else {
+        for feature_set in &feature_sets {
+            if is_verbose {
+                write_msg("Features", feature_set.label());
+            }
+            let set_cov_path = cov_path.join(feature_set.dir_name());
+            if let Err(e) = create_dir_all(&set_cov_path) {
+                return Err(Error::CannotCreateCoverageDirectory(e));
+            }
+            let tests = find_or_build_tests(
+                options,
+                pkgid,
+                &target_path,
+                Some(feature_set),
+                &members,
+                rustflags,
+            )?;
+            let set_test_runs = backend.run_tests(&set_cov_path, tests)?;
+            backend.merge(&set_cov_path, &set_test_runs)?;
+            apply_exclusions(&set_cov_path, &exclusions, &workspace_root)?;
+            test_runs.extend(set_test_runs);
+        }
+    }
+
+    let mut merged_runs = test_runs.clone();
+    if options.append {
+        merged_runs.extend(
+            backend
+                .previous_runs(&cov_path)?
+                .into_iter()
+                .filter(|previous| {
+                    test_runs
+                        .iter()
+                        .all(|run| run.cov_path != previous.cov_path)
+                }),
+        );
+    }
+    backend.finish(&cov_path, &merged_runs)?;
+    apply_exclusions(&cov_path, &exclusions, &workspace_root)?;
+    write_line_files(&cov_path, &workspace_root)?;
+
+    Ok(Report {
+        summary: Summary::read(&cov_path),
+        test_runs,
+        feature_sets: feature_sets
+            .iter()
+            .map(|feature_set| {
+                let set_cov_path = cov_path.join(feature_set.dir_name());
+                FeatureSetReport {
+                    label: feature_set.label().to_owned(),
+                    summary: Summary::read(&set_cov_path),
+                    cov_path: set_cov_path,
+                }
+            })
+            .collect(),
+        cov_path,
+    })
+}
+
+/// Collects coverage again after the files `changed` were modified, for `--watch`.
+///
+/// Only the workspace members containing these files and the members depending on them are
+/// rebuilt, and only their test executables are run again. The results of the other executables
+/// are kept from `previous`, the report of `collect_coverage_with` or of an earlier update with the
+/// same `backend`. Feature sets are not supported.
+pub fn update_coverage(
+    options: &CoverageOptions,
+    workspace: &Workspace,
+    backend: &dyn Backend,
+    previous: &Report,
+    changed: &BTreeSet<PathBuf>,
+) -> Result<Report, Error> {
+    let affected = affected_members(&workspace.members, changed);
+    if affected.is_empty() {
+        return Ok(previous.clone());
+    }
+    let mut options = options.clone();
+    if options.cargo.all {
+        options.cargo.all = false;
+        options.cargo.packages = affected.iter().map(|m| m.name.clone()).collect();
+    }
+    if options.is_verbose() {
+        let names = affected.iter().map(|m| &*m.name).collect::<Vec<_>>();
+        write_msg("Build", &names.join(", "));
+    }
+
+    // The JSON messages list the executables which are up to date too, since a test executable
+    // of an affected member is not necessarily rebuilt.
+    let (output, _) = cargo_test_no_run(
+        &options,
+        &[],
+        backend.rustflags(),
+        Some(&workspace.target_path),
+    )
+    .args(&["--message-format=json-render-diagnostics"])
+    .output()?;
+    let tests = parse_test_artifacts(&output)
+        .into_iter()
+        .filter(|(id, _)| affected.iter().any(|m| m.id == *id))
+        .map(|(_, path)| path)
+        .collect::<Vec<_>>();
+
+    let cov_path = &previous.cov_path;
+    let mut test_runs = previous
+        .test_runs
+        .iter()
+        .filter(|run| {
+            tests
+                .iter()
+                .all(|test| test.file_name() != run.executable.file_name())
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    for test in &tests {
+        backend.remove_results(cov_path, test)?;
+    }
+    test_runs.extend(backend.run_tests(cov_path, tests)?);
+    backend.finish(cov_path, &test_runs)?;
+    let exclusions = get_exclusions(&options, &workspace.config);
+    apply_exclusions(cov_path, &exclusions, &workspace.root)?;
+    write_line_files(cov_path, &workspace.root)?;
+
+    Ok(Report {
+        cov_path: cov_path.clone(),
+        summary: Summary::read(cov_path),
+        test_runs,
+        feature_sets: Vec::new(),
+    })
+}
+
+/// Creates the backend selected by `--backend`. Without it, kcov is used unless `--branch` is given
+/// or the tests are run by a `target.<triple>.runner` (e.g. qemu-user for cross-compiled tests),
+/// which kcov cannot trace through. The backend chosen instead is reported.
+pub fn create_backend(
+    options: &CoverageOptions,
+    workspace: &Workspace,
+) -> Result<Box<dyn Backend>, Error> {
+    let is_verbose = options.is_verbose();
+    let target_runner = get_target_runner(options);
+    let (kind, reason) = match (options.backend, &target_runner) {
+        (Some(kind), _) => (kind, None),
+        (None, _) if options.branch => (
+            BackendKind::InstrumentCoverage,
+            Some("kcov cannot collect branch coverage"),
+        ),
+        (None, &Some(_)) => (
+            BackendKind::InstrumentCoverage,
+            Some("kcov cannot trace the tests through the target runner"),
+        ),
+        (None, &None) => (BackendKind::Kcov, None),
+    };
+    // Switching the backend changes the build flags and the tools required, so it is always reported.
+    match reason {
+        Some(reason) => write_msg("Backend", &format!("{}, since {}", kind, reason)),
+        None if is_verbose => write_msg("Backend", &kind.to_string()),
+        None => {}
+    }
+
+    match kind {
+        BackendKind::Kcov => {
+            if options.branch {
+                write_warning(
+                    "ignoring `--branch`, kcov only collects line coverage of Rust programs",
+                );
+            }
+            let kcov = find_kcov(options, workspace)?;
+            let coveralls_option = get_coveralls_option(options)?;
+            let args = kcov_args(options);
+            let exclusion_args = workspace.config.exclusions.kcov_args(&args);
+            let mut runner = Kcov::new(kcov, args)
+                .args(exclusion_args)
+                .verbose(is_verbose);
+            if options.cache {
+                runner = runner.cache(&workspace.target_path);
+            }
+            Ok(Box::new(KcovBackend::new(runner, coveralls_option)))
+        }
+        BackendKind::InstrumentCoverage => {
+            for &(is_set, option) in &[
+                (options.coveralls, "`--coveralls`"),
+                (options.cache, "`--cache`"),
+                (options.runner.is_some(), "`--runner`"),
+                (options.kcov_args.is_some(), "the kcov arguments"),
+            ] {
+                if is_set {
+                    write_warning(&format!(
+                        "ignoring {}, which is only supported by the kcov backend",
+                        option
+                    ));
+                }
+            }
+            let tools = LlvmTools::find()?;
+            let mut backend = InstrumentBackend::new(tools)
+                .branch(options.branch)
+                .source_root(&workspace.root)
+                .verbose(is_verbose);
+            if let Some(target_runner) = target_runner {
+                if is_verbose {
+                    write_msg("Runner", &target_runner.join(" "));
+                }
+                backend = backend.runner(target_runner);
+            }
+            Ok(Box::new(backend))
+        }
+    }
+}
+
+/// The exclusions configured by the project, with the test code if `exclude_tests` is set.
+fn get_exclusions(options: &CoverageOptions, config: &Config) -> Exclusions {
+    let mut exclusions = config.exclusions.clone();
+    exclusions.test_code |= options.exclude_tests;
+    exclusions
+}
+
+/// Finds the runner configured for `--target`, if any. Tests for the host are run directly.
+fn get_target_runner(options: &CoverageOptions) -> Option<Vec<String>> {
+    let target = options.cargo.target.as_ref()?;
+    find_target_runner(target, &current_dir().ok()?)
+}
+
+/// Finds the hit count of every function defined by the test executables of `report`, and writes
+/// them to `functions.json` in the output directory.
+pub fn report_functions(report: &Report) -> Result<Vec<FunctionCoverage>, Error> {
+    let mut functions = HashSet::new();
+    let mut executables = report
+        .test_runs
+        .iter()
+        .map(|run| &run.executable)
+        .collect::<Vec<_>>();
+    executables.sort();
+    executables.dedup();
+    for executable in executables {
+        functions.extend(read_functions(executable)?);
+    }
+    let files = read_line_coverage(&report.cov_path)
+        .map_err(|e| Error::CannotFindCoverageData(report.cov_path.clone(), Some(e)))?;
+    let functions = function_coverage(&functions, &files);
+
+    let json = serde_json::to_string_pretty(&functions_json(&functions))?;
+    write(report.cov_path.join(FUNCTIONS_FILE), json)
+        .map_err(Error::CannotCreateCoverageDirectory)?;
+    Ok(functions)
+}
+
+/// Reads the line coverage of the source file at `path` from the merged report in the output
+/// directory, for `cargo kcov lines`. See `find_file` for how the file is found.
+pub fn report_lines(options: &CoverageOptions, path: &Path) -> Result<FileCoverage, Error> {
+    let files = report_files(options)?;
+    match find_file(&files, path) {
+        Some(file) => Ok(file.clone()),
+        None => Err(Error::SourceFileNotInReport(
+            path.to_owned(),
+            options.output_path(&find_target_path(options)?),
+        )),
+    }
+}
+
+/// Reads the line coverage of every source file from the latest report in the output directory.
+pub fn report_files(options: &CoverageOptions) -> Result<Vec<FileCoverage>, Error> {
+    let cov_path = options.output_path(&find_target_path(options)?);
+    read_line_coverage(&cov_path).map_err(|e| Error::CannotFindCoverageData(cov_path, Some(e)))
+}
+
+/// Renders the merged line coverage of `report` as a self-contained HTML file, `report.html` in
+/// the output directory. Returns the path of the file.
+pub fn write_html_report(options: &CoverageOptions, report: &Report) -> Result<PathBuf, Error> {
+    let workspace = find_workspace(options)?;
+    let files = read_line_coverage(&report.cov_path)
+        .map_err(|e| Error::CannotFindCoverageData(report.cov_path.clone(), Some(e)))?;
+    let path = report.cov_path.join(HTML_REPORT_FILE);
+    let file = File::create(&path).map_err(Error::CannotCreateCoverageDirectory)?;
+    write_html(BufWriter::new(file), &files, &workspace.root)
+        .map_err(Error::CannotCreateCoverageDirectory)?;
+    Ok(path)
+}
+
+/// Renders the Markdown summary of `report` into `report.md` in the output directory, compared with
+/// `baseline` if given: the output directory of a previous run, or its `lines.json`. When run in
+/// GitHub Actions, the summary is also appended to the job summary in `$GITHUB_STEP_SUMMARY`.
+/// Returns the summary.
+pub fn write_markdown_report(
+    options: &CoverageOptions,
+    report: &Report,
+    baseline: Option<&Path>,
+) -> Result<String, Error> {
+    let workspace = find_workspace(options)?;
+    let files = read_line_coverage(&report.cov_path)
+        .map_err(|e| Error::CannotFindCoverageData(report.cov_path.clone(), Some(e)))?;
+    let baseline = match baseline {
+        Some(path) => Some(read_baseline(path, &workspace.root)?),
+        None => None,
+    };
+    let markdown = markdown_summary(
+        &files,
+        baseline.as_deref(),
+        &workspace.members,
+        &workspace.root,
+    );
+    write(report.cov_path.join(MARKDOWN_REPORT_FILE), &markdown)
+        .map_err(Error::CannotCreateCoverageDirectory)?;
+
+    if let Some(path) = var_os("GITHUB_STEP_SUMMARY") {
+        let appended = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| writeln!(file, "{}", markdown));
+        if let Err(e) = appended {
+            write_warning(&format!(
+                "cannot write the GitHub Actions job summary, {}",
+                e
+            ));
+        }
+    }
+    Ok(markdown)
+}
+
+/// Reads the line coverage of a previous run from its `lines.json`, resolving the paths against
+/// `source_root`.
+fn read_baseline(path: &Path, source_root: &Path) -> Result<Vec<FileCoverage>, Error> {
+    use serde_json::{from_reader, Value};
+
+    let path = if path.is_dir() {
+        path.join(LINES_FILE)
+    } else {
+        path.to_owned()
+    };
+    let error = |msg: &dyn std::fmt::Display| {
+        Error::CannotReadBaseline(format!("{}: {}", path.display(), msg))
+    };
+    let file = File::open(&path).map_err(|e| error(&e))?;
+    let json = from_reader::<_, Value>(file).map_err(|e| error(&e))?;
+    parse_lines_json(&json, source_root).ok_or_else(|| error(&"unsupported format"))
+}
+
+/// Merges the output directories of previous runs (e.g. from several CI jobs) into one report,
+/// without building or running anything. Each `(from, to)` pair in `remaps` replaces the source
+/// path prefix `from` by `to`.
+pub fn merge_coverage(
+    options: &CoverageOptions,
+    input_paths: &[PathBuf],
+    remaps: &[(String, String)],
+) -> Result<Report, Error> {
+    let is_verbose = options.is_verbose();
+    let workspace = find_workspace(options)?;
+    let kcov = find_kcov(options, &workspace)?;
+    let coveralls_option = get_coveralls_option(options)?;
+    let Workspace {
+        target_path,
+        root: workspace_root,
+        config,
+        ..
+    } = workspace;
+
+    let exclusions = get_exclusions(options, &config);
+
+    let mut merge_cov_paths = Vec::new();
+    for input_path in input_paths {
+        find_merge_inputs_into(&mut merge_cov_paths, input_path)?;
+    }
+
+    if is_verbose {
+        write_msg(
+            "Coverage",
+            &format!("found the following results: {:?}", merge_cov_paths),
+        );
+    }
+
+    let mut runner = Kcov::new(kcov, default_kcov_args()).verbose(is_verbose);
+    if !remaps.is_empty() {
+        if runner.executable().supports_replace_src_path() {
+            runner = runner.args(remaps.iter().map(|(from, to)| {
+                // kcov uses `:` as separator, `=` is preferred for consistency with rustc.
+                OsString::from(format!("--replace-src-path={}:{}", from, to))
+            }));
+        } else {
+            write_warning("ignoring `--remap-path-prefix`, which requires kcov v34 or above");
+        }
+    }
+
+    // The inputs are protected too, so merging into one of them won't delete the others.
+    let mut protected = input_paths.iter().map(|p| &**p).collect::<Vec<_>>();
+    protected.push(&workspace_root);
+    let cov_path = create_cov_path(options, &target_path, &protected, options.append)?;
+
+    runner.merge(&cov_path, coveralls_option, &merge_cov_paths)?;
+    apply_exclusions(&cov_path, &exclusions, &workspace_root)?;
+    write_line_files(&cov_path, &workspace_root)?;
+
+    Ok(Report {
+        summary: Summary::read(&cov_path),
+        cov_path,
+        test_runs: Vec::new(),
+        feature_sets: Vec::new(),
+    })
+}
+
+/// Collects coverage of an arbitrary command, e.g. an end-to-end test script, or a binary built
+/// by `build_bin`. The result is written into the subdirectory `name` (default to the program
+/// name) of the output directory, and merged with everything already there.
+pub fn exec_coverage(
+    options: &CoverageOptions,
+    program: &OsStr,
+    args: &[OsString],
+    name: Option<&OsStr>,
+) -> Result<Report, Error> {
+    let workspace = find_workspace(options)?;
+    let kcov = find_kcov(options, &workspace)?;
+    let coveralls_option = get_coveralls_option(options)?;
+    let Workspace {
+        target_path,
+        root: workspace_root,
+        config,
+        ..
+    } = workspace;
+
+    let exclusions = get_exclusions(options, &config);
+    let name = name.unwrap_or_else(|| Path::new(program).file_name().unwrap_or(program));
+    let cov_path = create_cov_path(options, &target_path, &[&workspace_root], true)?;
+    let runner = Kcov::new(kcov, default_kcov_args()).verbose(options.is_verbose());
+    let pre_cov_path = cov_path.join(name);
+    runner.run_command(&pre_cov_path, program, args)?;
+
+    let mut merge_cov_paths = Vec::new();
+    find_result_dirs_into(&mut merge_cov_paths, &cov_path)?;
+    runner.merge(&cov_path, coveralls_option, &merge_cov_paths)?;
+    apply_exclusions(&cov_path, &exclusions, &workspace_root)?;
+    write_line_files(&cov_path, &workspace_root)?;
+
+    Ok(Report {
+        summary: Summary::read(&cov_path),
+        test_runs: vec![TestRun {
+            executable: PathBuf::from(program),
+            cov_path: pre_cov_path,
+            cached: false,
+        }],
+        cov_path,
+        feature_sets: Vec::new(),
+    })
+}
+
+/// Reads the report of a previous run in the output directory. If `coveralls` is set, the results
+/// are merged again to upload them to coveralls.io.
+pub fn report_coverage(options: &CoverageOptions) -> Result<Report, Error> {
+    let workspace = find_workspace(options)?;
+    let cov_path = options.output_path(&workspace.target_path);
+    let mut merge_cov_paths = Vec::new();
+    find_merge_inputs_into(&mut merge_cov_paths, &cov_path)?;
+
+    if let Some(coveralls_option) = get_coveralls_option(options)? {
+        let kcov = find_kcov(options, &workspace)?;
+        Kcov::new(kcov, default_kcov_args())
+            .verbose(options.is_verbose())
+            .merge(&cov_path, Some(coveralls_option), &merge_cov_paths)?;
+    }
+
+    Ok(Report {
+        summary: Summary::read(&cov_path),
+        cov_path,
+        test_runs: Vec::new(),
+        feature_sets: Vec::new(),
+    })
+}
+
+/// Removes the output directory (only if it was created by cargo-kcov) and the kcov result cache.
+/// Returns the paths removed.
+pub fn clean_coverage(options: &CoverageOptions) -> Result<Vec<PathBuf>, Error> {
+    let target_path = find_target_path(options)?;
+    let cov_path = options.output_path(&target_path);
+
+    let mut removed = Vec::new();
+    if remove_output_dir(&cov_path)? {
+        removed.push(cov_path);
+    }
+    if let Some(cache_path) = Cache::new(&target_path).clear()? {
+        removed.push(cache_path.to_owned());
+    }
+    Ok(removed)
+}
+
+/// Parses the `--runner` template, with the placeholders referring to `workspace`.
+pub fn get_runner(
+    options: &CoverageOptions,
+    workspace: &Workspace,
+) -> Result<Option<Runner>, Error> {
+    let template = match options.runner {
+        Some(ref template) => template,
+        None => return Ok(None),
+    };
+    let vars = [
+        ("workspace", &*workspace.root),
+        ("target", &*workspace.target_path),
+    ];
+    let mut runner = Runner::parse(template, &vars)?;
+    for map in &options.runner_maps {
+        runner = runner.map(map, &vars)?;
+    }
+    Ok(Some(runner))
+}
+
+/// Runs `check_kcov`, and reports the executable found.
+fn find_kcov(options: &CoverageOptions, workspace: &Workspace) -> Result<KcovExecutable, Error> {
+    let runner = get_runner(options, workspace)?;
+    let kcov = check_kcov(options, &workspace.config, runner.as_ref())?;
+    if options.is_verbose() {
+        write_msg("Kcov", &kcov.to_string());
+    }
+    if let Some(issue) = kcov.version.as_ref().and_then(KcovVersion::known_issue) {
+        write_warning(issue);
+    }
+    Ok(kcov)
+}
+
+/// Obtains the list of test executables, either by building them after `clean_members`, or by
+/// searching the target directory if `no_clean_rebuild` is set.
+///
+/// With `--all`, the `members` of the workspace are built one by one for a `feature_set`, since
+/// cargo may reject feature flags in the root of a virtual manifest, and the features need not
+/// exist in every member.
+fn find_or_build_tests(
+    options: &CoverageOptions,
+    pkgid: Option<&str>,
+    target_path: &Path,
+    feature_set: Option<&FeatureSet>,
+    members: &[Member],
+    rustflags: &str,
+) -> Result<Vec<PathBuf>, Error> {
+    let is_verbose = options.is_verbose();
+    let tests = if options.no_clean_rebuild {
+        find_tests(options, pkgid, target_path)?
+    } else {
+        if is_verbose {
+            write_msg("Build", "test executables");
+        }
+        match feature_set {
+            Some(feature_set) if options.cargo.all && !feature_set.cargo_args().is_empty() => {
+                let mut tests = Vec::new();
+                for member in members {
+                    let mut options = options.clone();
+                    options.cargo.all = false;
+                    options.cargo.packages = vec![member.name.clone()];
+                    tests.extend(build_tests_with_rustflags(
+                        &options,
+                        &feature_set.package_args(&member.features),
+                        rustflags,
+                        Some(target_path),
+                    )?);
+                }
+                tests
+            }
+            _ => {
+                let feature_args = feature_set.map_or_else(Vec::new, FeatureSet::cargo_args);
+                build_tests_with_rustflags(options, &feature_args, rustflags, Some(target_path))?
+            }
+        }
+    };
+
+    if is_verbose {
+        write_msg(
+            "Coverage",
+            &format!("found the following executables: {:?}", tests),
+        );
+    }
+    Ok(tests)
+}
+
+/// Feature sets to collect coverage for separately. Empty if the feature matrix is not used.
+pub fn get_feature_sets(options: &CoverageOptions, config: &Config) -> Vec<FeatureSet> {
+    if !options.feature_sets.is_empty() {
+        options
+            .feature_sets
+            .iter()
+            .map(|spec| FeatureSet::parse(spec))
+            .collect()
+    } else if !options.feature_matrix {
+        Vec::new()
+    } else if !config.feature_sets.is_empty() {
+        config
+            .feature_sets
+            .iter()
+            .map(|spec| FeatureSet::parse(spec))
+            .collect()
+    } else {
+        FeatureSet::default_matrix()
+    }
+}
+
+/// Gets the package ID of the current package, or `None` when testing all workspace members.
+pub fn get_pkgid(options: &CoverageOptions) -> Result<Option<String>, Error> {
+    if options.cargo.all {
+        return Ok(None);
+    }
+    let (mut output, _) = cargo("pkgid").args(&options.cargo.pkgid_args()).output()?;
+    let trimmed_len = output.trim_end().len();
+    output.truncate(trimmed_len);
+    Ok(Some(output))
+}
+
+/// Directories of the current workspace, as reported by `cargo metadata`.
+#[derive(Debug)]
+pub struct Workspace {
+    pub target_path: PathBuf,
+    pub root: PathBuf,
+    pub config: Config,
+    pub members: Vec<Member>,
+}
+
+/// A package of the workspace, as reported by `cargo metadata`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Member {
+    pub id: String,
+    pub name: String,
+    /// The directory containing `Cargo.toml`.
+    pub root: PathBuf,
+    /// Names of the packages it depends on.
+    pub dependencies: Vec<String>,
+    /// Names of its features, including the optional dependencies.
+    pub features: Vec<String>,
+}
+
+impl Member {
+    /// Reads the members from the output of `cargo metadata --no-deps`.
+    pub fn from_metadata(json: &serde_json::Value) -> Vec<Member> {
+        let packages = match json["packages"].as_array() {
+            Some(packages) => packages,
+            None => return Vec::new(),
+        };
+        packages
+            .iter()
+            .filter_map(|package| {
+                Some(Member {
+                    id: package["id"].as_str()?.to_owned(),
+                    name: package["name"].as_str()?.to_owned(),
+                    root: Path::new(package["manifest_path"].as_str()?)
+                        .parent()?
+                        .to_owned(),
+                    dependencies: package["dependencies"]
+                        .as_array()
+                        .into_iter()
+                        .flatten()
+                        .filter_map(|dependency| dependency["name"].as_str())
+                        .map(|name| name.to_owned())
+                        .collect(),
+                    features: package["features"]
+                        .as_object()
+                        .into_iter()
+                        .flat_map(|features| features.keys().map(|name| &**name))
+                        .chain(
+                            package["dependencies"]
+                                .as_array()
+                                .into_iter()
+                                .flatten()
+                                .filter(|dependency| dependency["optional"] == true)
+                                .filter_map(|dependency| {
+                                    dependency["rename"]
+                                        .as_str()
+                                        .or_else(|| dependency["name"].as_str())
+                                }),
+                        )
+                        .map(|name| name.to_owned())
+                        .collect(),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Finds the member containing the file at `path`. In nested members, the file belongs to the
+/// innermost one.
+pub fn owner<'a>(members: &'a [Member], path: &Path) -> Option<&'a Member> {
+    members
+        .iter()
+        .filter(|member| path.starts_with(&member.root))
+        .max_by_key(|member| member.root.components().count())
+}
+
+pub fn find_workspace(options: &CoverageOptions) -> Result<Workspace, Error> {
+    use serde_json::{from_str, Value};
+
+    let (json, _) = cargo("metadata")
+        .args(&options.cargo.metadata_args())
+        .args(&["--no-deps", "--format-version", "1"])
+        .output()?;
+
+    let json = from_str::<Value>(&json)?;
+    match (
+        json["target_directory"].as_str(),
+        json["workspace_root"].as_str(),
+    ) {
+        (Some(target_path), Some(root)) => {
+            let root = PathBuf::from(root);
+            Ok(Workspace {
+                target_path: PathBuf::from(target_path),
+                config: Config::from_metadata(&json, &root)?,
+                members: Member::from_metadata(&json),
+                root,
+            })
+        }
+        _ => Err(Error::Json(None)),
+    }
+}
+
+/// Gets the target directory of the workspace, e.g. `/path/to/project/target`.
+pub fn find_target_path(options: &CoverageOptions) -> Result<PathBuf, Error> {
+    find_workspace(options).map(|workspace| workspace.target_path)
+}
+
+/// Prepares the output directory, default to `target/cov`. See `prepare_output_dir` for the
+/// meaning of `protected` and `append`.
+pub fn create_cov_path(
+    options: &CoverageOptions,
+    target_path: &Path,
+    protected: &[&Path],
+    append: bool,
+) -> Result<PathBuf, Error> {
+    let cov_path = options.output_path(target_path);
+
+    // Never use a directory containing the workspace, the build artifacts or the user's files.
+    let home = var_os("HOME").map(PathBuf::from);
+    let mut protected = protected.to_vec();
+    protected.push(target_path);
+    if let Some(ref home) = home {
+        protected.push(home);
+    }
+
+    prepare_output_dir(&cov_path, &protected, append)?;
+    Ok(cov_path)
+}
+
+/// Runs `cargo clean`, on the package `pkg` only if given.
+pub fn clean(options: &CoverageOptions, pkg: Option<&str>) -> Result<(), Error> {
+    cargo("clean")
+        .args(&options.cargo.clean_args(pkg))
+        .output()?;
+
+    Ok(())
+}
+
+/// Cleans the package `pkgid`, or every member of the workspace when testing all of them. The
+/// members are cleaned one by one, since a bare `cargo clean` deletes the whole target directory,
+/// including the output directory and the cache in `target/kcov-cache`.
+fn clean_members(
+    options: &CoverageOptions,
+    pkgid: Option<&str>,
+    members: &[Member],
+) -> Result<(), Error> {
+    match pkgid {
+        Some(pkgid) => clean(options, Some(pkgid)),
+        None => members
+            .iter()
+            .try_for_each(|member| clean(options, Some(&member.name))),
+    }
+}
+
+/// Builds the test executables with `cargo test --no-run`, and returns their paths.
+pub fn build_tests(
+    options: &CoverageOptions,
+    feature_set: Option<&FeatureSet>,
+) -> Result<Vec<PathBuf>, Error> {
+    let feature_args = feature_set.map_or_else(Vec::new, FeatureSet::cargo_args);
+    build_tests_with_rustflags(options, &feature_args, KCOV_RUSTFLAGS, None)
+}
+
+/// Builds the tests with `rustflags` and the `feature_args` of a feature set. If `target_path` is
+/// given, the profiles written by instrumented build scripts go into `target_path/build-profraw`
+/// instead of the package directories.
+fn build_tests_with_rustflags(
+    options: &CoverageOptions,
+    feature_args: &[String],
+    rustflags: &str,
+    target_path: Option<&Path>,
+) -> Result<Vec<PathBuf>, Error> {
+    let (output, error) = cargo_test_no_run(options, feature_args, rustflags, target_path)
+        .args(&["-v"])
+        .output()?;
+
+    let mut targets = Vec::new();
+    parse_rustc_command_lines_into(&mut targets, &error);
+    parse_rustc_command_lines_into(&mut targets, &output);
+    Ok(targets)
+}
+
+/// Prepares `cargo test --no-run`, see `build_tests_with_rustflags`.
+fn cargo_test_no_run(
+    options: &CoverageOptions,
+    feature_args: &[String],
+    rustflags: &str,
+    target_path: Option<&Path>,
+) -> Cmd {
+    let mut cmd = cargo("test")
+        .args(&["--no-run"])
+        .env("RUSTFLAGS", " ", rustflags)
+        .args(&options.cargo.test_args());
+    if let Some(target_path) = target_path {
+        cmd = cmd.set_env(
+            "LLVM_PROFILE_FILE",
+            target_path.join("build-profraw").join("%p-%m.profraw"),
+        );
+    }
+    cmd.args(feature_args)
+}
+
+/// Builds the binary `bin` for coverage, and returns its path.
+pub fn build_bin(
+    options: &CoverageOptions,
+    bin: &str,
+    target_path: &Path,
+) -> Result<PathBuf, Error> {
+    cargo("build")
+        .args(&["--bin", bin])
+        .env("RUSTFLAGS", " ", KCOV_RUSTFLAGS)
+        .args(&options.cargo.build_args())
+        .output()?;
+    Ok(get_artifact_path(&options.cargo, target_path.to_owned()).join(bin))
+}
+
+//-------------------------------------------------------------------------------------------------
+
+/// Find all test executables using `read_dir` without clean-rebuild.
+pub fn find_tests(
+    options: &CoverageOptions,
+    pkgid: Option<&str>,
+    target_path: &Path,
+) -> Result<Vec<PathBuf>, Error> {
+    let (path, file_name_filters) =
+        get_args_for_find_test_targets(options, pkgid, target_path.to_owned());
+    find_test_targets(&path, file_name_filters)
+}
+
+/// Gets the directory containing the build artifacts, e.g. `target/debug`.
+fn get_artifact_path(cargo: &CargoOptions, mut path: PathBuf) -> PathBuf {
+    if let Some(ref target) = cargo.target {
+        path.push(target);
+    }
+    path.push(if cargo.release { "release" } else { "debug" });
+    path
+}
+
+fn get_args_for_find_test_targets<'a>(
+    options: &'a CoverageOptions,
+    pkgid: Option<&'a str>,
+    path: PathBuf,
+) -> (PathBuf, HashSet<Cow<'a, str>>) {
+    let path = get_artifact_path(&options.cargo, path);
+
+    let mut file_name_filters = HashSet::new();
+
+    if let Some(pkgid) = pkgid {
+        if options.cargo.lib {
+            file_name_filters.insert(find_package_name_from_pkgid(pkgid));
+        }
+    }
+
+    for names in &[
+        &options.cargo.bins,
+        &options.cargo.examples,
+        &options.cargo.tests,
+        &options.cargo.benches,
+    ] {
+        file_name_filters.extend(names.iter().map(|name| normalize_package_name(name)));
+    }
+
+    (path, file_name_filters)
+}
+
+#[test]
+fn test_get_args_for_find_test_targets() {
+    let path = Path::new("/path/to/some/great-project/target");
+    let pkgid = "file:///path/to/some/great-project#0.1.0";
+
+    let do_test = |options: CoverageOptions, expected_path, expected_filters: &[&'static str]| {
+        let args = get_args_for_find_test_targets(&options, Some(pkgid), path.to_path_buf());
+        assert_eq!(args.0, expected_path);
+        assert_eq!(
+            args.1,
+            expected_filters.iter().map(|x| Cow::Borrowed(*x)).collect()
+        );
+    };
+
+    do_test(
+        CoverageOptions::new(),
+        Path::new("/path/to/some/great-project/target/debug"),
+        &[],
+    );
+
+    do_test(
+        CoverageOptions::new().release(true),
+        Path::new("/path/to/some/great-project/target/release"),
+        &[],
+    );
+
+    do_test(
+        CoverageOptions::new().target("i586-unknown-linux-gnu"),
+        Path::new("/path/to/some/great-project/target/i586-unknown-linux-gnu/debug"),
+        &[],
+    );
+
+    do_test(
+        CoverageOptions::new().lib(true),
+        Path::new("/path/to/some/great-project/target/debug"),
+        &["great_project"],
+    );
+
+    do_test(
+        CoverageOptions::new()
+            .lib(true)
+            .bin("a")
+            .bin("b-c-d")
+            .test("e")
+            .example("ff")
+            .bench("g"),
+        Path::new("/path/to/some/great-project/target/debug"),
+        &["great_project", "a", "b_c_d", "e", "ff", "g"],
+    );
+}
+
+#[test]
+fn test_clean_members() {
+    use std::fs::{create_dir_all, write};
+    use tempdir::TempDir;
+
+    let root = TempDir::new("test_clean_members").unwrap();
+    let manifest_path = root.path().join("Cargo.toml");
+    write(
+        &manifest_path,
+        "[package]\nname = \"app\"\nversion = \"0.1.0\"\n\n[workspace]\n",
+    )
+    .unwrap();
+    create_dir_all(root.path().join("src")).unwrap();
+    write(root.path().join("src/lib.rs"), "").unwrap();
+
+    let options = CoverageOptions::new()
+        .manifest_path(&manifest_path)
+        .all(true);
+    let workspace = find_workspace(&options).unwrap();
+    let entry = workspace.target_path.join("kcov-cache/0123");
+    create_dir_all(&entry).unwrap();
+    let tests = build_tests(&options, None).unwrap();
+    assert!(!tests.is_empty() && tests.iter().all(|test| test.is_file()));
+
+    clean_members(&options, None, &workspace.members).unwrap();
+    assert!(entry.is_dir());
+    assert!(tests.iter().all(|test| !test.exists()));
+}
@@ -19,42 +19,51 @@
 
 #[macro_use]
 extern crate clap;
+extern crate cargo_kcov;
 extern crate open;
-extern crate regex;
-extern crate serde_json;
-extern crate shlex;
-#[cfg(test)]
-extern crate tempdir;
 extern crate term;
 
-mod cargo;
-mod errors;
-mod stderr;
-mod target_finder;
-
-use std::borrow::Cow;
-use std::collections::HashSet;
-use std::env::var_os;
-use std::ffi::{OsStr, OsString};
-use std::fs::{create_dir_all, remove_dir_all};
+use std::collections::BTreeSet;
+use std::env::current_dir;
+use std::ffi::OsStr;
+use std::fs::read_to_string;
+use std::io::{self, stderr, IsTerminal};
 use std::path::{Path, PathBuf};
-use std::process::Command;
-
-use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use std::thread::spawn;
 
-use cargo::{cargo, Cmd};
-use errors::Error;
-use target_finder::*;
-use term::color::{GREEN, YELLOW};
-use term::Attr;
+use cargo_kcov::stderr::{write_msg, write_warning};
+use cargo_kcov::*;
+use clap::{App, AppSettings, Arg, ArgMatches, ErrorKind, Shell, SubCommand};
 
 fn main() {
     let matches = create_arg_parser().get_matches();
     let matches = matches
         .subcommand_matches("kcov")
         .expect("Expecting subcommand `kcov`.");
+    if matches.is_present("watch") && matches.subcommand_name().is_some() {
+        clap::Error::with_description(
+            "`--watch` must be given after the `run` or `serve` subcommand",
+            ErrorKind::ArgumentConflict,
+        )
+        .exit();
+    }
 
-    match run(matches) {
+    let result = match matches.subcommand() {
+        ("merge", Some(matches)) => run_merge(matches),
+        ("exec", Some(matches)) => run_exec(matches),
+        ("report", Some(matches)) => run_report(matches),
+        ("lines", Some(matches)) => run_lines(matches),
+        ("show", Some(matches)) => run_show(matches),
+        ("serve", Some(matches)) => run_serve(matches),
+        ("install-kcov", Some(matches)) => run_install_kcov(matches),
+        ("clean", Some(matches)) => run_clean(matches),
+        ("doctor", Some(matches)) => run_doctor(matches),
+        ("completions", Some(matches)) => run_completions(matches),
+        ("run", Some(matches)) => run(matches),
+        _ => run(matches),
+    };
+
+    match result {
         Ok(_) => {}
         Err(e) => e.print_error_and_quit(),
     }
@@ -66,47 +75,303 @@ fn create_arg_parser() -> App<'static, 'static> {
         .version(crate_version!())
         .bin_name("cargo")
         .settings(&[AppSettings::SubcommandRequiredElseHelp, AppSettings::GlobalVersion])
-        .subcommand(SubCommand::with_name("kcov")
+        .subcommand(run_args(SubCommand::with_name("kcov")
             .about("Generate coverage report via kcov")
-            .settings(&[AppSettings::UnifiedHelpMessage, AppSettings::DeriveDisplayOrder])
-            .args(&[
-                Arg::with_name("lib").long("--lib").help("Test only this package's library"),
-                filtering_arg("--bin", "Test only the specified binary"),
-                filtering_arg("--example", "Test only the specified example"),
-                filtering_arg("--test", "Test only the specified integration test target"),
-                filtering_arg("--bench", "Test only the specified benchmark target"),
-            ])
-            .args_from_usage("
-                -j, --jobs=[N]          'The number of jobs to run in parallel'
-                --release               'Build artifacts in release mode, with optimizations'
-                --features [FEATURES]   'Space-separated list of features to also build'
-                --no-default-features   'Do not build the `default` feature'
-                --target [TRIPLE]       'Build for the target triple'
-                --manifest-path [PATH]  'Path to the manifest to build tests for'
-                --no-fail-fast          'Run all tests regardless of failure'
-                --kcov [PATH]           'Path to the kcov executable'
-                -o, --output [PATH]     'Output directory, default to [target/cov]'
-                -v, --verbose           'Use verbose output'
-                --all                   'In a workspace, test all members'
-                --open                  'Open the coverage report on finish'
-                --coveralls             'Upload merged coverage data to coveralls.io from Travis CI'
-                --no-clean-rebuild      'Do not perform a clean rebuild before collecting coverage. \
-                                         This improves performance when the test case was already \
-                                         built for coverage, but may cause wrong coverage statistics \
-                                         if used incorrectly. If you use this option, make sure the \
-                                         `target/` folder is used exclusively by one rustc/cargo \
-                                         version only, and the test cases are built with \
-                                         `RUSTFLAGS=\"-C link-dead-code\" cargo test`.'
-                --print-install-kcov-sh 'Prints the sh code that installs kcov to `~/.cargo/bin`. \
-                                         Note that this will *not* install dependencies required by \
-                                         kcov.'
-                [KCOV-ARGS]...          'Further arguments passed to kcov. If empty, the default \
-                                         arguments `--verify --exclude-pattern=$CARGO_HOME` will be \
-                                         passed to kcov.'
-            ")
+            .settings(&[AppSettings::UnifiedHelpMessage, AppSettings::DeriveDisplayOrder]))
+            .arg(Arg::with_name("print-install-kcov-sh")
+                .long("--print-install-kcov-sh")
+                .hidden(true))
+            .subcommand(run_args(SubCommand::with_name("run")
+                .about("Build and run the tests, and collect their coverage (default)")
+                .settings(&[AppSettings::UnifiedHelpMessage, AppSettings::DeriveDisplayOrder])))
+            .subcommand(SubCommand::with_name("merge")
+                .about("Merge the coverage output directories of previous runs")
+                .settings(&[AppSettings::UnifiedHelpMessage, AppSettings::DeriveDisplayOrder])
+                .args_from_usage("
+                    --manifest-path [PATH]  'Path to the manifest of the workspace'
+                    --kcov [PATH]           'Path to the kcov executable'
+                    -o, --output [PATH]     'Output directory, default to [target/cov]'
+                    -v, --verbose           'Use verbose output'
+                    --open                  'Open the coverage report on finish'
+                    --html                  'Render a self-contained `report.html` from the merged \
+                                             data, opened by `--open` instead of the kcov report'
+                    --coveralls             'Upload merged coverage data to coveralls.io from \
+                                             Travis CI'
+                    --append                'Accumulate coverage into the existing output \
+                                             directory instead of deleting it'
+                ")
+                .args(&runner_args())
+                .args(&report_args())
+                .arg(Arg::with_name("remap-path-prefix")
+                    .long("--remap-path-prefix")
+                    .value_name("FROM=TO")
+                    .number_of_values(1)
+                    .multiple(true)
+                    .validator(validate_remap_path_prefix)
+                    .help("Replace the source path prefix FROM with TO, for coverage collected \
+                           from a different checkout location. The value is split at the last \
+                           `=`, so only FROM may contain one"))
+                .arg(Arg::from_usage("<DIRS>... 'Output directories of previous cargo-kcov runs'"))
+            )
+            .subcommand(SubCommand::with_name("exec")
+                .about("Collect coverage of a binary or an arbitrary command, adding the result \
+                        to the existing output directory")
+                .settings(&[
+                    AppSettings::UnifiedHelpMessage,
+                    AppSettings::DeriveDisplayOrder,
+                    AppSettings::TrailingVarArg,
+                ])
+                .args_from_usage("
+                    --bin [NAME]            'Build the specified binary for coverage, and run it \
+                                             with ARGS'
+                    -j, --jobs=[N]          'The number of jobs to run in parallel'
+                    --release               'Build artifacts in release mode, with optimizations'
+                    --features [FEATURES]   'Space-separated list of features to also build'
+                    --no-default-features   'Do not build the `default` feature'
+                    --target [TRIPLE]       'Build for the target triple'
+                    --manifest-path [PATH]  'Path to the manifest to build the binary for'
+                    --kcov [PATH]           'Path to the kcov executable'
+                    -o, --output [PATH]     'Output directory, default to [target/cov]'
+                    --name [NAME]           'Name of the result subdirectory, default to the \
+                                             program name'
+                    -v, --verbose           'Use verbose output'
+                    --open                  'Open the coverage report on finish'
+                    --html                  'Render a self-contained `report.html` from the merged \
+                                             data, opened by `--open` instead of the kcov report'
+                    --coveralls             'Upload merged coverage data to coveralls.io from \
+                                             Travis CI'
+                ")
+                .args(&runner_args())
+                .args(&report_args())
+                .arg(Arg::from_usage("[ARGS]... 'The command to run, or the arguments passed to \
+                                                 the binary if `--bin` is given'")
+                    .required_unless("bin"))
+            )
+            .subcommand(SubCommand::with_name("report")
+                .about("Print the summary of an existing coverage report, open or upload it")
+                .settings(&[AppSettings::UnifiedHelpMessage, AppSettings::DeriveDisplayOrder])
+                .args_from_usage("
+                    --manifest-path [PATH]  'Path to the manifest of the workspace'
+                    --kcov [PATH]           'Path to the kcov executable'
+                    -o, --output [PATH]     'Output directory, default to [target/cov]'
+                    -v, --verbose           'Use verbose output'
+                    --open                  'Open the coverage report'
+                    --html                  'Render a self-contained `report.html` from the merged \
+                                             data, opened by `--open` instead of the kcov report'
+                    --coveralls             'Upload merged coverage data to coveralls.io from \
+                                             Travis CI'
+                ")
+                .args(&runner_args())
+                .args(&report_args())
+            )
+            .subcommand(SubCommand::with_name("lines")
+                .about("Print the covered and uncovered line ranges of a source file, from the \
+                        latest report")
+                .settings(&[AppSettings::UnifiedHelpMessage, AppSettings::DeriveDisplayOrder])
+                .args_from_usage("
+                    --manifest-path [PATH]  'Path to the manifest of the workspace'
+                    -o, --output [PATH]     'Output directory, default to [target/cov]'
+                    --json                  'Print the hit count of every line as JSON, in the \
+                                             format of the entries of `lines.json`'
+                    <FILE>                  'The source file, relative to the current directory \
+                                             or the workspace root'
+                ")
+            )
+            .subcommand(SubCommand::with_name("show")
+                .about("Print a source file annotated with the hit count of each line, from the \
+                        latest report")
+                .settings(&[AppSettings::UnifiedHelpMessage, AppSettings::DeriveDisplayOrder])
+                .args_from_usage("
+                    --manifest-path [PATH]  'Path to the manifest of the workspace'
+                    -o, --output [PATH]     'Output directory, default to [target/cov]'
+                    --show-uncovered        'Print only the uncovered lines and a few lines around \
+                                             them, of every file with any if FILE is not given'
+                ")
+                .arg(Arg::with_name("FILE")
+                    .required_unless("show-uncovered")
+                    .help("The source file, relative to the current directory or the workspace \
+                           root"))
+            )
+            .subcommand(run_args(SubCommand::with_name("serve")
+                .about("Serve the coverage report over HTTP, and optionally collect coverage again \
+                        whenever a source file changes")
+                .settings(&[AppSettings::UnifiedHelpMessage, AppSettings::DeriveDisplayOrder]))
+                .args_from_usage("
+                    --bind [ADDR]   'Address to listen on, default to [127.0.0.1]'
+                    --port [PORT]   'Port to listen on, default to [8000]'
+                ")
+            )
+            .subcommand(SubCommand::with_name("install-kcov")
+                .about("Build kcov from source and install it to `~/.cargo/cargo-kcov`, where it \
+                        is found automatically. Note that this will *not* install dependencies \
+                        required by kcov.")
+                .settings(&[AppSettings::UnifiedHelpMessage, AppSettings::DeriveDisplayOrder])
+                .args_from_usage("
+                    --source [PATH]   'Build from a local source tarball or directory instead of \
+                                       downloading the release'
+                    --sha256 [HEX]    'Expected SHA-256 of the source tarball, required to \
+                                       download a release other than the default'
+                    -j, --jobs=[N]    'The number of jobs to build kcov with'
+                    -v, --verbose     'Use verbose output'
+                    [VERSION]         'The kcov version to install, default to the version of \
+                                       the source, or v42 when downloading'
+                ")
+            )
+            .subcommand(SubCommand::with_name("clean")
+                .about("Remove the coverage output directory and the kcov result cache")
+                .settings(&[AppSettings::UnifiedHelpMessage, AppSettings::DeriveDisplayOrder])
+                .args_from_usage("
+                    --manifest-path [PATH]  'Path to the manifest of the workspace'
+                    -o, --output [PATH]     'Output directory, default to [target/cov]'
+                    -v, --verbose           'Use verbose output'
+                ")
+            )
+            .subcommand(SubCommand::with_name("doctor")
+                .about("Check whether kcov can collect coverage in this environment")
+                .settings(&[AppSettings::UnifiedHelpMessage, AppSettings::DeriveDisplayOrder])
+                .args_from_usage("
+                    --manifest-path [PATH]  'Path to the manifest of the workspace'
+                    --kcov [PATH]           'Path to the kcov executable'
+                    -o, --output [PATH]     'Output directory, default to [target/cov]'
+                    --release               'Check the release profile'
+                ")
+                .args(&runner_args())
+                .arg(Arg::with_name("backend")
+                    .long("--backend")
+                    .value_name("NAME")
+                    .possible_values(BackendKind::NAMES)
+                    .help("Check the prerequisites of this backend instead of kcov"))
+            )
+            .subcommand(SubCommand::with_name("completions")
+                .about("Print the completion script for a shell")
+                .arg(Arg::with_name("SHELL")
+                    .required(true)
+                    .possible_values(&Shell::variants())
+                    .help("The shell to generate the script for"))
+            )
         )
 }
 
+/// Arguments of the `run` and `serve` subcommands, and of `cargo kcov` without a subcommand.
+fn run_args(app: App<'static, 'static>) -> App<'static, 'static> {
+    app.args(&[
+        Arg::with_name("lib")
+            .long("--lib")
+            .help("Test only this package's library"),
+        filtering_arg("--bin", "Test only the specified binary"),
+        filtering_arg("--example", "Test only the specified example"),
+        filtering_arg("--test", "Test only the specified integration test target"),
+        filtering_arg("--bench", "Test only the specified benchmark target"),
+    ])
+    .args_from_usage(
+        "
+            -j, --jobs=[N]          'The number of jobs to run in parallel'
+            --release               'Build artifacts in release mode, with optimizations'
+            --features [FEATURES]   'Space-separated list of features to also build'
+            --no-default-features   'Do not build the `default` feature'
+            --target [TRIPLE]       'Build for the target triple'
+            --manifest-path [PATH]  'Path to the manifest to build tests for'
+            --no-fail-fast          'Run all tests regardless of failure'
+            --kcov [PATH]           'Path to the kcov executable'
+            -o, --output [PATH]     'Output directory, default to [target/cov]'
+            -v, --verbose           'Use verbose output'
+            --all                   'In a workspace, test all members'
+            --open                  'Open the coverage report on finish'
+            --html                  'Render a self-contained `report.html` from the merged data, \
+                                     opened by `--open` instead of the kcov report'
+            --coveralls             'Upload merged coverage data to coveralls.io from Travis CI'
+            --no-clean-rebuild      'Do not perform a clean rebuild before collecting coverage. \
+                                     This improves performance when the test case was already \
+                                     built for coverage, but may cause wrong coverage statistics \
+                                     if used incorrectly. If you use this option, make sure the \
+                                     `target/` folder is used exclusively by one rustc/cargo \
+                                     version only, and the test cases are built with \
+                                     `RUSTFLAGS=\"-C link-dead-code\" cargo test` (or \
+                                     `-C instrument-coverage` for that backend).'
+            --append                'Accumulate coverage into the existing output directory \
+                                     instead of deleting it'
+            --cache                 'Reuse the kcov results of test executables which are \
+                                     unchanged since a previous run. The results are cached in \
+                                     [target/kcov-cache], which keeps the 256 most recently used.'
+            [KCOV-ARGS]...          'Further arguments passed to kcov. If empty, the default \
+                                     arguments `--verify --exclude-pattern=$CARGO_HOME` will be \
+                                     passed to kcov.'
+        ",
+    )
+    .args(&runner_args())
+    .args(&report_args())
+    .arg(
+        Arg::with_name("backend")
+            .long("--backend")
+            .value_name("NAME")
+            .possible_values(BackendKind::NAMES)
+            .help(
+                "How to collect coverage: `kcov` traces the tests, `instrument-coverage` builds \
+                 them with `-C instrument-coverage` and reports with the LLVM tools. Default to \
+                 kcov, unless the tests of `--target` are run by a `target.<triple>.runner`.",
+            ),
+    )
+    .arg(Arg::with_name("branch").long("--branch").help(
+        "Collect branch coverage too, and list the lines with untaken branches. This \
+         uses the instrument-coverage backend, which needs a nightly compiler for \
+         branches, e.g. `cargo +nightly kcov --branch`.",
+    ))
+    .arg(
+        Arg::with_name("exclude-tests")
+            .long("--exclude-tests")
+            .help(
+                "Leave the test code out of the report and summary: `#[cfg(test)]` items, \
+                 `#[test]` functions and the files in `tests/` directories",
+            ),
+    )
+    .arg(
+        Arg::with_name("watch")
+            .long("--watch")
+            .conflicts_with_all(&["feature-matrix", "feature-set", "coveralls"])
+            .help(
+                "Collect coverage again whenever a source file of the workspace changes, \
+                 rebuilding and running only the tests of the changed packages and the \
+                 packages depending on them. `serve` reloads the page too.",
+            ),
+    )
+    .arg(Arg::with_name("functions").long("--functions").help(
+        "List the functions never called, and write the hit count of every function \
+         to `functions.json` in the output directory",
+    ))
+    .arg(
+        Arg::with_name("feature-matrix")
+            .long("--feature-matrix")
+            .conflicts_with_all(&["features", "no-default-features", "no-clean-rebuild"])
+            .help(
+                "Collect coverage separately for several feature sets, and merge them. The \
+                 sets are taken from `--feature-set`, the `feature-sets` list in \
+                 `[package.metadata.kcov]`, or default to no default features, default \
+                 features and all features.",
+            ),
+    )
+    .arg(
+        Arg::with_name("feature-set")
+            .long("--feature-set")
+            .conflicts_with_all(&["features", "no-default-features", "no-clean-rebuild"])
+            .value_name("FEATURES")
+            .number_of_values(1)
+            .multiple(true)
+            .help(
+                "A comma-separated list of features to collect coverage for, implies \
+                 `--feature-matrix`. The pseudo-features `no-default-features` and \
+                 `all-features` can be used too.",
+            ),
+    )
+}
+
+fn validate_remap_path_prefix(value: String) -> Result<(), String> {
+    if value.contains('=') {
+        Ok(())
+    } else {
+        Err(format!("`{}` is not in the form FROM=TO", value))
+    }
+}
+
 fn filtering_arg<'a, 'b>(name: &'a str, help: &'b str) -> Arg<'a, 'b> {
     Arg::with_name(&name[2..])
         .long(name)
@@ -117,360 +382,660 @@ fn filtering_arg<'a, 'b>(name: &'a str, help: &'b str) -> Arg<'a, 'b> {
 }
 
 fn run(matches: &ArgMatches) -> Result<(), Error> {
-    if cfg!(any(target_os = "windows")) {
-        return Err(Error::UnsupportedOS);
-    }
-
+    // Deprecated, superseded by `cargo kcov install-kcov`. The script is still printed as before,
+    // so existing `... | sh` pipelines installing into `$CARGO_HOME/bin` keep working.
     if matches.is_present("print-install-kcov-sh") {
         println!("{}", include_str!("install_kcov.sh"));
         return Ok(());
     }
 
-    let is_verbose = matches.is_present("verbose");
-    let kcov_path = check_kcov(matches)?;
+    let options = parse_run_options(matches);
+    if matches.is_present("watch") {
+        let mut is_opened = !matches.is_present("open");
+        return watch_sources(matches, &options, |report| {
+            let html_path = write_reports(matches, &options, report)?;
+            if !is_opened {
+                open_coverage_report(
+                    &html_path.unwrap_or_else(|| report.cov_path.join("index.html")),
+                );
+                is_opened = true;
+            }
+            Ok(())
+        });
+    }
+    let report = collect(matches, &options)?;
+    finish(matches, &options, &report)
+}
 
-    let coveralls_option = get_coveralls_option(matches)?;
-    let target_path = find_target_path(matches)?;
+/// Parses the options of the `run` and `serve` subcommands.
+fn parse_run_options(matches: &ArgMatches) -> CoverageOptions {
+    let mut options = parse_options(matches)
+        .lib(matches.is_present("lib"))
+        .no_fail_fast(matches.is_present("no-fail-fast"))
+        .all(matches.is_present("all"))
+        .no_clean_rebuild(matches.is_present("no-clean-rebuild"))
+        .cache(matches.is_present("cache"))
+        .branch(matches.is_present("branch"))
+        .exclude_tests(matches.is_present("exclude-tests"))
+        .feature_matrix(matches.is_present("feature-matrix"));
+    for name in values_of(matches, "bin") {
+        options = options.bin(name);
+    }
+    for name in values_of(matches, "example") {
+        options = options.example(name);
+    }
+    for name in values_of(matches, "test") {
+        options = options.test(name);
+    }
+    for name in values_of(matches, "bench") {
+        options = options.bench(name);
+    }
+    for spec in values_of(matches, "feature-set") {
+        options = options.feature_set(spec);
+    }
+    if let Some(args) = matches.values_of_os("KCOV-ARGS") {
+        options = options.kcov_args(args);
+    }
+    options
+}
 
-    let pkgid = if matches.is_present("all") {
-        None
-    } else {
-        Some(get_pkgid(matches)?)
-    };
+/// Collects coverage, and prints the details requested.
+fn collect(matches: &ArgMatches, options: &CoverageOptions) -> Result<Report, Error> {
+    let report = collect_coverage(options)?;
+    write_details(matches, &report)?;
+    Ok(report)
+}
 
-    let pkgid: Option<&str> = pkgid.as_ref().map(|id| &**id);
+/// Prints the coverage of the feature sets, the partial lines and the uncalled functions if
+/// requested.
+fn write_details(matches: &ArgMatches, report: &Report) -> Result<(), Error> {
+    for feature_set in &report.feature_sets {
+        write_summary(Some(&feature_set.label), feature_set.summary);
+    }
+    if matches.is_present("branch") {
+        write_partial_lines(&report.cov_path);
+    }
+    if matches.is_present("functions") {
+        write_uncalled_functions(&report_functions(report)?);
+    }
+    Ok(())
+}
 
-    let tests = if matches.is_present("no-clean-rebuild") {
-        find_tests(matches, pkgid, target_path.clone())?
-    } else {
-        if is_verbose {
-            write_msg("Clean", pkgid.unwrap_or("all"));
-        }
-        clean(matches, pkgid)?;
+/// Collects coverage, and again whenever a source file of the workspace changes, until
+/// interrupted. After each run, the details are printed and `on_report` is called. Failures are
+/// printed, and the run is tried again on the next change.
+fn watch_sources<F>(
+    matches: &ArgMatches,
+    options: &CoverageOptions,
+    mut on_report: F,
+) -> Result<(), Error>
+where
+    F: FnMut(&Report) -> Result<(), Error>,
+{
+    let workspace = find_workspace(options)?;
+    let backend = create_backend(options, &workspace)?;
+    let dirs = workspace
+        .members
+        .iter()
+        .map(|member| member.root.clone())
+        .collect::<Vec<_>>();
+    let ignored = vec![
+        workspace.target_path.clone(),
+        options.output_path(&workspace.target_path),
+    ];
+    let watcher = SourceWatcher::new(&dirs, ignored)?;
 
-        if is_verbose {
-            write_msg("Build", "test executables");
+    let mut report = None;
+    let mut changed = BTreeSet::new();
+    loop {
+        let result = match report {
+            Some(ref previous) => {
+                update_coverage(options, &workspace, &*backend, previous, &changed)
+            }
+            None => collect_coverage_with(options, &*backend),
+        };
+        let result = result.and_then(|report| {
+            write_details(matches, &report)?;
+            on_report(&report)?;
+            Ok(report)
+        });
+        match result {
+            Ok(new_report) => {
+                report = Some(new_report);
+                changed.clear();
+            }
+            // A failing test should not stop watching, the next change may fix it. The changes
+            // are kept, so the affected tests run again.
+            Err(e) => e.print_error(),
         }
-        build_test(matches)?
-    };
+        write_msg("Watching", "for changes, press Ctrl-C to stop");
 
-    if is_verbose {
-        write_msg(
-            "Coverage",
-            &format!("found the following executables: {:?}", tests),
-        );
+        changed.extend(watcher.wait()?);
+        clear_terminal();
+        let paths = changed
+            .iter()
+            .map(|path| {
+                path.strip_prefix(&workspace.root)
+                    .unwrap_or(path)
+                    .display()
+                    .to_string()
+            })
+            .collect::<Vec<_>>();
+        write_msg("Changed", &paths.join(", "));
     }
+}
 
-    let cov_path = create_cov_path(matches, target_path)?;
-    let kcov_args = match matches.values_of_os("KCOV-ARGS") {
-        Some(a) => a.map(|s| s.to_owned()).collect(),
-        None => {
-            let mut exclude_pattern = OsString::from("--exclude-pattern=");
-            exclude_pattern.push(
-                var_os("CARGO_HOME")
-                    .as_ref()
-                    .map_or(OsStr::new("/.cargo"), |s| s),
-            );
-            vec![
-                exclude_pattern,
-                OsString::from(if cfg!(target_os = "macos") {
-                    // Exclude the standard library symbols, otherwise kcov will take forever to run.
-                    "--exclude-pattern=/Users/travis/build/rust-lang/rust/"
-                } else {
-                    "--verify"
-                }),
-            ]
+/// Clears the terminal, so the summary of each run in watch mode replaces the previous one.
+fn clear_terminal() {
+    if stderr().is_terminal() {
+        eprint!("\x1b[2J\x1b[H");
+    }
+}
+
+/// Runs `cargo kcov merge`, which merges the output directories of previous runs (e.g. from several
+/// CI jobs) into one report, without building or running anything.
+fn run_merge(matches: &ArgMatches) -> Result<(), Error> {
+    let options = parse_options(matches);
+    let input_paths = matches
+        .values_of_os("DIRS")
+        .unwrap()
+        .map(PathBuf::from)
+        .collect::<Vec<_>>();
+    let remaps = values_of(matches, "remap-path-prefix")
+        .map(|remap| {
+            // Split at the last `=` like rustc does, so only FROM may contain one.
+            let i = remap.rfind('=').unwrap();
+            (remap[..i].to_owned(), remap[i + 1..].to_owned())
+        })
+        .collect::<Vec<_>>();
+
+    let report = merge_coverage(&options, &input_paths, &remaps)?;
+    finish(matches, &options, &report)
+}
+
+/// Runs `cargo kcov exec`, which collects coverage of a binary or command outside of the test
+/// executables, e.g. an end-to-end test script. The result is added to the output directory and
+/// merged with everything already there.
+fn run_exec(matches: &ArgMatches) -> Result<(), Error> {
+    let options = parse_options(matches);
+    let mut args = matches
+        .values_of_os("ARGS")
+        .map_or_else(Vec::new, |a| a.map(|s| s.to_owned()).collect());
+    let program = match matches.value_of("bin") {
+        Some(bin) => {
+            if options.is_verbose() {
+                write_msg("Build", bin);
+            }
+            let target_path = find_target_path(&options)?;
+            build_bin(&options, bin, &target_path)?.into_os_string()
         }
+        None => args.remove(0),
     };
 
-    let mut merge_cov_paths = Vec::with_capacity(tests.len());
-    for test in tests {
-        let mut pre_cov_path = cov_path.clone();
-        pre_cov_path.push(test.file_name().unwrap());
-        let cmd = Cmd::new(&kcov_path, "")
-            .env("LD_LIBRARY_PATH", ":", "target/debug/deps")
-            .args(&kcov_args)
-            .args(&[&pre_cov_path, &test]);
-        if is_verbose {
-            write_msg("Running", &cmd.to_string());
-        }
-        cmd.run_kcov()?;
-        merge_cov_paths.push(pre_cov_path);
-    }
+    let report = exec_coverage(&options, &program, &args, matches.value_of_os("name"))?;
+    finish(matches, &options, &report)
+}
 
-    let mut merge_cmd = Cmd::new(&kcov_path, "--merge")
-        .args(&kcov_args)
-        .args(&[&cov_path]);
-    if let Some(opt) = coveralls_option {
-        merge_cmd = merge_cmd.args(&[opt]);
+/// Runs `cargo kcov report`, which shows, opens or uploads the report of a previous run without
+/// collecting anything.
+fn run_report(matches: &ArgMatches) -> Result<(), Error> {
+    let options = parse_options(matches);
+    let report = report_coverage(&options)?;
+    if !is_summary_requested(matches, &options, &report) {
+        write_summary(None, report.summary);
     }
-    merge_cmd = merge_cmd.args(&merge_cov_paths);
-    if is_verbose {
-        write_msg("Running", &merge_cmd.to_string());
-    }
-    merge_cmd.run_kcov()?;
+    finish(matches, &options, &report)
+}
 
-    if matches.is_present("open") {
-        open_coverage_report(&cov_path);
+/// Runs `cargo kcov lines`, which prints the line coverage of a source file for editors and
+/// scripts, e.g. `covered: 3-4, 7` and `uncovered: 5` on separate lines.
+fn run_lines(matches: &ArgMatches) -> Result<(), Error> {
+    let options = parse_options(matches);
+    let file = report_lines(&options, Path::new(matches.value_of_os("FILE").unwrap()))?;
+    if matches.is_present("json") {
+        println!("{}", file_json(&file));
+        return Ok(());
+    }
+    for &(label, is_covered) in &[("covered", true), ("uncovered", false)] {
+        let ranges = line_ranges(&file, is_covered)
+            .iter()
+            .map(|&(start, end)| {
+                if start == end {
+                    start.to_string()
+                } else {
+                    format!("{}-{}", start, end)
+                }
+            })
+            .collect::<Vec<_>>();
+        println!("{}: {}", label, ranges.join(", "));
     }
-
     Ok(())
 }
 
-fn write_msg(title: &str, msg: &str) {
-    let mut t = stderr::new();
-    t.fg(GREEN).unwrap();
-    t.attr(Attr::Bold).unwrap();
-    write!(t, "{:>12}", title).unwrap();
-    t.reset().unwrap();
-    writeln!(t, " {}", msg).unwrap();
-}
-
-fn check_kcov<'a>(matches: &'a ArgMatches<'a>) -> Result<&'a OsStr, Error> {
-    let program = matches
-        .value_of_os("kcov")
-        .unwrap_or_else(|| OsStr::new("kcov"));
-    let output = match Command::new(program).arg("--version").output() {
-        Ok(o) => o,
-        Err(e) => return Err(Error::KcovNotInstalled(e)),
+/// Runs `cargo kcov show`, which prints source files with the hit count of each line.
+fn run_show(matches: &ArgMatches) -> Result<(), Error> {
+    let options = parse_options(matches);
+    let is_uncovered_only = matches.is_present("show-uncovered");
+    let files = match matches.value_of_os("FILE") {
+        Some(path) => vec![report_lines(&options, Path::new(path))?],
+        None => report_files(&options)?
+            .into_iter()
+            .filter(|file| file.lines.values().any(|&hits| hits == 0))
+            .collect(),
     };
-    if output.stdout.starts_with(b"kcov ") {
-        Ok(program)
-    } else {
-        Err(Error::KcovTooOld)
+    let dir = current_dir().unwrap_or_default();
+    let mut t = terminal::stdout();
+    for file in &files {
+        let source = read_to_string(&file.path)
+            .map_err(|e| Error::CannotReadSourceFile(format!("{}: {}", file.path.display(), e)))?;
+        let title = file.path.strip_prefix(&dir).unwrap_or(&file.path);
+        let lines = annotate(&source, file, is_uncovered_only);
+        match print_annotated(&mut *t, &title.to_string_lossy(), file, &lines) {
+            Ok(()) => {}
+            // E.g. piped into `head`, which has seen enough.
+            Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => return Ok(()),
+            Err(e) => return Err(Error::CannotWriteOutput(e)),
+        }
     }
+    Ok(())
 }
 
-fn get_pkgid(matches: &ArgMatches) -> Result<String, Error> {
-    let (mut output, _) = cargo("pkgid")
-        .forward(matches, &["--manifest-path"])
-        .output()?;
-    let trimmed_len = output.trim_end().len();
-    output.truncate(trimmed_len);
-    Ok(output)
-}
+/// Runs `cargo kcov serve`, which serves the report over HTTP. With `--watch`, coverage is
+/// collected first, and again whenever a source file changes, reloading the page.
+fn run_serve(matches: &ArgMatches) -> Result<(), Error> {
+    let options = parse_run_options(matches);
+    let cov_path = options.output_path(&find_target_path(&options)?);
+    let is_watch = matches.is_present("watch");
 
-fn get_coveralls_option(matches: &ArgMatches) -> Result<Option<OsString>, Error> {
-    if !matches.is_present("coveralls") {
-        Ok(None)
+    let address = format!(
+        "{}:{}",
+        matches.value_of("bind").unwrap_or("127.0.0.1"),
+        matches.value_of("port").unwrap_or("8000")
+    );
+    let index = if matches.is_present("html") {
+        HTML_REPORT_FILE
     } else {
-        match var_os("TRAVIS_JOB_ID") {
-            None => Err(Error::NoCoverallsId),
-            Some(id) => {
-                let mut res = OsString::from("--coveralls-id=");
-                res.push(id);
-                Ok(Some(res))
-            }
+        "index.html"
+    };
+    let server = ReportServer::bind(&address, cov_path)?
+        .index(index)
+        .live_reload(is_watch);
+    let url = server.url();
+
+    if !is_watch {
+        write_reports(matches, &options, &report_coverage(&options)?)?;
+        write_msg("Serving", &url);
+        if matches.is_present("open") {
+            open_url(&url);
         }
+        server.run();
+        return Ok(());
     }
-}
 
-fn find_target_path(matches: &ArgMatches) -> Result<PathBuf, Error> {
-    use serde_json::{from_str, Value};
-
-    let (json, _) = cargo("metadata")
-        .forward(matches, &["--manifest-path"])
-        .args(&["--no-deps", "--format-version", "1"])
-        .output()?;
+    // The page is opened once there is a report, since error pages are not reloaded.
+    let reloader = server.reloader();
+    spawn(move || server.run());
+    let mut is_opened = !matches.is_present("open");
+    watch_sources(matches, &options, |report| {
+        write_reports(matches, &options, report)?;
+        reloader.reload();
+        write_msg("Serving", &url);
+        if !is_opened {
+            open_url(&url);
+            is_opened = true;
+        }
+        Ok(())
+    })
+}
 
-    let json = from_str::<Value>(&json)?;
-    match json["target_directory"].as_str() {
-        None => Err(Error::Json(None)),
-        Some(p) => Ok(PathBuf::from(p)),
+/// Runs `cargo kcov install-kcov`, which builds kcov from source into the managed directory.
+fn run_install_kcov(matches: &ArgMatches) -> Result<(), Error> {
+    let mut options = InstallOptions::new().verbose(matches.is_present("verbose"));
+    if let Some(version) = matches.value_of("VERSION") {
+        options = options.version(version);
+    }
+    if let Some(source) = matches.value_of_os("source") {
+        options = options.source(source);
+    }
+    if let Some(sha256) = matches.value_of("sha256") {
+        options = options.sha256(sha256);
     }
+    if matches.is_present("jobs") {
+        options = options.jobs(value_t!(matches, "jobs", u32).unwrap_or_else(|e| e.exit()));
+    }
+    install_kcov(&options).map(|_| ())
 }
 
-fn create_cov_path(matches: &ArgMatches, mut target_path: PathBuf) -> Result<PathBuf, Error> {
-    let cov_path = match matches.value_of_os("output") {
-        Some(p) => PathBuf::from(p),
-        None => {
-            target_path.push("cov");
-            target_path
+/// Runs `cargo kcov clean`, which removes everything written by cargo-kcov.
+fn run_clean(matches: &ArgMatches) -> Result<(), Error> {
+    let options = parse_options(matches);
+    for path in clean_coverage(&options)? {
+        if options.is_verbose() {
+            write_msg("Removed", &path.to_string_lossy());
         }
-    };
+    }
+    Ok(())
+}
 
-    let _ = remove_dir_all(&cov_path);
-    match create_dir_all(&cov_path) {
-        Ok(_) => Ok(cov_path),
-        Err(e) => Err(Error::CannotCreateCoverageDirectory(e)),
+/// Runs `cargo kcov doctor`, which checks the prerequisites of collecting coverage.
+fn run_doctor(matches: &ArgMatches) -> Result<(), Error> {
+    let checks = diagnose(&parse_options(matches));
+    for check in &checks {
+        check.print();
+    }
+    match checks.iter().filter(|c| c.status == Status::Error).count() {
+        0 => Ok(()),
+        n => Err(Error::DoctorFoundProblems(n)),
     }
 }
 
-fn clean(matches: &ArgMatches, pkg: Option<&str>) -> Result<(), Error> {
-    let mut cmd = cargo("clean");
+/// Runs `cargo kcov completions`, which prints the completion script of `cargo-kcov`.
+fn run_completions(matches: &ArgMatches) -> Result<(), Error> {
+    let shell = value_t!(matches, "SHELL", Shell).unwrap_or_else(|e| e.exit());
+    print!("{}", completion_script(shell));
+    Ok(())
+}
 
-    if let Some(pkg) = pkg {
-        cmd = cmd.args(&["--package", pkg]);
+/// Generates the completion script of the `cargo-kcov` executable. A script for `cargo` would
+/// replace the completion of every other cargo subcommand.
+fn completion_script(shell: Shell) -> String {
+    let mut script = Vec::new();
+    create_arg_parser()
+        .bin_name("cargo-kcov")
+        .gen_completions_to("cargo-kcov", shell, &mut script);
+    let script = String::from_utf8(script).unwrap();
+    match shell {
+        // clap names the subcommands in the bash script `cargo__kcov__…`, but starts from the
+        // unmangled `cargo-kcov`, so they would never match.
+        Shell::Bash => script
+            .replace("cmd=\"cargo-kcov\"", "cmd=\"cargo__kcov\"")
+            .replace("\n        cargo-kcov)\n", "\n        cargo__kcov)\n"),
+        _ => script,
     }
+}
 
-    cmd.forward(matches, &["--manifest-path", "--target", "--release"])
-        .output()?;
+/// Converts the options shared by all subcommands.
+fn parse_options(matches: &ArgMatches) -> CoverageOptions {
+    let mut options = CoverageOptions::new()
+        .release(matches.is_present("release"))
+        .no_default_features(matches.is_present("no-default-features"))
+        .verbose(matches.is_present("verbose"))
+        .coveralls(matches.is_present("coveralls"))
+        .append(matches.is_present("append"));
+    if matches.is_present("jobs") {
+        options = options.jobs(value_t!(matches, "jobs", u32).unwrap_or_else(|e| e.exit()));
+    }
+    if let Some(features) = matches.value_of("features") {
+        options = options.features(features);
+    }
+    if let Some(target) = matches.value_of("target") {
+        options = options.target(target);
+    }
+    if let Some(path) = matches.value_of_os("manifest-path") {
+        options = options.manifest_path(path);
+    }
+    if let Some(path) = matches.value_of_os("kcov") {
+        options = options.kcov_path(path);
+    }
+    if let Some(path) = matches.value_of_os("output") {
+        options = options.output(path);
+    }
+    if let Some(runner) = matches.value_of("runner") {
+        options = options.runner(runner);
+    }
+    for map in values_of(matches, "runner-map") {
+        options = options.runner_map(map);
+    }
+    if let Some(backend) = matches.value_of("backend") {
+        options = options.backend(BackendKind::parse(backend).unwrap());
+    }
+    options
+}
 
-    Ok(())
+/// Arguments running kcov through a wrapper command, shared by the subcommands running kcov.
+fn runner_args() -> [Arg<'static, 'static>; 2] {
+    [
+        Arg::with_name("runner")
+            .long("--runner")
+            .value_name("COMMAND")
+            .help(
+                "Run kcov through this command, e.g. `docker run --cap-add SYS_PTRACE -v \
+                 {workspace}:{workspace} IMAGE`. `{workspace}` and `{target}` are replaced by \
+                 the workspace root and the target directory.",
+            ),
+        Arg::with_name("runner-map")
+            .long("--runner-map")
+            .value_name("HOST=CONTAINER")
+            .number_of_values(1)
+            .multiple(true)
+            .requires("runner")
+            .help(
+                "Translate the paths passed to kcov under HOST to CONTAINER, if the runner \
+                 sees the host directories at another location",
+            ),
+    ]
 }
 
-fn build_test(matches: &ArgMatches) -> Result<Vec<PathBuf>, Error> {
-    let (output, error) = cargo("test")
-        .args(&["--no-run", "-v"])
-        .env("RUSTFLAGS", " ", "-C link-dead-code")
-        .forward(
-            matches,
-            &[
-                "--lib",
-                "--bin",
-                "--example",
-                "--test",
-                "--bench",
-                "--jobs",
-                "--release",
-                "--target",
-                "--manifest-path",
-                "--features",
-                "--no-default-features",
-                "--no-fail-fast",
-                "--all",
-            ],
-        )
-        .output()?;
+fn report_args() -> [Arg<'static, 'static>; 2] {
+    [
+        Arg::with_name("output-format")
+            .long("--output-format")
+            .value_name("FORMAT")
+            .possible_values(&["text", "markdown"])
+            .help(
+                "Format of the summary: `markdown` also prints a Markdown summary for pull \
+                 request comments to stdout and `report.md`, and appends it to the GitHub \
+                 Actions job summary if `$GITHUB_STEP_SUMMARY` is set. Default to text.",
+            ),
+        Arg::with_name("baseline")
+            .long("--baseline")
+            .value_name("PATH")
+            .requires("output-format")
+            .help(
+                "Compare the Markdown summary with a previous report, given by its output \
+                 directory or its `lines.json`",
+            ),
+    ]
+}
 
-    let mut targets = Vec::new();
-    parse_rustc_command_lines_into(&mut targets, &error);
-    parse_rustc_command_lines_into(&mut targets, &output);
-    Ok(targets)
+fn values_of<'a>(matches: &'a ArgMatches, name: &str) -> impl Iterator<Item = &'a str> {
+    matches.values_of(name).into_iter().flatten()
 }
 
-fn open_coverage_report(output_path: &Path) {
-    let index_path = output_path.join("index.html");
-    write_msg("Opening", &index_path.to_string_lossy());
-    if let Err(e) = open::that(index_path) {
-        let mut t = stderr::new();
-        t.fg(YELLOW).unwrap();
-        t.attr(Attr::Bold).unwrap();
-        write!(t, "warning").unwrap();
-        t.reset().unwrap();
-        writeln!(t, ": cannot open coverage report, {}", e).unwrap();
+/// Prints the total coverage, renders the HTML report and opens a report if requested.
+fn finish(matches: &ArgMatches, options: &CoverageOptions, report: &Report) -> Result<(), Error> {
+    let html_path = write_reports(matches, options, report)?;
+    if matches.is_present("open") {
+        open_coverage_report(&html_path.unwrap_or_else(|| report.cov_path.join("index.html")));
     }
+    Ok(())
 }
 
-//-------------------------------------------------------------------------------------------------
-
-/// Find all test executables using `read_dir` without clean-rebuild.
-fn find_tests(
+/// Prints the total coverage if requested, and renders the HTML report if requested, returning its
+/// path.
+fn write_reports(
     matches: &ArgMatches,
-    pkgid: Option<&str>,
-    path: PathBuf,
-) -> Result<Vec<PathBuf>, Error> {
-    let (path, file_name_filters) = get_args_for_find_test_targets(matches, pkgid, path);
-    find_test_targets(&path, file_name_filters)
-}
-
-fn get_args_for_find_test_targets<'a>(
-    matches: &'a ArgMatches,
-    pkgid: Option<&'a str>,
-    mut path: PathBuf,
-) -> (PathBuf, HashSet<Cow<'a, str>>) {
-    if let Some(target) = matches.value_of_os("target") {
-        path.push(target);
-    }
-    path.push(if matches.is_present("release") {
-        "release"
+    options: &CoverageOptions,
+    report: &Report,
+) -> Result<Option<PathBuf>, Error> {
+    if is_summary_requested(matches, options, report) {
+        write_summary(None, report.summary);
+    }
+    if matches.value_of("output-format") == Some("markdown") {
+        let baseline = matches.value_of_os("baseline").map(Path::new);
+        print!("{}", write_markdown_report(options, report, baseline)?);
+    }
+    if matches.is_present("html") {
+        write_html_report(options, report).map(Some)
     } else {
-        "debug"
-    });
+        Ok(None)
+    }
+}
 
-    let mut file_name_filters = HashSet::new();
+/// Whether the total coverage is printed after collecting it, i.e. in verbose mode, next to the
+/// coverage of the feature sets or branches, or in watch mode.
+fn is_summary_requested(matches: &ArgMatches, options: &CoverageOptions, report: &Report) -> bool {
+    options.is_verbose()
+        || !report.feature_sets.is_empty()
+        || matches.is_present("branch")
+        || matches.is_present("watch")
+}
 
-    if let Some(pkgid) = pkgid {
-        if matches.is_present("lib") {
-            file_name_filters.insert(find_package_name_from_pkgid(pkgid));
+/// Prints the total line coverage of a report.
+fn write_summary(label: Option<&str>, summary: Option<Summary>) {
+    if let Some(summary) = summary {
+        let mut msg = format!(
+            "{:.2}% ({}/{} lines)",
+            summary.percent(),
+            summary.covered_lines,
+            summary.total_lines
+        );
+        if summary.total_branches > 0 {
+            msg.push_str(&format!(
+                ", {:.2}% ({}/{} branches)",
+                summary.branch_percent(),
+                summary.covered_branches,
+                summary.total_branches
+            ));
+        }
+        if let Some(label) = label {
+            msg.push_str(" with features: ");
+            msg.push_str(label);
         }
+        write_msg("Coverage", &msg);
+    }
+}
+
+/// Prints the lines of each file where not all branches were taken.
+fn write_partial_lines(cov_path: &Path) {
+    let files = match read_lcov(&cov_path.join(LCOV_FILE)) {
+        Ok(files) => files,
+        Err(_) => return,
+    };
+    let current_dir = current_dir().unwrap_or_default();
+    for file in &files {
+        let partial_lines = file.partial_lines();
+        if partial_lines.is_empty() {
+            continue;
+        }
+        let lines = partial_lines
+            .iter()
+            .map(|(line, count)| format!("{} ({}/{})", line, count.taken, count.total))
+            .collect::<Vec<_>>();
+        let path = file.path.strip_prefix(&current_dir).unwrap_or(&file.path);
+        write_msg(
+            "Partial",
+            &format!("{}: {}", path.display(), lines.join(", ")),
+        );
     }
+}
 
-    extend_file_name_filters(&mut file_name_filters, matches, "bin");
-    extend_file_name_filters(&mut file_name_filters, matches, "example");
-    extend_file_name_filters(&mut file_name_filters, matches, "test");
-    extend_file_name_filters(&mut file_name_filters, matches, "bench");
+/// Prints the functions never called, and how many were.
+fn write_uncalled_functions(functions: &[FunctionCoverage]) {
+    let current_dir = current_dir().unwrap_or_default();
+    for function in functions.iter().filter(|f| f.hits == 0) {
+        let symbol = &function.symbol;
+        let path = symbol
+            .file
+            .strip_prefix(&current_dir)
+            .unwrap_or(&symbol.file);
+        write_msg(
+            "Uncalled",
+            &format!("{} ({}:{})", symbol.name, path.display(), symbol.line),
+        );
+    }
+    let called = functions.iter().filter(|f| f.hits > 0).count();
+    write_msg(
+        "Functions",
+        &format!("{}/{} called", called, functions.len()),
+    );
+}
 
-    (path, file_name_filters)
+fn open_coverage_report(path: &Path) {
+    write_msg("Opening", &path.to_string_lossy());
+    open_url(path);
 }
 
-fn extend_file_name_filters<'a>(
-    filters: &mut HashSet<Cow<'a, str>>,
-    matches: &'a ArgMatches<'a>,
-    key: &str,
-) {
-    if let Some(values) = matches.values_of(key) {
-        filters.extend(values.map(normalize_package_name));
+fn open_url<P: AsRef<OsStr>>(url: P) {
+    if let Err(e) = open::that(url) {
+        write_warning(&format!("cannot open coverage report, {}", e));
     }
 }
 
 #[test]
-fn test_get_args_for_find_test_targets() {
-    use std::path::Path;
-
-    let path = Path::new("/path/to/some/great-project/target");
-    let pkgid = "file:///path/to/some/great-project#0.1.0";
+fn test_create_arg_parser() {
     let mut app = create_arg_parser();
-
-    let mut do_test = |args: &[&'static str], expected_path, expected_filters: &[&'static str]| {
+    let mut subcommand = |args: &[&str]| {
         let matches = app.get_matches_from_safe_borrow(args).unwrap();
         let matches = matches.subcommand_matches("kcov").unwrap();
-        let args = get_args_for_find_test_targets(&matches, Some(pkgid), path.to_path_buf());
-        assert_eq!(args.0, expected_path);
-        assert_eq!(
-            args.1,
-            expected_filters.iter().map(|x| Cow::Borrowed(*x)).collect()
-        );
+        matches.subcommand_name().unwrap_or("").to_owned()
     };
 
-    do_test(
-        &["cargo", "kcov", "--no-clean-rebuild"],
-        Path::new("/path/to/some/great-project/target/debug"),
-        &[],
-    );
-
-    do_test(
-        &["cargo", "kcov", "--no-clean-rebuild", "--release"],
-        Path::new("/path/to/some/great-project/target/release"),
-        &[],
-    );
-
-    do_test(
-        &[
+    assert_eq!(subcommand(&["cargo", "kcov", "--lib"]), "");
+    assert_eq!(subcommand(&["cargo", "kcov", "run", "--lib"]), "run");
+    assert_eq!(subcommand(&["cargo", "kcov", "report", "--open"]), "report");
+    assert_eq!(
+        subcommand(&[
             "cargo",
             "kcov",
-            "--no-clean-rebuild",
-            "--target",
-            "i586-unknown-linux-gnu",
-        ],
-        Path::new("/path/to/some/great-project/target/i586-unknown-linux-gnu/debug"),
-        &[],
+            "merge",
+            "--output-format",
+            "markdown",
+            "--baseline",
+            "old",
+            "a",
+        ]),
+        "merge"
     );
-
-    do_test(
-        &["cargo", "kcov", "--no-clean-rebuild", "--lib"],
-        Path::new("/path/to/some/great-project/target/debug"),
-        &["great_project"],
+    assert_eq!(
+        subcommand(&["cargo", "kcov", "install-kcov"]),
+        "install-kcov"
     );
-
-    do_test(
-        &[
-            "cargo",
-            "kcov",
-            "--no-clean-rebuild",
-            "--lib",
-            "--bin",
-            "a",
-            "--bin",
-            "b-c-d",
-            "--test",
-            "e",
-            "--example",
-            "ff",
-            "--bench",
-            "g",
-        ],
-        Path::new("/path/to/some/great-project/target/debug"),
-        &["great_project", "a", "b_c_d", "e", "ff", "g"],
+    assert_eq!(subcommand(&["cargo", "kcov", "clean"]), "clean");
+    assert_eq!(
+        subcommand(&["cargo", "kcov", "lines", "--json", "src/lib.rs"]),
+        "lines"
+    );
+    assert_eq!(subcommand(&["cargo", "kcov", "show", "src/lib.rs"]), "show");
+    assert_eq!(
+        subcommand(&["cargo", "kcov", "show", "--show-uncovered"]),
+        "show"
+    );
+    assert_eq!(
+        subcommand(&["cargo", "kcov", "serve", "--watch", "--port", "0"]),
+        "serve"
+    );
+    assert_eq!(subcommand(&["cargo", "kcov", "--watch", "--lib"]), "");
+    assert_eq!(subcommand(&["cargo", "kcov", "doctor"]), "doctor");
+    assert_eq!(
+        subcommand(&["cargo", "kcov", "completions", "zsh"]),
+        "completions"
     );
+    assert!(app
+        .get_matches_from_safe_borrow(["cargo", "kcov", "completions", "tcsh"])
+        .is_err());
+    assert!(app
+        .get_matches_from_safe_borrow(["cargo", "kcov", "--watch", "--feature-matrix"])
+        .is_err());
+    assert!(app
+        .get_matches_from_safe_borrow(["cargo", "kcov", "show"])
+        .is_err());
+    assert!(app
+        .get_matches_from_safe_borrow(["cargo", "kcov", "report", "--baseline", "old"])
+        .is_err());
+    assert!(app
+        .get_matches_from_safe_borrow(["cargo", "kcov", "--output-format", "html"])
+        .is_err());
+}
+
+#[test]
+fn test_completion_script() {
+    let script = completion_script(Shell::Bash);
+    assert!(script.contains("cmd=\"cargo__kcov\""));
+    assert!(script.contains("\n        cargo__kcov)\n"));
+    assert!(script.contains("\n        cargo__kcov__kcov__run)\n"));
+    assert!(script.ends_with("complete -F _cargo-kcov -o bashdefault -o default cargo-kcov\n"));
+
+    let script = completion_script(Shell::Zsh);
+    assert!(script.starts_with("#compdef cargo-kcov\n"));
 }
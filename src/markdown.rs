@@ -0,0 +1,217 @@
+//! A compact Markdown summary of the coverage, for pull request comments and GitHub Actions job
+//! summaries.
+//!
+//! The summary has the totals, a table of the workspace members and the least covered files. When
+//! compared with a baseline, e.g. the `lines.json` of a run on the main branch, each row also shows
+//! the change of its coverage.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::Path;
+
+use lcov::FileCoverage;
+use summary::Summary;
+use {owner, Member};
+
+/// Name of the Markdown summary in the output directory.
+pub const MARKDOWN_REPORT_FILE: &str = "report.md";
+
+/// Number of files listed in the table of the least covered files.
+const WORST_FILES: usize = 10;
+
+/// Renders the Markdown summary of `files`, grouped by the workspace `members`, with the paths
+/// relative to `root`. With a `baseline`, the tables get a column with the change of the coverage.
+pub fn markdown_summary(
+    files: &[FileCoverage],
+    baseline: Option<&[FileCoverage]>,
+    members: &[Member],
+    root: &Path,
+) -> String {
+    let mut md = String::new();
+    let total = summarize(files);
+    writeln!(md, "## Coverage: {:.2}%\n", total.percent()).unwrap();
+    write!(
+        md,
+        "{}/{} lines covered",
+        total.covered_lines, total.total_lines
+    )
+    .unwrap();
+    if total.total_branches > 0 {
+        write!(
+            md,
+            ", {}/{} branches taken ({:.2}%)",
+            total.covered_branches,
+            total.total_branches,
+            total.branch_percent()
+        )
+        .unwrap();
+    }
+    if let Some(baseline) = baseline {
+        write!(
+            md,
+            " ({} compared with the baseline)",
+            delta(&total, Some(&summarize(baseline)))
+        )
+        .unwrap();
+    }
+    writeln!(md, "\n").unwrap();
+
+    let baseline_crates = baseline.map(|baseline| group(baseline, members));
+    write_header(&mut md, "Crate", baseline.is_some());
+    for (name, files) in group(files, members) {
+        let previous = baseline_crates.as_ref().map(|crates| {
+            crates
+                .get(name)
+                .map(|files| summarize(files.iter().cloned()))
+        });
+        write_row(&mut md, name, &summarize(files), previous);
+    }
+
+    let mut worst = files
+        .iter()
+        .filter(|file| file.lines.values().any(|&hits| hits == 0))
+        .map(|file| (file, summarize(Some(file))))
+        .collect::<Vec<_>>();
+    worst.sort_by(|a, b| {
+        let uncovered = |s: &Summary| s.total_lines - s.covered_lines;
+        a.1.percent()
+            .partial_cmp(&b.1.percent())
+            .unwrap()
+            .then(uncovered(&b.1).cmp(&uncovered(&a.1)))
+            .then(a.0.path.cmp(&b.0.path))
+    });
+    if !worst.is_empty() {
+        writeln!(md, "\n### Least covered files\n").unwrap();
+        write_header(&mut md, "File", baseline.is_some());
+        for (file, summary) in worst.into_iter().take(WORST_FILES) {
+            let path = file.path.strip_prefix(root).unwrap_or(&file.path);
+            let previous = baseline.map(|baseline| {
+                baseline
+                    .iter()
+                    .find(|previous| previous.path == file.path)
+                    .map(|previous| summarize(Some(previous)))
+            });
+            write_row(&mut md, &path.to_string_lossy(), &summary, previous);
+        }
+    }
+    md
+}
+
+/// Groups `files` by the member containing them, or `(other)` outside of the workspace.
+fn group<'a>(
+    files: &'a [FileCoverage],
+    members: &'a [Member],
+) -> BTreeMap<&'a str, Vec<&'a FileCoverage>> {
+    let mut crates = BTreeMap::<_, Vec<_>>::new();
+    for file in files {
+        let name = owner(members, &file.path).map_or("(other)", |member| &*member.name);
+        crates.entry(name).or_default().push(file);
+    }
+    crates
+}
+
+/// Adds up the line and branch counts of `files`.
+fn summarize<'a, I: IntoIterator<Item = &'a FileCoverage>>(files: I) -> Summary {
+    let mut summary = Summary::default();
+    for file in files {
+        summary.covered_lines += file.lines.values().filter(|&&hits| hits > 0).count() as u64;
+        summary.total_lines += file.lines.len() as u64;
+        for count in file.branches.values() {
+            summary.covered_branches += count.taken;
+            summary.total_branches += count.total;
+        }
+    }
+    summary
+}
+
+fn write_header(md: &mut String, title: &str, has_baseline: bool) {
+    if has_baseline {
+        writeln!(md, "| {} | Lines | Coverage | Change |", title).unwrap();
+        md.push_str("| :--- | ---: | ---: | ---: |\n");
+    } else {
+        writeln!(md, "| {} | Lines | Coverage |", title).unwrap();
+        md.push_str("| :--- | ---: | ---: |\n");
+    }
+}
+
+/// Writes a row of a table. `previous` is `None` without a baseline, and `Some(None)` if the row is
+/// missing in the baseline.
+fn write_row(md: &mut String, name: &str, summary: &Summary, previous: Option<Option<Summary>>) {
+    write!(
+        md,
+        "| `{}` | {}/{} | {:.2}% |",
+        name,
+        summary.covered_lines,
+        summary.total_lines,
+        summary.percent()
+    )
+    .unwrap();
+    if let Some(previous) = previous {
+        write!(md, " {} |", delta(summary, previous.as_ref())).unwrap();
+    }
+    md.push('\n');
+}
+
+/// The change of the line coverage from `previous`, e.g. `+1.25%`, or `new` without one.
+fn delta(summary: &Summary, previous: Option<&Summary>) -> String {
+    let previous = match previous {
+        Some(previous) => previous,
+        None => return "new".to_owned(),
+    };
+    let delta = summary.percent() - previous.percent();
+    if delta.abs() < 0.005 {
+        "0.00%".to_owned()
+    } else {
+        format!("{:+.2}%", delta)
+    }
+}
+
+#[test]
+fn test_markdown_summary() {
+    use lcov::parse_lcov;
+    use std::path::PathBuf;
+
+    let member = |name: &str, root: &str| Member {
+        id: format!("{} 0.1.0", name),
+        name: name.to_owned(),
+        root: PathBuf::from(root),
+        dependencies: Vec::new(),
+        features: Vec::new(),
+    };
+    let members = [member("app", "/ws"), member("core", "/ws/core")];
+    let files = parse_lcov(
+        "SF:/ws/src/main.rs\nDA:1,1\nDA:2,0\nend_of_record\n\
+         SF:/ws/core/src/lib.rs\nDA:1,1\nDA:2,1\nDA:3,1\nDA:4,0\nend_of_record\n\
+         SF:/ws/core/src/done.rs\nDA:1,3\nend_of_record\n",
+    );
+
+    let md = markdown_summary(&files, None, &members, Path::new("/ws"));
+    assert_eq!(
+        md,
+        "## Coverage: 71.43%\n\n\
+         5/7 lines covered\n\n\
+         | Crate | Lines | Coverage |\n\
+         | :--- | ---: | ---: |\n\
+         | `app` | 1/2 | 50.00% |\n\
+         | `core` | 4/5 | 80.00% |\n\
+         \n### Least covered files\n\n\
+         | File | Lines | Coverage |\n\
+         | :--- | ---: | ---: |\n\
+         | `src/main.rs` | 1/2 | 50.00% |\n\
+         | `core/src/lib.rs` | 3/4 | 75.00% |\n"
+    );
+
+    let baseline = parse_lcov(
+        "SF:/ws/core/src/lib.rs\nDA:1,1\nDA:2,0\nDA:3,0\nDA:4,0\nend_of_record\n\
+         SF:/ws/core/src/done.rs\nDA:1,3\nend_of_record\n",
+    );
+    let md = markdown_summary(&files, Some(&baseline), &members, Path::new("/ws"));
+    assert!(md.contains("5/7 lines covered (+31.43% compared with the baseline)\n"));
+    assert!(md.contains("| `app` | 1/2 | 50.00% | new |\n"));
+    assert!(md.contains("| `core` | 4/5 | 80.00% | +40.00% |\n"));
+    assert!(md.contains("| `core/src/lib.rs` | 3/4 | 75.00% | +50.00% |\n"));
+
+    let md = markdown_summary(&files[..1], Some(&files[..1]), &members, Path::new("/ws"));
+    assert!(md.contains("(0.00% compared with the baseline)"));
+    assert!(!md.contains("Least covered"));
+}
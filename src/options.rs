@@ -0,0 +1,248 @@
+//! Options controlling a coverage run.
+
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+
+use backend::BackendKind;
+use cargo::CargoOptions;
+
+/// Options of a coverage run, equivalent to the command line arguments of `cargo kcov`.
+///
+/// ```no_run
+/// use cargo_kcov::{collect_coverage, CoverageOptions};
+///
+/// let options = CoverageOptions::new()
+///     .all(true)
+///     .features("serde")
+///     .output("target/cov");
+/// let report = collect_coverage(&options).unwrap();
+/// println!("{:?}", report.summary);
+/// ```
+#[derive(Default, Debug, Clone)]
+pub struct CoverageOptions {
+    pub(crate) cargo: CargoOptions,
+    pub(crate) backend: Option<BackendKind>,
+    pub(crate) branch: bool,
+    pub(crate) exclude_tests: bool,
+    pub(crate) kcov_path: Option<PathBuf>,
+    pub(crate) kcov_args: Option<Vec<OsString>>,
+    pub(crate) runner: Option<String>,
+    pub(crate) runner_maps: Vec<String>,
+    pub(crate) output: Option<PathBuf>,
+    pub(crate) verbose: bool,
+    pub(crate) coveralls: bool,
+    pub(crate) no_clean_rebuild: bool,
+    pub(crate) append: bool,
+    pub(crate) cache: bool,
+    pub(crate) feature_matrix: bool,
+    pub(crate) feature_sets: Vec<String>,
+}
+
+impl CoverageOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces all options forwarded to cargo.
+    pub fn cargo_options(mut self, cargo: CargoOptions) -> Self {
+        self.cargo = cargo;
+        self
+    }
+
+    /// Test only the package's library.
+    pub fn lib(mut self, lib: bool) -> Self {
+        self.cargo.lib = lib;
+        self
+    }
+
+    /// Test only the specified binary. Can be called multiple times.
+    pub fn bin<S: Into<String>>(mut self, name: S) -> Self {
+        self.cargo.bins.push(name.into());
+        self
+    }
+
+    /// Test only the specified example. Can be called multiple times.
+    pub fn example<S: Into<String>>(mut self, name: S) -> Self {
+        self.cargo.examples.push(name.into());
+        self
+    }
+
+    /// Test only the specified integration test target. Can be called multiple times.
+    pub fn test<S: Into<String>>(mut self, name: S) -> Self {
+        self.cargo.tests.push(name.into());
+        self
+    }
+
+    /// Test only the specified benchmark target. Can be called multiple times.
+    pub fn bench<S: Into<String>>(mut self, name: S) -> Self {
+        self.cargo.benches.push(name.into());
+        self
+    }
+
+    /// The number of jobs to run in parallel.
+    pub fn jobs(mut self, jobs: u32) -> Self {
+        self.cargo.jobs = Some(jobs);
+        self
+    }
+
+    /// Build artifacts in release mode.
+    pub fn release(mut self, release: bool) -> Self {
+        self.cargo.release = release;
+        self
+    }
+
+    /// Space-separated list of features to also build.
+    pub fn features<S: Into<String>>(mut self, features: S) -> Self {
+        self.cargo.features = Some(features.into());
+        self
+    }
+
+    /// Do not build the `default` feature.
+    pub fn no_default_features(mut self, no_default_features: bool) -> Self {
+        self.cargo.no_default_features = no_default_features;
+        self
+    }
+
+    /// Build for the target triple.
+    pub fn target<S: Into<String>>(mut self, target: S) -> Self {
+        self.cargo.target = Some(target.into());
+        self
+    }
+
+    /// Path to the manifest to build tests for.
+    pub fn manifest_path<P: Into<PathBuf>>(mut self, manifest_path: P) -> Self {
+        self.cargo.manifest_path = Some(manifest_path.into());
+        self
+    }
+
+    /// Run all tests regardless of failure.
+    pub fn no_fail_fast(mut self, no_fail_fast: bool) -> Self {
+        self.cargo.no_fail_fast = no_fail_fast;
+        self
+    }
+
+    /// In a workspace, test all members.
+    pub fn all(mut self, all: bool) -> Self {
+        self.cargo.all = all;
+        self
+    }
+
+    /// The backend collecting coverage. By default kcov is used, unless the tests are run by a
+    /// `target.<triple>.runner`, which kcov cannot trace.
+    pub fn backend(mut self, backend: BackendKind) -> Self {
+        self.backend = Some(backend);
+        self
+    }
+
+    /// Collect branch coverage, with the instrument-coverage backend and a nightly compiler.
+    pub fn branch(mut self, branch: bool) -> Self {
+        self.branch = branch;
+        self
+    }
+
+    /// Leave `#[cfg(test)]` items, `#[test]` functions and the files in `tests/` out of the
+    /// merged report and summary.
+    pub fn exclude_tests(mut self, exclude_tests: bool) -> Self {
+        self.exclude_tests = exclude_tests;
+        self
+    }
+
+    /// Path to the kcov executable. By default it is searched as described in `check_kcov`.
+    pub fn kcov_path<P: Into<PathBuf>>(mut self, kcov_path: P) -> Self {
+        self.kcov_path = Some(kcov_path.into());
+        self
+    }
+
+    /// A command line template wrapping every kcov invocation, e.g. to run kcov in a container.
+    /// See the `runner` module for the placeholders.
+    pub fn runner<S: Into<String>>(mut self, template: S) -> Self {
+        self.runner = Some(template.into());
+        self
+    }
+
+    /// Translates paths passed to the runner, in the form `HOST=CONTAINER`. Can be called multiple
+    /// times.
+    pub fn runner_map<S: Into<String>>(mut self, map: S) -> Self {
+        self.runner_maps.push(map.into());
+        self
+    }
+
+    /// Arguments passed to kcov, replacing the default `--verify --exclude-pattern=$CARGO_HOME`.
+    pub fn kcov_args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        self.kcov_args = Some(args.into_iter().map(|s| s.as_ref().to_owned()).collect());
+        self
+    }
+
+    /// Output directory. Default to `target/cov`.
+    pub fn output<P: Into<PathBuf>>(mut self, output: P) -> Self {
+        self.output = Some(output.into());
+        self
+    }
+
+    /// The output directory, `cov` in the target directory `target_path` unless set by `output`.
+    pub fn output_path(&self, target_path: &Path) -> PathBuf {
+        match self.output {
+            Some(ref path) => path.clone(),
+            None => target_path.join("cov"),
+        }
+    }
+
+    /// Print progress messages to stderr.
+    pub fn verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    /// Upload merged coverage data to coveralls.io from Travis CI.
+    pub fn coveralls(mut self, coveralls: bool) -> Self {
+        self.coveralls = coveralls;
+        self
+    }
+
+    /// Do not perform a clean rebuild, but find existing test executables in the target directory.
+    pub fn no_clean_rebuild(mut self, no_clean_rebuild: bool) -> Self {
+        self.no_clean_rebuild = no_clean_rebuild;
+        self
+    }
+
+    /// Accumulate coverage into the existing output directory instead of deleting it.
+    pub fn append(mut self, append: bool) -> Self {
+        self.append = append;
+        self
+    }
+
+    /// Reuse the kcov results of unchanged test executables from `target/kcov-cache`.
+    pub fn cache(mut self, cache: bool) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// Collect coverage separately for several feature sets. The sets are taken from
+    /// `feature_set`, the project configuration, or `FeatureSet::default_matrix()`.
+    pub fn feature_matrix(mut self, feature_matrix: bool) -> Self {
+        self.feature_matrix = feature_matrix;
+        self
+    }
+
+    /// Adds a feature set to collect coverage for, implies `feature_matrix(true)`. See
+    /// `FeatureSet::parse` for the syntax.
+    pub fn feature_set<S: Into<String>>(mut self, spec: S) -> Self {
+        self.feature_matrix = true;
+        self.feature_sets.push(spec.into());
+        self
+    }
+
+    /// Options forwarded to cargo.
+    pub fn get_cargo_options(&self) -> &CargoOptions {
+        &self.cargo
+    }
+
+    /// Whether progress messages are printed.
+    pub fn is_verbose(&self) -> bool {
+        self.verbose
+    }
+}
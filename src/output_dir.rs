@@ -0,0 +1,268 @@
+//! Safe preparation of the coverage output directory.
+//!
+//! The output directory is wiped before every run. To avoid destroying user data when `--output`
+//! points somewhere unexpected, only directories carrying a marker file written by cargo-kcov are
+//! deleted, and paths containing the workspace or the home directory are always refused. Output
+//! directories of older versions, which wrote no marker, are recognized by their merged report.
+//!
+//! The per-executable kcov results inside an existing output directory can be found again for
+//! merging.
+
+use std::fs::{canonicalize, create_dir_all, read_dir, remove_dir_all, write};
+use std::path::{Path, PathBuf};
+
+use errors::Error;
+
+/// Name of the marker file identifying a directory created by cargo-kcov.
+pub const MARKER_FILE: &str = ".cargo-kcov";
+
+const MARKER_CONTENT: &str = "This directory is created by cargo-kcov, \
+                              and will be deleted on the next run.\n";
+
+/// Prepares `cov_path` for a new coverage run.
+///
+/// If `append` is false, an existing directory is deleted only if it was created by cargo-kcov (or
+/// is empty). If `append` is true, existing content is kept so kcov can accumulate into it.
+///
+/// The `protected` paths (e.g. the workspace root) and all their ancestors are never used.
+pub fn prepare_output_dir(cov_path: &Path, protected: &[&Path], append: bool) -> Result<(), Error> {
+    if is_protected(cov_path, protected) {
+        return Err(Error::DangerousOutputDirectory(cov_path.to_owned()));
+    }
+
+    if cov_path.exists() {
+        let is_owned = is_owned(cov_path);
+        let is_empty = match read_dir(cov_path) {
+            Ok(mut entries) => entries.next().is_none(),
+            Err(e) => return Err(Error::CannotCreateCoverageDirectory(e)),
+        };
+        if append {
+            if !is_empty {
+                return Ok(());
+            }
+        } else if is_owned {
+            if let Err(e) = remove_dir_all(cov_path) {
+                return Err(Error::CannotCreateCoverageDirectory(e));
+            }
+        } else if !is_empty {
+            return Err(Error::OutputDirectoryNotOwned(cov_path.to_owned()));
+        }
+    }
+
+    match create_dir_all(cov_path).and_then(|_| write(cov_path.join(MARKER_FILE), MARKER_CONTENT)) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(Error::CannotCreateCoverageDirectory(e)),
+    }
+}
+
+/// Deletes the output directory `cov_path` if it was created by cargo-kcov. Returns false if it
+/// does not exist.
+pub fn remove_output_dir(cov_path: &Path) -> Result<bool, Error> {
+    if !cov_path.exists() {
+        return Ok(false);
+    }
+    if !is_owned(cov_path) {
+        return Err(Error::OutputDirectoryNotOwned(cov_path.to_owned()));
+    }
+    match remove_dir_all(cov_path) {
+        Ok(_) => Ok(true),
+        Err(e) => Err(Error::CannotCreateCoverageDirectory(e)),
+    }
+}
+
+/// Checks whether `cov_path` was created by cargo-kcov: it carries the marker, or it contains the
+/// `kcov-merged` report written by versions predating the marker.
+fn is_owned(cov_path: &Path) -> bool {
+    cov_path.join(MARKER_FILE).is_file() || cov_path.join("kcov-merged").is_dir()
+}
+
+/// Checks whether `path` is the root directory, or is equal to or an ancestor of any protected path.
+pub fn is_protected(path: &Path, protected: &[&Path]) -> bool {
+    let path = normalize(path);
+    if path.parent().is_none() {
+        return true;
+    }
+    protected.iter().any(|p| normalize(p).starts_with(&path))
+}
+
+/// Resolves symlinks and `..` as far as the path exists, so that e.g. `target/cov/../..` is
+/// recognized as the workspace root.
+fn normalize(path: &Path) -> PathBuf {
+    if let Ok(p) = canonicalize(path) {
+        return p;
+    }
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) if !parent.as_os_str().is_empty() => {
+            normalize(parent).join(name)
+        }
+        _ => path.to_owned(),
+    }
+}
+
+/// Collects the per-executable kcov results inside an output directory of a previous run. The
+/// per-feature-set directories created by `--feature-matrix` are searched recursively.
+pub fn find_merge_inputs_into(inputs: &mut Vec<PathBuf>, cov_path: &Path) -> Result<(), Error> {
+    if !cov_path.join("kcov-merged").is_dir() {
+        return Err(Error::CannotFindCoverageData(cov_path.to_owned(), None));
+    }
+    find_result_dirs_into(inputs, cov_path)
+}
+
+/// Same as `find_merge_inputs_into`, but `cov_path` may not contain a merged report yet.
+pub fn find_result_dirs_into(inputs: &mut Vec<PathBuf>, cov_path: &Path) -> Result<(), Error> {
+    let result = (|| {
+        let mut result = Vec::new();
+        for entry in cov_path.read_dir()? {
+            let entry = entry?;
+            // `data` and `kcov-merged` are written by `kcov --merge` itself.
+            if entry.file_type()?.is_dir()
+                && entry.file_name() != "data"
+                && entry.file_name() != "kcov-merged"
+            {
+                result.push(entry.path());
+            }
+        }
+        Ok(result)
+    })();
+
+    match result {
+        Ok(ref r) if r.is_empty() => Err(Error::CannotFindCoverageData(cov_path.to_owned(), None)),
+        Ok(mut r) => {
+            r.sort();
+            for path in r {
+                if path.join("kcov-merged").is_dir() {
+                    find_merge_inputs_into(inputs, &path)?;
+                } else {
+                    inputs.push(path);
+                }
+            }
+            Ok(())
+        }
+        Err(e) => Err(Error::CannotFindCoverageData(cov_path.to_owned(), Some(e))),
+    }
+}
+
+#[test]
+fn test_find_merge_inputs() {
+    use std::fs::{create_dir_all, File};
+    use tempdir::TempDir;
+
+    let root = TempDir::new("test_find_merge_inputs").unwrap();
+    let root_path = root.path();
+    for dir in &[
+        "cov-a/data",
+        "cov-a/kcov-merged",
+        "cov-a/second-f0ac3ec8d3d3bcd5/second-f0ac3ec8d3d3bcd5.a1b2c3",
+        "cov-a/first-d5d6293fc6d22a93",
+        "cov-b/kcov-merged",
+        "cov-b/first-d5d6293fc6d22a93",
+        "cov-b/features-default/kcov-merged",
+        "cov-b/features-default/sixth-cd20d019c38b7035",
+        "src/bin",
+    ] {
+        create_dir_all(root_path.join(dir)).unwrap();
+    }
+    File::create(root_path.join("cov-a/index.html")).unwrap();
+
+    let mut inputs = Vec::new();
+    find_merge_inputs_into(&mut inputs, &root_path.join("cov-a")).unwrap();
+    find_merge_inputs_into(&mut inputs, &root_path.join("cov-b")).unwrap();
+    assert_eq!(
+        inputs,
+        [
+            root_path.join("cov-a/first-d5d6293fc6d22a93"),
+            root_path.join("cov-a/second-f0ac3ec8d3d3bcd5"),
+            root_path.join("cov-b/features-default/sixth-cd20d019c38b7035"),
+            root_path.join("cov-b/first-d5d6293fc6d22a93"),
+        ]
+    );
+
+    for dir in &["src", "cov-c"] {
+        match find_merge_inputs_into(&mut inputs, &root_path.join(dir)) {
+            Err(Error::CannotFindCoverageData(..)) => {}
+            r => panic!("unexpected result {:?} for {}", r, dir),
+        }
+    }
+}
+
+#[test]
+fn test_prepare_output_dir() {
+    use std::fs::{create_dir, remove_file, File};
+    use tempdir::TempDir;
+
+    let root = TempDir::new("test_prepare_output_dir").unwrap();
+    let workspace = root.path().join("workspace");
+    create_dir(&workspace).unwrap();
+    let protected: &[&Path] = &[&workspace];
+
+    // fresh directory is created with a marker.
+    let cov_path = workspace.join("target/cov");
+    prepare_output_dir(&cov_path, protected, false).unwrap();
+    assert!(cov_path.join(MARKER_FILE).is_file());
+
+    // directory created by cargo-kcov is wiped.
+    File::create(cov_path.join("index.html")).unwrap();
+    prepare_output_dir(&cov_path, protected, false).unwrap();
+    assert!(!cov_path.join("index.html").exists());
+    assert!(cov_path.join(MARKER_FILE).is_file());
+
+    // existing content is kept when appending.
+    File::create(cov_path.join("index.html")).unwrap();
+    prepare_output_dir(&cov_path, protected, true).unwrap();
+    assert!(cov_path.join("index.html").exists());
+
+    // foreign directory is never deleted.
+    let reports = root.path().join("reports");
+    create_dir(&reports).unwrap();
+    File::create(reports.join("important.txt")).unwrap();
+    match prepare_output_dir(&reports, protected, false) {
+        Err(Error::OutputDirectoryNotOwned(ref p)) if *p == reports => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+    assert!(reports.join("important.txt").exists());
+    prepare_output_dir(&reports, protected, true).unwrap();
+    assert!(reports.join("important.txt").exists());
+    assert!(!reports.join(MARKER_FILE).exists());
+
+    // output directory of an older version without a marker is wiped.
+    let old_cov_path = workspace.join("target/old-cov");
+    create_dir_all(old_cov_path.join("kcov-merged")).unwrap();
+    File::create(old_cov_path.join("index.html")).unwrap();
+    prepare_output_dir(&old_cov_path, protected, false).unwrap();
+    assert!(!old_cov_path.join("index.html").exists());
+    assert!(old_cov_path.join(MARKER_FILE).is_file());
+    create_dir_all(old_cov_path.join("kcov-merged")).unwrap();
+    remove_file(old_cov_path.join(MARKER_FILE)).unwrap();
+    assert!(remove_output_dir(&old_cov_path).unwrap());
+
+    // empty foreign directory is adopted.
+    let empty = root.path().join("empty");
+    create_dir(&empty).unwrap();
+    prepare_output_dir(&empty, protected, false).unwrap();
+    assert!(empty.join(MARKER_FILE).is_file());
+
+    // only directories created by cargo-kcov are removed.
+    assert!(remove_output_dir(&empty).unwrap());
+    assert!(!empty.exists());
+    assert!(!remove_output_dir(&empty).unwrap());
+    match remove_output_dir(&reports) {
+        Err(Error::OutputDirectoryNotOwned(_)) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+    assert!(reports.join("important.txt").exists());
+
+    // dangerous paths are refused even if they carry a marker.
+    File::create(workspace.join(MARKER_FILE)).unwrap();
+    for path in &[
+        workspace.clone(),
+        root.path().to_owned(),
+        cov_path.join("../.."),
+        PathBuf::from("/"),
+    ] {
+        match prepare_output_dir(path, protected, false) {
+            Err(Error::DangerousOutputDirectory(_)) => {}
+            r => panic!("unexpected result {:?} for {:?}", r, path),
+        }
+    }
+    assert!(workspace.join("target").exists());
+}
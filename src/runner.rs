@@ -0,0 +1,162 @@
+//! Running kcov through a wrapper command, e.g. inside a container where ptrace is permitted.
+//!
+//! The runner is given as a command line template such as
+//!
+//! ```text
+//! docker run --rm --cap-add SYS_PTRACE -v {workspace}:{workspace} kcov/kcov
+//! ```
+//!
+//! and the kcov command line is appended to it. `{workspace}` and `{target}` are replaced by the
+//! workspace root and the target directory. If the container sees the host directories at other
+//! locations, the paths passed to kcov are translated by the `HOST=CONTAINER` path maps, so the
+//! results still land in the host's output directory.
+
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+
+use shlex;
+
+use cargo::Cmd;
+use errors::Error;
+
+/// A command wrapping every invocation of kcov.
+#[derive(Debug, Clone)]
+pub struct Runner {
+    words: Vec<String>,
+    maps: Vec<(PathBuf, PathBuf)>,
+}
+
+impl Runner {
+    /// Parses the template, replacing the placeholders `{name}` by the paths in `vars`.
+    pub fn parse(template: &str, vars: &[(&str, &Path)]) -> Result<Runner, Error> {
+        let words = match shlex::split(template) {
+            Some(ref words) if !words.is_empty() => words
+                .iter()
+                .map(|word| substitute(word, vars))
+                .collect::<Result<_, _>>()?,
+            Some(_) => return Err(Error::InvalidRunner("the command is empty".to_owned())),
+            None => return Err(Error::InvalidRunner("unbalanced quotes".to_owned())),
+        };
+        Ok(Runner {
+            words,
+            maps: Vec::new(),
+        })
+    }
+
+    /// Translates paths under `host` to `container`. Placeholders are replaced like the template.
+    pub fn map(mut self, map: &str, vars: &[(&str, &Path)]) -> Result<Runner, Error> {
+        let (host, container) = match map.find('=') {
+            Some(i) => (&map[..i], &map[i + 1..]),
+            None => {
+                return Err(Error::InvalidRunner(format!(
+                    "path map `{}` should be in the form `HOST=CONTAINER`",
+                    map
+                )))
+            }
+        };
+        self.maps.push((
+            PathBuf::from(substitute(host, vars)?),
+            PathBuf::from(substitute(container, vars)?),
+        ));
+        Ok(self)
+    }
+
+    /// Prepares the command running `program` with `args` through the runner.
+    pub(crate) fn wrap<S: AsRef<OsStr>>(&self, program: &Path, args: &[S]) -> Cmd {
+        let args = args
+            .iter()
+            .map(|arg| self.map_arg(arg.as_ref()))
+            .collect::<Vec<_>>();
+        Cmd::new(&self.words[0], "")
+            .args(&self.words[1..])
+            .args(&[self.map_arg(program.as_os_str())])
+            .args(&args)
+    }
+
+    /// Translates a path argument, or the value of an `--option=path` argument.
+    fn map_arg(&self, arg: &OsStr) -> OsString {
+        let s = match arg.to_str() {
+            Some(s) => s,
+            None => return arg.to_owned(),
+        };
+        let (prefix, path) = match s.find('=') {
+            Some(i) if s.starts_with("--") => s.split_at(i + 1),
+            _ => ("", s),
+        };
+        for (host, container) in &self.maps {
+            if let Ok(rest) = Path::new(path).strip_prefix(host) {
+                let mut mapped = OsString::from(prefix);
+                if rest.as_os_str().is_empty() {
+                    mapped.push(container);
+                } else {
+                    mapped.push(container.join(rest));
+                }
+                return mapped;
+            }
+        }
+        arg.to_owned()
+    }
+}
+
+fn substitute(word: &str, vars: &[(&str, &Path)]) -> Result<String, Error> {
+    let mut result = word.to_owned();
+    for &(name, path) in vars {
+        let path = path.to_str().ok_or_else(|| {
+            Error::InvalidRunner(format!("`{}` is not UTF-8 encoded", path.display()))
+        })?;
+        result = result.replace(&format!("{{{}}}", name), path);
+    }
+    Ok(result)
+}
+
+#[test]
+fn test_runner() {
+    let vars = [
+        ("workspace", Path::new("/home/me/project")),
+        ("target", Path::new("/home/me/project/target")),
+    ];
+    let runner = Runner::parse(
+        "docker run --rm -v '{workspace}:/src' -v {target}:{target} kcov/kcov",
+        &vars,
+    )
+    .unwrap()
+    .map("{workspace}=/src", &vars)
+    .unwrap();
+    assert_eq!(
+        runner.words,
+        [
+            "docker",
+            "run",
+            "--rm",
+            "-v",
+            "/home/me/project:/src",
+            "-v",
+            "/home/me/project/target:/home/me/project/target",
+            "kcov/kcov",
+        ]
+    );
+
+    let map = |arg| runner.map_arg(OsStr::new(arg)).into_string().unwrap();
+    assert_eq!(map("/home/me/project/target/cov"), "/src/target/cov");
+    assert_eq!(map("/home/me/project"), "/src");
+    assert_eq!(map("/home/me/project2/target"), "/home/me/project2/target");
+    assert_eq!(
+        map("--include-path=/home/me/project/src"),
+        "--include-path=/src/src"
+    );
+    assert_eq!(map("--verify"), "--verify");
+    assert_eq!(map("/usr/bin/kcov"), "/usr/bin/kcov");
+
+    match Runner::parse("docker 'run", &vars) {
+        Err(Error::InvalidRunner(_)) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+    match Runner::parse("  ", &vars) {
+        Err(Error::InvalidRunner(_)) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+    match runner.map("/src", &vars) {
+        Err(Error::InvalidRunner(_)) => {}
+        r => panic!("unexpected result {:?}", r),
+    }
+}
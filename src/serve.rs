@@ -0,0 +1,238 @@
+//! A local HTTP server for the coverage report, for machines where `--open` cannot start a browser,
+//! e.g. over SSH.
+//!
+//! The files of the output directory are served as is. With live reload, a script polling the
+//! generation of the report is added to the HTML pages, which reload when `Reloader::reload` is
+//! called after the report is rewritten.
+
+use std::fs::File;
+use std::io::{self, Cursor, Read};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use tiny_http::{Header, Request, Response, Server, StatusCode};
+
+use errors::Error;
+
+/// URL polled by the live reload script, returning the generation of the report.
+const GENERATION_URL: &str = "/.cargo-kcov/generation";
+
+const RELOAD_SCRIPT: &str = r#"<script>
+(function () {
+  var generation = null;
+  setInterval(function () {
+    fetch("/.cargo-kcov/generation").then(function (r) { return r.text(); }).then(function (g) {
+      if (generation !== null && g !== generation) { location.reload(); }
+      generation = g;
+    }).catch(function () {});
+  }, 1000);
+})();
+</script>"#;
+
+/// Serves the output directory over HTTP.
+pub struct ReportServer {
+    server: Server,
+    cov_path: PathBuf,
+    index: String,
+    generation: Arc<AtomicUsize>,
+    is_live_reload: bool,
+}
+
+/// Makes the pages served by a `ReportServer` reload.
+#[derive(Clone)]
+pub struct Reloader(Arc<AtomicUsize>);
+
+impl Reloader {
+    /// Reloads the pages open in the browser, after the report was rewritten.
+    pub fn reload(&self) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+impl ReportServer {
+    /// Listens on `address`, e.g. `127.0.0.1:8000`. Port 0 picks a free port.
+    pub fn bind(address: &str, cov_path: PathBuf) -> Result<Self, Error> {
+        let server = Server::http(address)
+            .map_err(|e| Error::CannotStartServer(format!("{}: {}", address, e)))?;
+        Ok(ReportServer {
+            server,
+            cov_path,
+            index: "index.html".to_owned(),
+            generation: Arc::new(AtomicUsize::new(0)),
+            is_live_reload: false,
+        })
+    }
+
+    /// The page served at `/`, relative to the output directory. Default to `index.html`.
+    pub fn index<S: Into<String>>(mut self, index: S) -> Self {
+        self.index = index.into();
+        self
+    }
+
+    /// Adds the live reload script to the HTML pages.
+    pub fn live_reload(mut self, is_live_reload: bool) -> Self {
+        self.is_live_reload = is_live_reload;
+        self
+    }
+
+    /// The URL of the report.
+    pub fn url(&self) -> String {
+        match self.server.server_addr().to_ip() {
+            Some(addr) if addr.ip().is_unspecified() => {
+                format!("http://localhost:{}/", addr.port())
+            }
+            Some(addr) => format!("http://{}/", addr),
+            None => "http://localhost/".to_owned(),
+        }
+    }
+
+    pub fn reloader(&self) -> Reloader {
+        Reloader(self.generation.clone())
+    }
+
+    /// Serves requests until the process exits.
+    pub fn run(self) {
+        for request in self.server.incoming_requests() {
+            // The browser may have gone away, which is not worth reporting.
+            let _ = self.respond(request);
+        }
+    }
+
+    fn respond(&self, request: Request) -> io::Result<()> {
+        let url = request
+            .url()
+            .split(['?', '#'])
+            .next()
+            .unwrap_or("/")
+            .to_owned();
+        if url == GENERATION_URL {
+            let generation = self.generation.load(Ordering::SeqCst).to_string();
+            return request.respond(Response::from_string(generation));
+        }
+        let path = match resolve(&self.cov_path, &url, &self.index) {
+            Some(path) => path,
+            None => return request.respond(not_found()),
+        };
+        let mut file = match File::open(&path) {
+            Ok(file) => file,
+            Err(_) => return request.respond(not_found()),
+        };
+        let content_type = content_type(&path);
+        if self.is_live_reload && content_type.starts_with("text/html") {
+            let mut html = String::new();
+            file.read_to_string(&mut html)?;
+            match html.rfind("</body>") {
+                Some(i) => html.insert_str(i, RELOAD_SCRIPT),
+                None => html.push_str(RELOAD_SCRIPT),
+            }
+            request.respond(Response::from_string(html).with_header(header(content_type)))
+        } else {
+            request.respond(Response::from_file(file).with_header(header(content_type)))
+        }
+    }
+}
+
+/// Maps the URL path to a file in `root`, refusing paths escaping it.
+fn resolve(root: &Path, url: &str, index: &str) -> Option<PathBuf> {
+    let relative = Path::new(url.trim_start_matches('/'));
+    if relative
+        .components()
+        .any(|c| !matches!(c, Component::Normal(_)))
+    {
+        return None;
+    }
+    let path = root.join(relative);
+    if path.is_dir() {
+        let index = if relative.as_os_str().is_empty() {
+            index
+        } else {
+            "index.html"
+        };
+        Some(path.join(index))
+    } else {
+        Some(path)
+    }
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("html") | Some("htm") => "text/html; charset=utf-8",
+        Some("css") => "text/css",
+        Some("js") => "application/javascript",
+        Some("json") => "application/json",
+        Some("xml") => "application/xml",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("gif") => "image/gif",
+        _ => "text/plain; charset=utf-8",
+    }
+}
+
+fn header(content_type: &str) -> Header {
+    Header::from_bytes(&b"Content-Type"[..], content_type.as_bytes()).unwrap()
+}
+
+fn not_found() -> Response<Cursor<Vec<u8>>> {
+    Response::from_string("not found").with_status_code(StatusCode(404))
+}
+
+#[test]
+fn test_report_server() {
+    use std::fs::{create_dir, write};
+    use std::io::Write;
+    use std::net::TcpStream;
+    use std::thread::spawn;
+    use tempdir::TempDir;
+
+    let dir = TempDir::new("cargo-kcov-serve").unwrap();
+    write(
+        dir.path().join("index.html"),
+        "<html><body>kcov</body></html>",
+    )
+    .unwrap();
+    write(
+        dir.path().join("report.html"),
+        "<html><body>report</body></html>",
+    )
+    .unwrap();
+    create_dir(dir.path().join("kcov-merged")).unwrap();
+    write(dir.path().join("kcov-merged/coverage.json"), "{}").unwrap();
+
+    assert_eq!(resolve(dir.path(), "/../secret", "index.html"), None);
+    assert_eq!(
+        resolve(dir.path(), "/kcov-merged", "report.html"),
+        Some(dir.path().join("kcov-merged/index.html"))
+    );
+
+    let server = ReportServer::bind("127.0.0.1:0", dir.path().to_owned())
+        .unwrap()
+        .index("report.html")
+        .live_reload(true);
+    let url = server.url();
+    let reloader = server.reloader();
+    spawn(move || server.run());
+
+    let get = |path: &str| {
+        let host = url.trim_start_matches("http://").trim_end_matches('/');
+        let mut stream = TcpStream::connect(host).unwrap();
+        write!(
+            stream,
+            "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            path, host
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    };
+    let page = get("/");
+    assert!(page.starts_with("HTTP/1.1 200"));
+    assert!(page.contains("text/html"));
+    assert!(page.contains("report<script>"));
+    assert!(get("/kcov-merged/coverage.json").ends_with("{}"));
+    assert!(get("/missing.html").starts_with("HTTP/1.1 404"));
+    assert!(get(GENERATION_URL).ends_with("\r\n0"));
+    reloader.reload();
+    assert!(get(GENERATION_URL).ends_with("\r\n1"));
+}
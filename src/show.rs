@@ -0,0 +1,220 @@
+//! Source listings annotated with hit counts, printed by `cargo kcov show` for a quick look at the
+//! coverage without a browser, e.g. over SSH.
+//!
+//! Uncovered lines are printed in full together with a few lines around them, while long regions
+//! without any are collapsed into a single line.
+
+use std::io;
+
+use term::color::{GREEN, RED, WHITE};
+use term::{Attr, Terminal};
+
+use lcov::FileCoverage;
+
+/// Number of lines printed before and after each uncovered line.
+const CONTEXT: u32 = 3;
+
+/// Regions without uncovered lines are collapsed only if they are longer than this, unless only the
+/// uncovered lines are shown.
+const MIN_COLLAPSED: u32 = 8;
+
+/// A line of an annotated source listing.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AnnotatedLine<'a> {
+    /// A line of the source, with its hit count if it is instrumented.
+    Source {
+        number: u32,
+        hits: Option<u64>,
+        text: &'a str,
+    },
+    /// The lines `start` to `end` left out, none of which is uncovered.
+    Collapsed { start: u32, end: u32 },
+}
+
+/// Annotates `source`, the content of the file covered by `file`. With `is_uncovered_only`, only
+/// the uncovered lines and the lines around them are kept, otherwise only the regions without any
+/// uncovered lines longer than `MIN_COLLAPSED` are collapsed.
+pub fn annotate<'a>(
+    source: &'a str,
+    file: &FileCoverage,
+    is_uncovered_only: bool,
+) -> Vec<AnnotatedLine<'a>> {
+    let uncovered = file
+        .lines
+        .iter()
+        .filter(|&(_, &hits)| hits == 0)
+        .map(|(&line, _)| line)
+        .collect::<Vec<_>>();
+    let is_near_uncovered = |number: u32| {
+        uncovered
+            .iter()
+            .any(|&line| number + CONTEXT >= line && number <= line + CONTEXT)
+    };
+
+    let mut annotated = Vec::new();
+    let mut hidden = Vec::new();
+    let flush = |hidden: &mut Vec<AnnotatedLine<'a>>, annotated: &mut Vec<AnnotatedLine<'a>>| {
+        let len = hidden.len() as u32;
+        if len > 0 && (is_uncovered_only || len > MIN_COLLAPSED) {
+            let start = match hidden[0] {
+                AnnotatedLine::Source { number, .. } => number,
+                AnnotatedLine::Collapsed { start, .. } => start,
+            };
+            annotated.push(AnnotatedLine::Collapsed {
+                start,
+                end: start + len - 1,
+            });
+            hidden.clear();
+        } else {
+            annotated.append(hidden);
+        }
+    };
+    for (number, text) in (1..).zip(source.lines()) {
+        let line = AnnotatedLine::Source {
+            number,
+            hits: file.lines.get(&number).cloned(),
+            text,
+        };
+        if is_near_uncovered(number) {
+            flush(&mut hidden, &mut annotated);
+            annotated.push(line);
+        } else {
+            hidden.push(line);
+        }
+    }
+    flush(&mut hidden, &mut annotated);
+    annotated
+}
+
+/// Prints the annotated lines of a source file to `t`, under a header with `title` and the share of
+/// covered lines of `file`. Covered lines are green and uncovered ones red.
+pub fn print_annotated<T: Terminal + ?Sized>(
+    t: &mut T,
+    title: &str,
+    file: &FileCoverage,
+    lines: &[AnnotatedLine],
+) -> io::Result<()> {
+    let covered = file.lines.values().filter(|&&hits| hits > 0).count();
+    t.attr(Attr::Bold)?;
+    write!(t, "{}", title)?;
+    t.reset()?;
+    if file.lines.is_empty() {
+        writeln!(t, ": no instrumented lines")?;
+    } else {
+        writeln!(
+            t,
+            ": {:.2}% ({}/{} lines)",
+            100.0 * covered as f64 / file.lines.len() as f64,
+            covered,
+            file.lines.len()
+        )?;
+    }
+
+    let width = lines
+        .iter()
+        .map(|line| match *line {
+            AnnotatedLine::Source { number, .. } => number,
+            AnnotatedLine::Collapsed { end, .. } => end,
+        })
+        .max()
+        .unwrap_or(0)
+        .to_string()
+        .len()
+        .max("...".len());
+    for line in lines {
+        match *line {
+            AnnotatedLine::Source { number, hits, text } => {
+                let hits = match hits {
+                    Some(0) => {
+                        t.fg(RED)?;
+                        "0".to_owned()
+                    }
+                    Some(hits) => {
+                        t.fg(GREEN)?;
+                        hits.to_string()
+                    }
+                    None => String::new(),
+                };
+                writeln!(
+                    t,
+                    "{:>width$} {:>6} | {}",
+                    number,
+                    hits,
+                    text,
+                    width = width
+                )?;
+                t.reset()?;
+            }
+            AnnotatedLine::Collapsed { start, end } => {
+                t.fg(WHITE)?;
+                writeln!(
+                    t,
+                    "{:>width$} {:>6} | ({} lines without uncovered code)",
+                    "...",
+                    "",
+                    end - start + 1,
+                    width = width
+                )?;
+                t.reset()?;
+            }
+        }
+    }
+    writeln!(t)?;
+    Ok(())
+}
+
+#[test]
+fn test_annotate() {
+    use lcov::parse_lcov;
+
+    let source = (1..=30)
+        .map(|n| format!("line {}", n))
+        .collect::<Vec<_>>()
+        .join("\n");
+    let files = parse_lcov(
+        "SF:/ws/src/lib.rs\nDA:2,1\nDA:3,0\nDA:4,2\nDA:20,1\nDA:21,1\nDA:29,0\nend_of_record\n",
+    );
+    let file = &files[0];
+
+    let annotated = annotate(&source, file, false);
+    assert_eq!(
+        annotated[..3],
+        [
+            AnnotatedLine::Source {
+                number: 1,
+                hits: None,
+                text: "line 1"
+            },
+            AnnotatedLine::Source {
+                number: 2,
+                hits: Some(1),
+                text: "line 2"
+            },
+            AnnotatedLine::Source {
+                number: 3,
+                hits: Some(0),
+                text: "line 3"
+            },
+        ]
+    );
+    // Lines 7 to 25 have no uncovered line nearby.
+    assert_eq!(annotated[6], AnnotatedLine::Collapsed { start: 7, end: 25 });
+    assert_eq!(annotated.len(), 6 + 1 + 5);
+
+    let annotated = annotate(&source, file, true);
+    assert_eq!(annotated.len(), 6 + 1 + 5);
+    let annotated = annotate(&source[..source.find("line 12").unwrap()], file, true);
+    assert_eq!(annotated[6], AnnotatedLine::Collapsed { start: 7, end: 11 });
+    // The short region is kept.
+    assert_eq!(
+        annotate(&source[..source.find("line 12").unwrap()], file, false).len(),
+        11
+    );
+
+    let covered = parse_lcov("SF:/ws/src/a.rs\nDA:1,1\nend_of_record\n");
+    assert_eq!(
+        annotate(&source, &covered[0], false),
+        [AnnotatedLine::Collapsed { start: 1, end: 30 }]
+    );
+    assert!(annotate("", &covered[0], true).is_empty());
+}
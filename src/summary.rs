@@ -0,0 +1,140 @@
+//! Reads the coverage summary written by kcov, or by `llvm-cov export -summary-only`.
+
+use std::fs::File;
+use std::path::Path;
+
+use serde_json::{from_reader, Value};
+
+/// File written into the output directory by instrumented runs, see `instrument`.
+pub const LLVM_COV_SUMMARY: &str = "llvm-cov-summary.json";
+
+/// Total line coverage of a kcov report.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Summary {
+    pub covered_lines: u64,
+    pub total_lines: u64,
+    /// Branches taken, and the total number of branches. Zero unless collected with `--branch`.
+    pub covered_branches: u64,
+    pub total_branches: u64,
+}
+
+impl Summary {
+    /// Reads the summary of a merged report in `cov_path`, from `kcov-merged/coverage.json`, or
+    /// `llvm-cov-summary.json` for instrumented runs. Returns `None` if the file is missing or
+    /// malformed, e.g. for kcov versions not writing it.
+    pub fn read(cov_path: &Path) -> Option<Summary> {
+        match File::open(cov_path.join("kcov-merged").join("coverage.json")) {
+            Ok(file) => Summary::from_json(&from_reader(file).ok()?),
+            Err(_) => {
+                let file = File::open(cov_path.join(LLVM_COV_SUMMARY)).ok()?;
+                Summary::from_llvm_cov_json(&from_reader(file).ok()?)
+            }
+        }
+    }
+
+    pub fn from_json(json: &Value) -> Option<Summary> {
+        Some(Summary {
+            covered_lines: get_u64(&json["covered_lines"])?,
+            total_lines: get_u64(&json["total_lines"])?,
+            ..Summary::default()
+        })
+    }
+
+    /// Parses the output of `llvm-cov export -summary-only`.
+    pub fn from_llvm_cov_json(json: &Value) -> Option<Summary> {
+        let totals = &json["data"][0]["totals"];
+        let lines = &totals["lines"];
+        let branches = &totals["branches"];
+        Some(Summary {
+            covered_lines: lines["covered"].as_u64()?,
+            total_lines: lines["count"].as_u64()?,
+            covered_branches: branches["covered"].as_u64().unwrap_or(0),
+            total_branches: branches["count"].as_u64().unwrap_or(0),
+        })
+    }
+
+    pub fn percent(&self) -> f64 {
+        percent(self.covered_lines, self.total_lines)
+    }
+
+    pub fn branch_percent(&self) -> f64 {
+        percent(self.covered_branches, self.total_branches)
+    }
+}
+
+fn percent(covered: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        covered as f64 * 100.0 / total as f64
+    }
+}
+
+/// Some versions of kcov write numbers as strings.
+fn get_u64(value: &Value) -> Option<u64> {
+    match *value {
+        Value::Number(ref n) => n.as_u64(),
+        Value::String(ref s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+#[test]
+fn test_summary_from_json() {
+    use serde_json::from_str;
+
+    let json = from_str(
+        r#"{
+            "files": [],
+            "percent_covered": "45.00",
+            "covered_lines": 9,
+            "total_lines": 20,
+            "percent_low": 25,
+            "percent_high": 75,
+            "command": "sixth-cd20d019c38b7035",
+            "date": "2017-01-01 00:00:00"
+        }"#,
+    )
+    .unwrap();
+    let summary = Summary::from_json(&json).unwrap();
+    assert_eq!(
+        summary,
+        Summary {
+            covered_lines: 9,
+            total_lines: 20,
+            ..Summary::default()
+        }
+    );
+    assert_eq!(summary.percent(), 45.0);
+
+    let json = from_str(r#"{"covered_lines": "0", "total_lines": "0"}"#).unwrap();
+    assert_eq!(Summary::from_json(&json).unwrap().percent(), 0.0);
+
+    assert_eq!(Summary::from_json(&from_str("{}").unwrap()), None);
+
+    let json = from_str(
+        r#"{
+            "data": [{
+                "files": [],
+                "totals": {
+                    "branches": {"count": 8, "covered": 6, "notcovered": 2, "percent": 75},
+                    "functions": {"count": 4, "covered": 3, "percent": 75},
+                    "lines": {"count": 40, "covered": 30, "percent": 75}
+                }
+            }],
+            "type": "llvm.coverage.json.export",
+            "version": "2.0.1"
+        }"#,
+    )
+    .unwrap();
+    assert_eq!(
+        Summary::from_llvm_cov_json(&json),
+        Some(Summary {
+            covered_lines: 30,
+            total_lines: 40,
+            covered_branches: 6,
+            total_branches: 8,
+        })
+    );
+    assert_eq!(Summary::from_llvm_cov_json(&from_str("{}").unwrap()), None);
+}
@@ -6,6 +6,7 @@ use std::iter::IntoIterator;
 use std::path::{Path, PathBuf};
 
 use regex::{escape, RegexSet};
+use serde_json::{from_str, Value};
 use shlex::Shlex;
 
 use errors::Error;
@@ -41,7 +42,12 @@ struct Info {
 /// compilation of a test executable, the path will be extracted. Otherwise, it returns `None`.
 fn parse_rustc_command_line(line: &str) -> Option<PathBuf> {
     let trimmed_line = line.trim_start();
-    if !trimmed_line.starts_with("Running `rustc ") {
+    // Newer cargo prints the full path of rustc, e.g. `Running `/path/to/bin/rustc ...``.
+    let program = trimmed_line
+        .strip_prefix("Running `")?
+        .split_whitespace()
+        .next()?;
+    if Path::new(program).file_stem() != Some("rustc".as_ref()) {
         return None;
     }
 
@@ -87,10 +93,7 @@ fn parse_rustc_command_line(line: &str) -> Option<PathBuf> {
         return None;
     }
 
-    let mut file_name = match info.crate_name {
-        Some(c) => c,
-        None => return None,
-    };
+    let mut file_name = info.crate_name?;
 
     if let Some(extra) = info.extra_filename {
         file_name.push_str(&extra[15..]);
@@ -125,6 +128,8 @@ fn test_parse_rustc_command_lines() {
      Running `rustc src/bin/second.rs --crate-name second --crate-type bin -g --out-dir /path/to/cargo-kcov/specimen/target/debug --emit=dep-info,link -L dependency=/path/to/cargo-kcov/specimen/target/debug -L dependency=/path/to/cargo-kcov/specimen/target/debug/deps --extern cargo_kcov_test=/path/to/cargo-kcov/specimen/target/debug/libcargo_kcov_test.rlib`
      Running `rustc src/bin/first.rs --crate-name first --crate-type bin -g --test -C metadata=d5d6293fc6d22a93 -C extra-filename=-d5d6293fc6d22a93 --out-dir /path/to/cargo-kcov/specimen/target/debug --emit=dep-info,link -L dependency=/path/to/cargo-kcov/specimen/target/debug -L dependency=/path/to/cargo-kcov/specimen/target/debug/deps --extern cargo_kcov_test=/path/to/cargo-kcov/specimen/target/debug/libcargo_kcov_test.rlib`
      Running `rustc examples/fourth.rs --crate-name fourth --crate-type bin -g --out-dir /path/to/cargo-kcov/specimen/target/debug/examples --emit=dep-info,link -L dependency=/path/to/cargo-kcov/specimen/target/debug -L dependency=/path/to/cargo-kcov/specimen/target/debug/deps --extern cargo_kcov_test=/path/to/cargo-kcov/specimen/target/debug/libcargo_kcov_test.rlib`
+     Running `/home/me/.rustup/toolchains/stable-x86_64-unknown-linux-gnu/bin/rustc --crate-name seventh --edition=2015 tests/seventh.rs --error-format=json --emit=dep-info,link -C embed-bitcode=no -C debuginfo=2 --test -C metadata=5210f5f0e405c366 -C extra-filename=-3efd8cb263214b42 --out-dir /path/to/cargo-kcov/specimen/target/x86_64-unknown-linux-gnu/debug/deps -L dependency=/path/to/cargo-kcov/specimen/target/x86_64-unknown-linux-gnu/debug/deps`
+     Running `/home/me/.rustup/toolchains/stable-x86_64-unknown-linux-gnu/bin/rustdoc --crate-name seventh --test src/lib.rs`
     ";
 
     let expected_paths = [
@@ -136,6 +141,9 @@ fn test_parse_rustc_command_lines() {
         Path::new("/path/to/cargo-kcov/specimen/target/debug/cargo_kcov_test-41b658cb1ecbc7a1"),
         Path::new("/path/to/cargo-kcov/specimen/target/debug/fifth-eaaacda44386e87c"),
         Path::new("/path/to/cargo-kcov/specimen/target/debug/first-d5d6293fc6d22a93"),
+        Path::new(
+            "/path/to/cargo-kcov/specimen/target/x86_64-unknown-linux-gnu/debug/deps/seventh-3efd8cb263214b42",
+        ),
     ];
 
     let mut actual_paths = Vec::new();
@@ -146,6 +154,50 @@ fn test_parse_rustc_command_lines() {
 
 //-------------------------------------------------------------------------------------------------
 
+/// Collects the package ID and path of each test executable from the output of
+/// `cargo test --no-run --message-format=json`. Unlike the verbose output, this lists the
+/// executables which were up to date too.
+pub fn parse_test_artifacts(output: &str) -> Vec<(String, PathBuf)> {
+    output
+        .lines()
+        .filter_map(|line| from_str::<Value>(line).ok())
+        .filter(|message| {
+            message["reason"] == "compiler-artifact" && message["profile"]["test"] == true
+        })
+        .filter_map(|message| {
+            Some((
+                message["package_id"].as_str()?.to_owned(),
+                PathBuf::from(message["executable"].as_str()?),
+            ))
+        })
+        .collect()
+}
+
+#[test]
+fn test_parse_test_artifacts() {
+    let output = r#"{"reason":"compiler-artifact","package_id":"path+file:///ws/core#0.1.0","target":{"kind":["lib"],"name":"core"},"profile":{"test":false},"executable":null,"fresh":true}
+{"reason":"compiler-artifact","package_id":"path+file:///ws/core#0.1.0","target":{"kind":["lib"],"name":"core"},"profile":{"test":true},"executable":"/ws/target/debug/deps/core-0123456789abcdef","fresh":true}
+{"reason":"compiler-message","package_id":"path+file:///ws/cli#0.1.0","message":{"rendered":"warning: unused"}}
+{"reason":"compiler-artifact","package_id":"path+file:///ws/cli#0.1.0","target":{"kind":["test"],"name":"cli"},"profile":{"test":true},"executable":"/ws/target/debug/deps/cli-fedcba9876543210","fresh":false}
+{"reason":"build-finished","success":true}
+"#;
+    assert_eq!(
+        parse_test_artifacts(output),
+        [
+            (
+                "path+file:///ws/core#0.1.0".to_owned(),
+                PathBuf::from("/ws/target/debug/deps/core-0123456789abcdef")
+            ),
+            (
+                "path+file:///ws/cli#0.1.0".to_owned(),
+                PathBuf::from("/ws/target/debug/deps/cli-fedcba9876543210")
+            ),
+        ]
+    );
+}
+
+//-------------------------------------------------------------------------------------------------
+
 /// Finds all test targets in the target folder (usually `target/debug/`).
 ///
 /// If the `filter` set is empty, all test executables will be gathered.
@@ -157,7 +209,7 @@ where
 {
     let filter = filter.into_iter();
     let test_target_regex = if filter.len() == 0 {
-        RegexSet::new(&["^[^-]+-[0-9a-f]{16}$"])
+        RegexSet::new(["^[^-]+-[0-9a-f]{16}$"])
     } else {
         RegexSet::new(filter.map(|f| format!("^{}-[0-9a-f]{{16}}$", escape(f.as_ref()))))
     }
@@ -302,14 +354,14 @@ fn test_find_test_targets() {
         let result = find_test_targets(root_path, &["asdaksdhaskdkasdk"]);
         match result {
             Err(Error::CannotFindTestTargets(None)) => {}
-            _ => assert!(false),
+            _ => panic!("expected CannotFindTestTargets(None)"),
         }
     }
 }
 
 //-------------------------------------------------------------------------------------------------
 
-pub fn find_package_name_from_pkgid(pkgid: &str) -> Cow<str> {
+pub fn find_package_name_from_pkgid(pkgid: &str) -> Cow<'_, str> {
     // whoever think of this pkgid syntax... wtf???
     let path = match pkgid.rfind('/') {
         Some(i) => &pkgid[i + 1..],
@@ -324,7 +376,7 @@ pub fn find_package_name_from_pkgid(pkgid: &str) -> Cow<str> {
     normalize_package_name(pkg_name)
 }
 
-pub fn normalize_package_name(name: &str) -> Cow<str> {
+pub fn normalize_package_name(name: &str) -> Cow<'_, str> {
     if name.contains('-') {
         Cow::Owned(name.replace('-', "_"))
     } else {
@@ -0,0 +1,200 @@
+//! Watching the workspace for source changes, to collect coverage again.
+//!
+//! Each change is attributed to the workspace member containing the file. Only that member and the
+//! members depending on it need to be rebuilt and tested again, see `update_coverage`.
+
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+use notify::{self, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+
+use errors::Error;
+use {owner, Member};
+
+/// Time without further changes after which the changes are reported, so saving several files at
+/// once triggers only one run.
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+/// Finds the members affected by changes of the files `changed`: the members containing them, and
+/// the members depending on those, directly or not.
+pub fn affected_members<'a>(members: &'a [Member], changed: &BTreeSet<PathBuf>) -> Vec<&'a Member> {
+    let mut affected = BTreeSet::new();
+    for path in changed {
+        if let Some(owner) = owner(members, path) {
+            affected.insert(&*owner.name);
+        }
+    }
+    loop {
+        let dependents = members
+            .iter()
+            .filter(|member| !affected.contains(&*member.name))
+            .filter(|member| {
+                member
+                    .dependencies
+                    .iter()
+                    .any(|name| affected.contains(&**name))
+            })
+            .map(|member| &*member.name)
+            .collect::<Vec<_>>();
+        if dependents.is_empty() {
+            break;
+        }
+        affected.extend(dependents);
+    }
+    members
+        .iter()
+        .filter(|member| affected.contains(&*member.name))
+        .collect()
+}
+
+/// Watches directories recursively for changed files.
+pub struct SourceWatcher {
+    // Kept alive for the events to be sent.
+    _watcher: RecommendedWatcher,
+    events: Receiver<notify::Result<Event>>,
+    dirs: Vec<PathBuf>,
+    ignored: Vec<PathBuf>,
+}
+
+impl SourceWatcher {
+    /// Watches `dirs`, usually the roots of the workspace members, ignoring the changes under
+    /// `ignored`, e.g. the target directory which is rewritten by every run.
+    pub fn new(dirs: &[PathBuf], ignored: Vec<PathBuf>) -> Result<Self, Error> {
+        let error = |e: notify::Error| Error::CannotWatchSources(e.to_string());
+        let (sender, events) = channel();
+        let mut watcher = notify::recommended_watcher(sender).map_err(error)?;
+        // A member nested in another one is already watched with its parent.
+        let mut dirs = dirs.to_vec();
+        dirs.sort();
+        dirs.dedup_by(|dir, parent| dir.starts_with(parent));
+        for dir in &dirs {
+            watcher
+                .watch(dir, RecursiveMode::Recursive)
+                .map_err(error)?;
+        }
+        Ok(SourceWatcher {
+            _watcher: watcher,
+            events,
+            dirs,
+            ignored,
+        })
+    }
+
+    /// Blocks until files change, and returns their paths.
+    pub fn wait(&self) -> Result<BTreeSet<PathBuf>, Error> {
+        let mut changed = BTreeSet::new();
+        loop {
+            let event = if changed.is_empty() {
+                self.events
+                    .recv()
+                    .map_err(|_| RecvTimeoutError::Disconnected)
+            } else {
+                self.events.recv_timeout(DEBOUNCE)
+            };
+            match event {
+                Ok(Ok(event)) => {
+                    if let EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_) =
+                        event.kind
+                    {
+                        changed.extend(event.paths.into_iter().filter(|p| !self.is_ignored(p)));
+                    }
+                }
+                Ok(Err(e)) => return Err(Error::CannotWatchSources(e.to_string())),
+                Err(RecvTimeoutError::Timeout) => return Ok(changed),
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(Error::CannotWatchSources("the watcher stopped".to_owned()))
+                }
+            }
+        }
+    }
+
+    /// Whether changes of `path` are ignored: it is under an ignored directory, or a hidden file or
+    /// directory like `.git` inside a watched directory.
+    fn is_ignored(&self, path: &Path) -> bool {
+        if self.ignored.iter().any(|dir| is_under(path, dir)) {
+            return true;
+        }
+        let relative = self
+            .dirs
+            .iter()
+            .find_map(|dir| path.strip_prefix(dir).ok())
+            .unwrap_or(path);
+        relative
+            .components()
+            .any(|c| c.as_os_str().to_string_lossy().starts_with('.'))
+    }
+}
+
+/// Whether `path` is inside `dir`, or inside the temporary sibling cargo creates the target
+/// directory from and renames: the name of `dir` followed by six random alphanumeric characters,
+/// e.g. `targetCMDgAa`.
+fn is_under(path: &Path, dir: &Path) -> bool {
+    if path.starts_with(dir) {
+        return true;
+    }
+    let (parent, name) = match (dir.parent(), dir.file_name()) {
+        (Some(parent), Some(name)) => (parent, name.to_string_lossy()),
+        _ => return false,
+    };
+    match path.strip_prefix(parent).ok().and_then(|p| p.iter().next()) {
+        Some(first) => first
+            .to_string_lossy()
+            .strip_prefix(&*name)
+            .is_some_and(|suffix| {
+                suffix.len() == 6 && suffix.bytes().all(|b| b.is_ascii_alphanumeric())
+            }),
+        None => false,
+    }
+}
+
+#[test]
+fn test_affected_members() {
+    use serde_json::{from_str, Value};
+
+    let json = from_str::<Value>(
+        r#"{"packages": [
+            {"id": "app 0.1.0", "name": "app", "manifest_path": "/ws/Cargo.toml",
+             "dependencies": [{"name": "cli"}, {"name": "serde", "optional": true}],
+             "features": {"default": ["serde"]}},
+            {"id": "core 0.1.0", "name": "core", "manifest_path": "/ws/core/Cargo.toml",
+             "dependencies": []},
+            {"id": "cli 0.1.0", "name": "cli", "manifest_path": "/ws/cli/Cargo.toml",
+             "dependencies": [{"name": "core"}]},
+            {"id": "docs 0.1.0", "name": "docs", "manifest_path": "/ws/docs/Cargo.toml"}
+        ]}"#,
+    )
+    .unwrap();
+    let members = Member::from_metadata(&json);
+    assert_eq!(members.len(), 4);
+    assert_eq!(members[1].root, Path::new("/ws/core"));
+    assert_eq!(members[2].dependencies, ["core"]);
+    assert_eq!(members[0].features, ["default", "serde"]);
+    assert!(members[3].features.is_empty());
+
+    let affected = |paths: &[&str]| {
+        let changed = paths.iter().map(PathBuf::from).collect();
+        affected_members(&members, &changed)
+            .iter()
+            .map(|member| member.name.clone())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(affected(&["/ws/core/src/lib.rs"]), ["app", "core", "cli"]);
+    assert_eq!(affected(&["/ws/cli/src/main.rs"]), ["app", "cli"]);
+    assert_eq!(affected(&["/ws/src/main.rs"]), ["app"]);
+    assert_eq!(
+        affected(&["/ws/docs/README.md", "/elsewhere/x.rs"]),
+        ["docs"]
+    );
+    assert!(affected(&[]).is_empty());
+
+    let target = Path::new("/ws/target");
+    assert!(is_under(Path::new("/ws/target/debug/app"), target));
+    assert!(is_under(Path::new("/ws/targetCMDgAa/debug"), target));
+    assert!(!is_under(Path::new("/ws/src/target.rs"), target));
+    assert!(!is_under(Path::new("/ws/targets/debug"), target));
+    assert!(!is_under(Path::new("/ws/target-utils/src/lib.rs"), target));
+    assert!(!is_under(Path::new("/ws/targetCMDgAa2/debug"), target));
+    assert!(!is_under(Path::new("/ws"), target));
+}
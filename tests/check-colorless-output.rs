@@ -25,7 +25,7 @@ use std::process::Command;
 #[test]
 fn test_colorless_stderr() {
     let status = Command::new("cargo")
-        .args(&["run", "--", "kcov", "--manifest-path", "/dev/null"])
+        .args(["run", "--", "kcov", "--manifest-path", "/dev/null"])
         .env("TERM", "none")
         .status()
         .expect("finished normally");
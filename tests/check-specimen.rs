@@ -28,12 +28,12 @@ use rquery::Document;
 #[test]
 fn test_specimen() {
     Command::new("cargo")
-        .args(&["clean", "--manifest-path", "specimen/Cargo.toml"])
+        .args(["clean", "--manifest-path", "specimen/Cargo.toml"])
         .status()
         .expect("cargo clean");
 
     Command::new("cargo")
-        .args(&[
+        .args([
             "run",
             "--",
             "kcov",